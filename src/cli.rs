@@ -1,6 +1,19 @@
-use clap::{Parser, Subcommand};
-use iroh::PublicKey;
-use std::path::PathBuf;
+use crate::{
+    core::{
+        limit::Rate,
+        protocol::{ByteRange, DEFAULT_MAX_FAILURES},
+        template::OutputTemplate,
+        ticket::Ticket,
+        uri::HostUri,
+    },
+    utils::{format::parse_bytes, logging::LogOptions},
+};
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use iroh::{PublicKey, RelayUrl};
+use iroh_blobs::Hash;
+use std::{fmt, net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
+use tracing::level_filters::LevelFilter;
 
 #[derive(Parser, Debug)]
 pub struct Opts {
@@ -9,31 +22,367 @@ pub struct Opts {
     pub command: Command,
 
     /// The key to use
-    #[clap(long, short = 'k')]
+    #[clap(long, short = 'k', env = "POOF_KEY")]
     pub key: Option<String>,
+
+    /// Print messages, events and errors as JSON lines
+    #[clap(long, global = true)]
+    pub json: bool,
+
+    /// Log more, repeat for warn, info, debug then trace (POOF_LOG wins when set)
+    #[clap(long, short = 'v', global = true, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Prefix log lines with a timestamp
+    #[clap(long, global = true, value_enum, default_value_t = LogTime::None)]
+    pub log_time: LogTime,
+
+    /// When to color output, `auto` colors terminals unless NO_COLOR is set
+    #[clap(long, global = true, value_enum, default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+
+    /// Keep keys, hosts, settings and blobs here instead of ~/.config/poof
+    #[clap(long, global = true, env = "POOF_CONFIG_DIR")]
+    pub config_dir: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub network: NetworkOpts,
+}
+
+impl Opts {
+    /// How `--verbose` and `--log-time` ask logs to look, off without them.
+    pub fn log_options(&self) -> LogOptions {
+        let level = match self.verbose {
+            0 => LevelFilter::OFF,
+            1 => LevelFilter::WARN,
+            2 => LevelFilter::INFO,
+            3 => LevelFilter::DEBUG,
+            _ => LevelFilter::TRACE,
+        };
+        LogOptions {
+            level,
+            time: self.log_time,
+        }
+    }
+}
+
+/// Flags shaping the endpoint started by `drop` and `catch`.
+#[derive(Args, Debug)]
+pub struct NetworkOpts {
+    /// Bind the endpoint to this address instead of an ephemeral port
+    #[clap(long, global = true)]
+    pub bind: Option<SocketAddr>,
+
+    /// Where blobs are kept while serving or catching
+    #[clap(long, global = true, value_enum, default_value_t = StoreKind::Disk)]
+    pub store: StoreKind,
+
+    /// Drops smaller than this stay in memory with `--store auto` (64MiB unless set in config.toml)
+    #[clap(long, global = true, value_name = "SIZE", value_parser = parse_bytes)]
+    pub store_threshold: Option<u64>,
+
+    /// Which IP stacks the endpoint should use
+    #[clap(long, global = true, value_enum, default_value_t = IpVersion::Both)]
+    pub ip_version: IpVersion,
+
+    /// Use this relay server instead of the default ones
+    #[clap(long, global = true, conflicts_with = "no_relay")]
+    pub relay: Option<RelayUrl>,
+
+    /// Never use a relay, only direct and local network connections
+    #[clap(long, global = true)]
+    pub no_relay: bool,
+
+    /// Known address of the remote node, tried before discovery (repeatable)
+    #[clap(long, global = true)]
+    pub direct_addr: Vec<SocketAddr>,
+}
+
+/// Flags shared by `drop` and `serve`.
+#[derive(Args, Debug)]
+pub struct ServeOpts {
+    /// Serve a blob already in the store by its hash instead of adding files
+    #[clap(long, conflicts_with_all = ["files", "wrap", "follow_symlinks", "dry_run"])]
+    pub hash: Option<Hash>,
+
+    /// Serve the tickets of the last drop again, along with any new files
+    #[clap(long, conflicts_with = "dry_run")]
+    pub resume: bool,
+
+    /// Filename announced to catchers, defaults to the dropped file's name
+    #[clap(long)]
+    pub name: Option<String>,
+
+    /// Wrap files in a collection carrying their name, for plain iroh-blobs catchers
+    #[clap(long)]
+    pub wrap: bool,
+
+    /// Follow symlinks inside a dropped directory instead of skipping them
+    #[clap(long)]
+    pub follow_symlinks: bool,
+
+    /// Send each file's Unix mode and modification time for catchers to restore
+    #[clap(long, conflicts_with = "hash")]
+    pub preserve_meta: bool,
+
+    /// List every ticket this node serves once the drop is ready
+    #[clap(long)]
+    pub list: bool,
+
+    /// Serve the file under this query instead of one derived from its hash (repeatable)
+    #[clap(long, short = 'q')]
+    pub query: Vec<String>,
+
+    /// Write the ticket as JSON to this path
+    #[clap(long)]
+    pub ticket_out: Option<PathBuf>,
+
+    /// Consecutive failed connections before backing off (0 to disable)
+    #[clap(long, default_value_t = DEFAULT_MAX_FAILURES)]
+    pub max_retries_total: usize,
+
+    /// Drop catchers that send no query within this time (e.g. 10s)
+    #[clap(long, default_value = "10s", value_parser = humantime::parse_duration)]
+    pub idle_timeout: Duration,
+
+    /// Cap the upload speed of each catcher's connection (e.g. 2MiB/s), 0 is unlimited
+    #[clap(long)]
+    pub limit: Option<Rate>,
+
+    /// Stop serving the drop after this long (e.g. 30s, 10m, 2h)
+    #[clap(long, value_parser = parse_expiry)]
+    pub expire: Option<Duration>,
+
+    /// Exit once every ticket of this drop has expired
+    #[clap(long)]
+    pub exit_on_expire: bool,
+
+    /// Require catchers to present this passphrase
+    #[clap(long)]
+    pub password: Option<Password>,
+
+    /// Short note shown to catchers before they download
+    #[clap(long, value_parser = parse_note)]
+    pub note: Option<String>,
+
+    /// Print a QR code of each ticket's `poof://` URI
+    #[clap(long)]
+    pub qr: bool,
+
+    /// Copy the `poof://` URIs to the clipboard, when there is one
+    #[clap(long)]
+    pub copy: bool,
+
+    /// Print the full node address so peers can connect without discovery
+    #[clap(long)]
+    pub print_node_addr: bool,
+
+    /// Print a live feed of connection and transfer events
+    #[clap(long)]
+    pub events: bool,
+
+    /// Print only the ticket to stdout, moving every other message to stderr
+    #[clap(long, value_enum)]
+    pub print_ticket: Option<TicketFormat>,
+
+    /// Hash the files and print the tickets a drop would serve, without serving anything
+    #[clap(long, conflicts_with_all = ["list", "ticket_out", "exit_on_expire", "print_node_addr", "events"])]
+    pub dry_run: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StoreKind {
+    /// Keep blobs in RAM, nothing survives the process
+    Memory,
+    /// Keep blobs under the config directory
+    Disk,
+    /// Keep drops below `--store-threshold` in RAM, so they can't be resumed, and the rest on disk
+    Auto,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TicketFormat {
+    /// One JSON object per ticket with its node id, query, hash and filename
+    Json,
+    /// The ticket's `poof://` URI
+    Uri,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyFormat {
+    /// Same layout as `keys.toml`
+    #[default]
+    Toml,
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ListFormat {
+    /// Colored and meant to be read
+    #[default]
+    Human,
+    /// A JSON array with an object per entry
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HostSort {
+    /// Alphabetically
+    #[default]
+    Alias,
+    /// Oldest first
+    Added,
+    /// Most recently caught from first, never seen hosts last
+    LastSeen,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpVersion {
+    V4,
+    V6,
+    Both,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogTime {
+    /// No timestamps
+    None,
+    /// Time elapsed since poof started
+    Uptime,
+    /// Wall clock time in RFC 3339
+    Rfc3339,
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Command {
-    /// Drop a file (send)
+    /// Drop a file (send), short for `serve` with at least one file
     #[clap(alias = "d")]
     Drop {
-        /// The file to drop
-        file: PathBuf,
+        /// The files or directories to drop, `-` reads from stdin
+        #[clap(required_unless_present_any = ["hash", "resume"])]
+        files: Vec<PathBuf>,
+
+        #[clap(flatten)]
+        serve: ServeOpts,
+    },
+
+    /// Start a node serving these files, or none, until Ctrl-C, taking more
+    /// from the interactive session
+    Serve {
+        /// The files or directories to serve right away, `-` reads from stdin
+        files: Vec<PathBuf>,
+
+        #[clap(flatten)]
+        serve: ServeOpts,
     },
 
     /// Catch a file (receive)
     #[clap(alias = "c")]
     Catch {
-        /// Host identifier or alias
-        host: String,
+        /// Host alias, node id, node address (from `drop --print-node-addr`) or `poof://` URI
+        #[clap(required_unless_present = "ticket_in")]
+        host: Option<String>,
+
+        /// File identifiers, dropped filenames or tickets to catch over one connection,
+        /// omitted when the host is a `poof://` URI
+        queries: Vec<String>,
+
+        /// Read the host and query from a ticket JSON written by `drop --ticket-out`
+        #[clap(long, conflicts_with_all = ["host", "queries"])]
+        ticket_in: Option<PathBuf>,
+
+        /// List what the host serves instead of catching anything
+        #[clap(long, short, conflicts_with_all = ["queries", "ticket_in"])]
+        list: bool,
+
+        /// Only check that the host serves the query and print its ticket, without downloading
+        #[clap(long, conflicts_with_all = ["list", "range", "output", "output_dir", "force", "no_clobber", "no_resume"])]
+        verify_only: bool,
 
-        /// File identifier or ticket
-        query: String,
+        /// Fail unless the served file has this name
+        #[clap(long)]
+        expect_name: Option<String>,
+
+        /// Only fetch a byte range, given as OFFSET:LENGTH
+        #[clap(long)]
+        range: Option<ByteRange>,
 
-        /// Optional destination path
+        /// Passphrase for a protected drop
+        #[clap(long)]
+        password: Option<Password>,
+
+        /// Don't record this catch in the history
+        #[clap(long)]
+        no_history: bool,
+
+        /// Discard what an interrupted catch left in the store and start over
+        #[clap(long)]
+        no_resume: bool,
+
+        /// Give up when the host can't be reached and asked within this time, 0 waits forever
+        #[clap(long, default_value = "60s", value_parser = humantime::parse_duration)]
+        timeout: Duration,
+
+        /// Connection retries before giving up, 0 tries exactly once (3 unless set in config.toml)
+        #[clap(long)]
+        retries: Option<usize>,
+
+        /// Delay before the first retry, doubling after each one (2s unless set in config.toml)
+        #[clap(long, value_parser = humantime::parse_duration)]
+        retry_delay: Option<Duration>,
+
+        /// Overwrite the destination if it already exists
+        #[clap(long, short = 'f', conflicts_with = "no_clobber")]
+        force: bool,
+
+        /// Keep an existing destination and write to a numbered name next to it
+        #[clap(long)]
+        no_clobber: bool,
+
+        /// Ask before downloading more than this, e.g. `500MiB`
+        #[clap(long, value_name = "SIZE", value_parser = parse_bytes, default_value = "1GiB")]
+        confirm_above: u64,
+
+        /// Download without asking, however large
+        #[clap(long, short = 'y')]
+        yes: bool,
+
+        /// Destination path, an existing directory or one ending in `/` keeps the sent name
         #[clap(long, short = 'o')]
         output: Option<PathBuf>,
+
+        /// Directory to catch into instead of the current one, created if missing
+        #[clap(long)]
+        output_dir: Option<PathBuf>,
+
+        /// Name outputs from {query}, {filename}, {hash} and {date}, e.g. `{date}/{filename}`
+        #[clap(long, conflicts_with_all = ["output", "list", "verify_only"])]
+        output_template: Option<OutputTemplate>,
+
+        /// Save the host under this alias once something was caught from it
+        #[clap(long, value_name = "ALIAS", conflicts_with_all = ["list", "verify_only"])]
+        save_host: Option<String>,
+    },
+
+    /// Check that a host is reachable and measure the round trip to it
+    Ping {
+        /// Host alias, node id or node address (from `drop --print-node-addr`)
+        host: String,
+
+        /// How many pings to send, one per second
+        #[clap(long, short = 'c', default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+        count: u32,
+
+        /// Give up on a ping the host doesn't answer within this time
+        #[clap(long, default_value = "10s", value_parser = humantime::parse_duration)]
+        timeout: Duration,
     },
 
     /// Host management commands
@@ -43,6 +392,124 @@ pub enum Command {
     /// Key management commands
     #[clap(subcommand, aliases = ["k", "keys"])]
     Key(KeyCommand),
+
+    /// Configuration commands
+    #[clap(subcommand)]
+    Config(ConfigCommand),
+
+    /// Recently caught queries
+    #[clap(subcommand)]
+    History(HistoryCommand),
+
+    /// Show a summary of configured hosts and keys
+    Stats,
+
+    /// Check the network, keys and config files for problems
+    Doctor,
+
+    /// Remove blobs no saved ticket needs from the disk store
+    Gc {
+        /// Only remove what was stored longer ago than this, e.g. `30d`
+        #[clap(long, value_parser = humantime::parse_duration)]
+        older_than: Option<Duration>,
+
+        /// Print what would be removed and the space it takes, without removing anything
+        #[clap(long)]
+        dry_run: bool,
+    },
+
+    /// Print a shell completion script
+    Completions {
+        /// Shell to generate completions for
+        shell: Shell,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Open a config file in $EDITOR, restoring it if the result doesn't parse
+    #[clap(alias = "e")]
+    Edit {
+        /// Which config file to edit
+        file: ConfigFile,
+    },
+
+    /// Print a default from config.toml
+    #[clap(alias = "g")]
+    Get {
+        /// Setting to print
+        key: SettingKey,
+    },
+
+    /// Store a default in config.toml, used when the matching flag isn't given
+    #[clap(alias = "s")]
+    Set {
+        /// Setting to change
+        key: SettingKey,
+        /// New value, checked like the flag it stands for
+        value: String,
+    },
+
+    /// Remove a default from config.toml
+    #[clap(alias = "u")]
+    Unset {
+        /// Setting to remove
+        key: SettingKey,
+    },
+
+    /// Print where config.toml lives
+    #[clap(alias = "p")]
+    Path,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum HistoryCommand {
+    /// List recently caught queries, newest first
+    #[clap(alias = "l")]
+    List {
+        /// Only show catches from this host
+        host: Option<String>,
+    },
+
+    /// Forget all recorded catches
+    #[clap(alias = "c")]
+    Clear,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ConfigFile {
+    Hosts,
+    Keys,
+    /// Defaults for flags, config.toml
+    Config,
+}
+
+/// Defaults config.toml can hold.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SettingKey {
+    /// Key used when `--key` isn't given
+    DefaultKey,
+    /// Directory catches are written to without `--output`
+    OutputDir,
+    /// How long drops are served without `--expire`
+    Expire,
+    /// Relay used without `--relay`
+    Relay,
+    /// Catch retries without `--retries`
+    Retries,
+    /// First retry delay without `--retry-delay`
+    RetryDelay,
+    /// Size below which drops stay in memory without `--store-threshold`
+    StoreThreshold,
+}
+
+impl fmt::Display for SettingKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.to_possible_value() {
+            Some(value) => f.write_str(value.get_name()),
+            None => write!(f, "{:?}", self),
+        }
+    }
 }
 
 #[derive(Subcommand, Debug)]
@@ -52,9 +519,10 @@ pub enum HostCommand {
     Add {
         /// Alias for the host
         alias: String,
-        /// Public key of the host
-        public_key: PublicKey,
-        /// Optional description
+        /// Public key of the host, or a `poof-host://` URI from `key show --qr`
+        #[clap(value_parser = parse_host_key)]
+        public_key: HostUri,
+        /// Optional description, overriding the one from the URI
         #[clap(long, short = 'd')]
         description: Option<String>,
     },
@@ -69,9 +537,18 @@ pub enum HostCommand {
     /// List all hosts
     #[clap(alias = "l")]
     List {
-        /// Show detailed information
+        /// Show every detail of each host
+        #[clap(long)]
+        full: bool,
+        /// Only show hosts whose alias, description or metadata contains this
         #[clap(long)]
-        verbose: bool,
+        filter: Option<String>,
+        /// Order of the listed hosts
+        #[clap(long, value_enum, default_value_t)]
+        sort: HostSort,
+        /// How to print the hosts
+        #[clap(long, value_enum, default_value_t)]
+        format: ListFormat,
     },
 
     /// Show host details
@@ -81,6 +558,14 @@ pub enum HostCommand {
         alias: String,
     },
 
+    /// Find a host by its key fingerprint
+    #[clap(alias = "f")]
+    Find {
+        /// Fingerprint as shown by `poof key show`, e.g. AB12-CD34-EF56
+        #[clap(long)]
+        fingerprint: String,
+    },
+
     /// Rename a host
     #[clap(alias = "rn")]
     Rename {
@@ -89,6 +574,49 @@ pub enum HostCommand {
         /// New alias
         new_alias: String,
     },
+
+    /// Exchange the aliases of two hosts
+    #[clap(alias = "sw")]
+    Swap {
+        /// First alias
+        alias_a: String,
+        /// Second alias
+        alias_b: String,
+    },
+
+    /// Tag a host with a metadata value, e.g. location=office
+    SetMeta {
+        /// Alias of the host
+        alias: String,
+        /// Metadata key
+        key: String,
+        /// Value to store
+        value: String,
+    },
+
+    /// Print a host's metadata value
+    GetMeta {
+        /// Alias of the host
+        alias: String,
+        /// Metadata key
+        key: String,
+    },
+
+    /// Remove a metadata value from a host
+    RmMeta {
+        /// Alias of the host
+        alias: String,
+        /// Metadata key
+        key: String,
+    },
+
+    /// Ping every saved host at once and show which ones are reachable
+    #[clap(alias = "pa")]
+    PingAll {
+        /// Count a host as unreachable when it doesn't answer within this time
+        #[clap(long, default_value = "5s", value_parser = humantime::parse_duration)]
+        timeout: Duration,
+    },
 }
 
 #[derive(Subcommand, Debug)]
@@ -104,6 +632,9 @@ pub enum KeyCommand {
         /// Set as default key
         #[clap(long)]
         default: bool,
+        /// Encrypt the secret key with a passphrase (prompted, or POOF_PASSPHRASE)
+        #[clap(long)]
+        encrypt: bool,
     },
 
     /// Add an existing key
@@ -138,6 +669,14 @@ pub enum KeyCommand {
         /// Show full key
         #[clap(short, long)]
         full: bool,
+
+        /// Only show keys whose name or description contains this
+        #[clap(long)]
+        filter: Option<String>,
+
+        /// How to print the keys, secrets only appear with --show-secret
+        #[clap(long, value_enum, default_value_t)]
+        format: ListFormat,
     },
 
     /// Show key details
@@ -149,6 +688,14 @@ pub enum KeyCommand {
         /// Show secret key (use with caution)
         #[clap(long)]
         show_secret: bool,
+
+        /// Also print a QR code of the key's `poof-host://` URI, to add it as a host elsewhere
+        #[clap(long)]
+        qr: bool,
+
+        /// Alias the URI suggests to whoever adds the host
+        #[clap(long, requires = "qr")]
+        alias: Option<String>,
     },
 
     /// Set default key
@@ -157,4 +704,98 @@ pub enum KeyCommand {
         /// Name of the key to set as default
         name: String,
     },
+
+    /// Rename a key
+    #[clap(alias = "rn")]
+    Rename {
+        /// Current name
+        old_name: String,
+        /// New name
+        new_name: String,
+    },
+
+    /// Replace a key's secret with a fresh one, keeping its name and description
+    Rotate {
+        /// Name of the key to rotate
+        name: String,
+
+        /// Keep the old secret as `<name>-old-<timestamp>`
+        #[clap(long)]
+        keep_old: bool,
+    },
+
+    /// Export keys for backup or migration (secrets included)
+    #[clap(alias = "e")]
+    Export {
+        /// Name of the key to export
+        #[clap(required_unless_present = "all")]
+        name: Option<String>,
+        /// Export every key
+        #[clap(long, conflicts_with = "name")]
+        all: bool,
+        /// Write to this file instead of stdout
+        #[clap(long, short)]
+        output: Option<PathBuf>,
+        /// Format of the export
+        #[clap(long, value_enum, default_value_t)]
+        format: KeyFormat,
+    },
+
+    /// Import keys from a file written by `poof key export`, or `-` for stdin
+    #[clap(alias = "i")]
+    Import {
+        /// File to import from
+        file: PathBuf,
+    },
+
+    /// Switch to a key for the current shell: eval "$(poof key use <name>)"
+    #[clap(alias = "u")]
+    Use {
+        /// Name of the key to switch to
+        name: String,
+    },
+}
+
+pub fn parse_expiry(s: &str) -> Result<Duration, String> {
+    let duration = humantime::parse_duration(s).map_err(|e| e.to_string())?;
+    if duration.is_zero() {
+        return Err("expiry must be greater than 0".to_string());
+    }
+    Ok(duration)
+}
+
+/// A bare public key, or a host URI carrying one.
+fn parse_host_key(s: &str) -> Result<HostUri, String> {
+    if s.contains("://") {
+        return HostUri::from_str(s).map_err(|e| e.to_string());
+    }
+    PublicKey::from_str(s)
+        .map(|public_key| HostUri::new(public_key, None))
+        .map_err(|e| format!("invalid public key: {}", e))
+}
+
+fn parse_note(s: &str) -> Result<String, String> {
+    Ticket::check_note(s)?;
+    Ok(s.to_string())
+}
+
+/// A passphrase given on the command line, redacted from debug output.
+#[derive(Clone)]
+pub struct Password(pub String);
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Password(<redacted>)")
+    }
+}
+
+impl FromStr for Password {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("password cannot be empty".to_string());
+        }
+        Ok(Self(s.to_string()))
+    }
 }
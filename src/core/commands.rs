@@ -1,10 +1,35 @@
-use crate::cli::{HostCommand, KeyCommand};
-use crate::core::hosts::{HostManager, KeyManager};
-use crate::utils::format::{ReducedId, format_duration};
+use crate::cli::NetworkOpts;
+use crate::cli::{
+    ConfigCommand, ConfigFile, HistoryCommand, HostCommand, HostSort, KeyCommand, KeyFormat,
+    ListFormat,
+};
+use crate::core::bind_endpoint;
+use crate::core::config::ConfigManager;
+use crate::core::history::HistoryManager;
+use crate::core::hosts::{Host, HostConfig, HostKey, HostManager, KeyConfig, KeyManager};
+use crate::core::settings::{Settings, SettingsManager};
+use crate::core::store;
+use crate::core::tickets::TicketStore;
+use crate::core::uri::HostUri;
+use crate::utils::constants::{BLOBS_DIRECTORY, CONFIG_DIRECTORY};
+use crate::utils::format::{
+    Fingerprint, ReducedId, format_bytes, format_duration, normalize_fingerprint, render_qr,
+};
+use crate::utils::logging::{self, Level};
+use crate::utils::secret;
 use crate::{Result, info, success, warning};
+use facet::Facet;
 use iroh::SecretKey;
+use iroh_blobs::{BlobFormat, HashAndFormat, store::Store as _};
 use owo_colors::OwoColorize;
+use rand::rngs::OsRng;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
 use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
 pub async fn handle_host_command(cmd: HostCommand, host_manager: &HostManager) -> Result<()> {
     match cmd {
@@ -13,7 +38,12 @@ pub async fn handle_host_command(cmd: HostCommand, host_manager: &HostManager) -
             public_key,
             description,
         } => {
-            host_manager.add_host(alias.clone(), public_key, description)?;
+            let HostUri {
+                public_key,
+                description: suggested,
+                ..
+            } = public_key;
+            host_manager.add_host(alias.clone(), public_key, description.or(suggested))?;
             success!(
                 "Added host '{}' with public key {}",
                 alias.bold(),
@@ -23,92 +53,138 @@ pub async fn handle_host_command(cmd: HostCommand, host_manager: &HostManager) -
 
         HostCommand::Remove { alias } => {
             let host = host_manager.remove_host(&alias)?;
+            // Broken entries can still be removed, show their raw key instead
             success!(
                 "Removed host '{}' ({})",
                 alias.bold(),
-                host.public_key().reduced()
+                host.public_key()
+                    .map_or_else(|_| host.public_key.clone(), |key| key.reduced())
             );
         }
 
-        HostCommand::List { verbose } => {
-            let hosts = host_manager.list_hosts()?;
+        HostCommand::List {
+            full,
+            filter,
+            sort,
+            format,
+        } => {
+            let mut hosts = host_manager.list_hosts(filter.as_deref())?;
+            match sort {
+                HostSort::Alias => hosts.sort_by(|a, b| a.alias.cmp(&b.alias)),
+                HostSort::Added => hosts.sort_by_key(|host| host.added_at),
+                HostSort::LastSeen => hosts.sort_by_key(|host| std::cmp::Reverse(host.last_seen)),
+            }
+            if format == ListFormat::Json {
+                let hosts = hosts.iter().map(HostOutput::new).collect::<Vec<_>>();
+                println!("{}", facet_json::to_string(&hosts));
+                return Ok(());
+            }
             if hosts.is_empty() {
-                info!("No hosts configured");
+                match filter {
+                    Some(filter) => info!("No hosts match '{}'", filter),
+                    None => info!("No hosts configured"),
+                }
                 return Ok(());
             }
 
-            println!("\n{}", "Configured Hosts:".bold().underline());
+            logging::emit(format!("\n{}", "Configured Hosts:".bold().underline()));
             for host in hosts {
-                if verbose {
-                    println!(
+                let public_key = match host.public_key() {
+                    Ok(public_key) => public_key,
+                    Err(e) => {
+                        warning!("{}, skipping it", e);
+                        continue;
+                    }
+                };
+                if full {
+                    logging::emit(format!(
                         "\n{}",
                         format!("  {} {}", "•".blue(), host.alias.bold()).bright_white()
-                    );
-                    println!("    {}: {}", "Public Key".dimmed(), host.public_key);
+                    ));
+                    logging::emit(format!("    {}: {}", "Public Key".dimmed(), public_key));
                     if let Some(desc) = &host.description {
-                        println!("    {}: {}", "Description".dimmed(), desc);
+                        logging::emit(format!("    {}: {}", "Description".dimmed(), desc));
                     }
-                    println!(
+                    logging::emit(format!(
                         "    {}: {}",
                         "Added".dimmed(),
                         format_duration(host.added_at().elapsed().unwrap_or_default())
-                    );
+                    ));
                     if let Some(last_seen) = host.last_seen() {
-                        println!(
+                        logging::emit(format!(
                             "    {}: {}",
                             "Last Seen".dimmed(),
                             format_duration(last_seen.elapsed().unwrap_or_default())
-                        );
+                        ));
                     }
                     if !host.metadata.is_empty() {
-                        println!("    {}:", "Metadata".dimmed());
+                        logging::emit(format!("    {}:", "Metadata".dimmed()));
                         for (key, value) in &host.metadata {
-                            println!("      {}: {}", key, value);
+                            logging::emit(format!("      {}: {}", key, value));
                         }
                     }
                 } else {
-                    println!(
+                    logging::emit(format!(
                         "  {} {} ({})",
                         "•".blue(),
                         host.alias.bold(),
-                        host.public_key().reduced()
-                    );
+                        public_key.reduced()
+                    ));
                 }
             }
-            println!();
+            logging::emit(String::new());
         }
 
         HostCommand::Show { alias } => {
             if let Some(host) = host_manager.get_host(&alias)? {
-                println!("\n{}", format!("Host: {}", host.alias).bold().underline());
-                println!("  {}: {}", "Public Key".dimmed(), host.public_key);
+                logging::emit(format!(
+                    "\n{}",
+                    format!("Host: {}", host.alias).bold().underline()
+                ));
+                logging::emit(format!("  {}: {}", "Public Key".dimmed(), host.public_key));
+                if let Ok(fingerprint) = host.fingerprint() {
+                    logging::emit(format!("  {}: {}", "Fingerprint".dimmed(), fingerprint));
+                }
                 if let Some(desc) = &host.description {
-                    println!("  {}: {}", "Description".dimmed(), desc);
+                    logging::emit(format!("  {}: {}", "Description".dimmed(), desc));
                 }
-                println!(
+                logging::emit(format!(
                     "  {}: {}",
                     "Added".dimmed(),
                     format_duration(host.added_at().elapsed().unwrap_or_default())
-                );
+                ));
                 if let Some(last_seen) = host.last_seen() {
-                    println!(
+                    logging::emit(format!(
                         "  {}: {}",
                         "Last Seen".dimmed(),
                         format_duration(last_seen.elapsed().unwrap_or_default())
-                    );
+                    ));
                 }
                 if !host.metadata.is_empty() {
-                    println!("  {}:", "Metadata".dimmed());
+                    logging::emit(format!("  {}:", "Metadata".dimmed()));
                     for (key, value) in &host.metadata {
-                        println!("    {}: {}", key, value);
+                        logging::emit(format!("    {}: {}", key, value));
                     }
                 }
-                println!();
+                logging::emit(String::new());
             } else {
                 warning!("Host '{}' not found", alias);
             }
         }
 
+        HostCommand::Find { fingerprint } => {
+            let fingerprint = normalize_fingerprint(&fingerprint);
+            match host_manager.find_by_fingerprint(&fingerprint)? {
+                Some(host) => success!(
+                    "Fingerprint {} belongs to host '{}' ({})",
+                    fingerprint.bold(),
+                    host.alias.bold(),
+                    host.public_key()?.reduced()
+                ),
+                None => warning!("No host with fingerprint {}", fingerprint.bold()),
+            }
+        }
+
         HostCommand::Rename {
             old_alias,
             new_alias,
@@ -120,6 +196,57 @@ pub async fn handle_host_command(cmd: HostCommand, host_manager: &HostManager) -
                 new_alias.bold()
             );
         }
+
+        HostCommand::Swap { alias_a, alias_b } => {
+            host_manager.swap_hosts(&alias_a, &alias_b)?;
+            success!(
+                "Swapped hosts '{}' and '{}'",
+                alias_a.bold(),
+                alias_b.bold()
+            );
+        }
+
+        HostCommand::SetMeta { alias, key, value } => {
+            if let Some(previous) = host_manager.set_metadata(&alias, key.clone(), value.clone())?
+                && previous != value
+            {
+                warning!(
+                    "Overwriting '{}' on host '{}', it was '{}'",
+                    key,
+                    alias.bold(),
+                    previous
+                );
+            }
+            success!("Set '{}' to '{}' on host '{}'", key, value, alias.bold());
+        }
+
+        HostCommand::GetMeta { alias, key } => {
+            let host = host_manager
+                .get_host(&alias)?
+                .ok_or_else(|| crate::error!("Host with alias '{}' not found", alias))?;
+            match host.metadata.get(&key) {
+                // Just the value so it can be used in scripts
+                Some(value) => println!("{}", value),
+                None => {
+                    return Err(crate::error!("Host '{}' has no metadata '{}'", alias, key));
+                }
+            }
+        }
+
+        HostCommand::RmMeta { alias, key } => {
+            let value = host_manager.remove_metadata(&alias, &key)?;
+            success!(
+                "Removed '{}' from host '{}' (was '{}')",
+                key,
+                alias.bold(),
+                value
+            );
+        }
+
+        // Pinging needs a node, `core::run` starts one for it instead
+        HostCommand::PingAll { .. } => {
+            return Err(crate::error!("host ping-all needs a node to ping from"));
+        }
     }
 
     Ok(())
@@ -131,15 +258,19 @@ pub async fn handle_key_command(cmd: KeyCommand, key_manager: &KeyManager) -> Re
             name,
             description,
             default,
+            encrypt,
         } => {
-            let key = key_manager.generate_key(name.clone(), description)?;
+            let passphrase = encrypt
+                .then(|| secret::read_passphrase("Passphrase for the new key", true))
+                .transpose()?;
+            let key = key_manager.generate_key(name.clone(), description, passphrase.as_deref())?;
             if default {
                 key_manager.set_default_key(&name)?;
             }
             success!(
                 "Generated new key '{}' with public key {}",
                 name.bold(),
-                key.public_key().reduced()
+                key.public_key()?.reduced()
             );
             if default {
                 info!("Set '{}' as default key", name.bold());
@@ -170,94 +301,170 @@ pub async fn handle_key_command(cmd: KeyCommand, key_manager: &KeyManager) -> Re
 
         KeyCommand::Remove { name } => {
             let key = key_manager.remove_key(&name)?;
-            success!(
-                "Removed key '{}' ({})",
-                name.bold(),
-                key.public_key().reduced()
-            );
+            match key.public_key() {
+                Ok(public_key) => {
+                    success!("Removed key '{}' ({})", name.bold(), public_key.reduced())
+                }
+                Err(_) => success!("Removed key '{}'", name.bold()),
+            }
         }
 
-        KeyCommand::List { show_secret, full } => {
-            let keys = key_manager.list_keys()?;
-            let default_key = key_manager.get_default_key()?;
+        KeyCommand::List {
+            show_secret,
+            full,
+            filter,
+            format,
+        } => {
+            let keys = key_manager.list_keys(filter.as_deref())?;
+            let default_key = key_manager.default_key_name()?;
+
+            if format == ListFormat::Json {
+                let keys = keys
+                    .iter()
+                    .map(|key| KeyOutput::new(key, default_key.as_deref(), show_secret))
+                    .collect::<Vec<_>>();
+                println!("{}", facet_json::to_string(&keys));
+                return Ok(());
+            }
 
             if keys.is_empty() {
-                info!("No keys configured");
+                match filter {
+                    Some(filter) => info!("No keys match '{}'", filter),
+                    None => info!("No keys configured"),
+                }
                 return Ok(());
             }
 
-            println!("\n{}", "Configured Keys:".bold().underline());
-            for key in keys {
-                let is_default = default_key
-                    .as_ref()
-                    .map(|dk| dk.name == key.name)
-                    .unwrap_or(false);
+            logging::emit(format!("\n{}", "Configured Keys:".bold().underline()));
+            for key in &keys {
+                let public_key = match key.public_key() {
+                    Ok(public_key) => public_key,
+                    Err(e) => {
+                        warning!("{}, skipping it", e);
+                        continue;
+                    }
+                };
+                let is_default = default_key.as_deref() == Some(key.name.as_str());
                 let marker = if is_default {
                     "★".yellow().to_string()
                 } else {
                     "•".blue().to_string()
                 };
 
-                println!("  {} {} ({})", marker, key.name.bold(), {
+                logging::emit(format!(
+                    "  {} {} ({}){}",
+                    marker,
+                    key.name.bold(),
                     if full {
-                        key.public_key().to_string().bold().blue().to_string()
+                        public_key.to_string().bold().blue().to_string()
                     } else {
-                        key.public_key().reduced()
+                        public_key.reduced()
+                    },
+                    if key.is_encrypted() {
+                        " encrypted".dimmed().to_string()
+                    } else {
+                        String::new()
                     }
-                });
+                ));
 
                 if show_secret {
-                    println!("    {}: {}", "Secret Key".dimmed(), key.secret_key);
+                    logging::emit(format!("    {}: {}", "Secret Key".dimmed(), key.secret_key));
                 }
                 if let Some(desc) = &key.description {
-                    println!("    {}: {}", "Description".dimmed(), desc);
+                    logging::emit(format!("    {}: {}", "Description".dimmed(), desc));
                 }
-                println!(
+                logging::emit(format!(
                     "    {}: {} ago",
                     "Created".dimmed(),
                     format_duration(key.created_at().elapsed().unwrap_or_default())
-                );
+                ));
+                if let Some(last_used) = key.last_used() {
+                    logging::emit(format!(
+                        "    {}: {} ago",
+                        "Last Used".dimmed(),
+                        format_duration(last_used.elapsed().unwrap_or_default())
+                    ));
+                }
             }
-            println!();
+            logging::emit(String::new());
 
-            if let Some(default) = default_key {
+            if let Some(default) = default_key
+                .as_deref()
+                .and_then(|name| keys.iter().find(|key| key.name == name))
+                && let Ok(public_key) = default.public_key()
+            {
                 info!(
                     "Default key: {} ({})",
                     default.name.bold(),
                     if full {
-                        default.public_key().to_string().bold().blue().to_string()
+                        public_key.to_string().bold().blue().to_string()
                     } else {
-                        default.public_key().reduced()
+                        public_key.reduced()
                     }
                 );
             }
         }
 
-        KeyCommand::Show { name, show_secret } => {
-            if let Some(key) = key_manager.get_key(&name)? {
-                let default_key = key_manager.get_default_key()?;
-                let is_default = default_key
-                    .as_ref()
-                    .map(|dk| dk.name == key.name)
-                    .unwrap_or(false);
+        KeyCommand::Show {
+            name,
+            show_secret,
+            qr,
+            alias,
+        } => {
+            // Only unlock encrypted keys when the secret is actually shown
+            let key = if show_secret {
+                key_manager.get_key(&name)?
+            } else {
+                key_manager
+                    .list_keys(None)?
+                    .into_iter()
+                    .find(|key| key.name == name)
+            };
+            if let Some(key) = key {
+                let is_default =
+                    key_manager.default_key_name()?.as_deref() == Some(key.name.as_str());
 
-                println!("\n{}", format!("Key: {}", key.name).bold().underline());
+                logging::emit(format!(
+                    "\n{}",
+                    format!("Key: {}", key.name).bold().underline()
+                ));
                 if is_default {
-                    println!("  {} {}", "Status".dimmed(), "Default".yellow().bold());
+                    logging::emit(format!(
+                        "  {} {}",
+                        "Status".dimmed(),
+                        "Default".yellow().bold()
+                    ));
                 }
-                println!("  {}: {}", "Public Key".dimmed(), key.public_key());
+                let public_key = key.public_key()?;
+                logging::emit(format!("  {}: {}", "Public Key".dimmed(), public_key));
+                logging::emit(format!(
+                    "  {}: {}",
+                    "Fingerprint".dimmed(),
+                    public_key.fingerprint()
+                ));
                 if show_secret {
-                    println!("  {}: {}", "Secret Key".dimmed(), key.secret_key);
+                    logging::emit(format!("  {}: {}", "Secret Key".dimmed(), key.secret_key));
                 }
                 if let Some(desc) = &key.description {
-                    println!("  {}: {}", "Description".dimmed(), desc);
+                    logging::emit(format!("  {}: {}", "Description".dimmed(), desc));
                 }
-                println!(
+                logging::emit(format!(
                     "  {}: {} ago",
                     "Created".dimmed(),
                     format_duration(key.created_at().elapsed().unwrap_or_default())
+                ));
+                let last_used = key.last_used().map_or_else(
+                    || "never".to_string(),
+                    |at| format!("{} ago", format_duration(at.elapsed().unwrap_or_default())),
                 );
-                println!();
+                logging::emit(format!("  {}: {}", "Last Used".dimmed(), last_used));
+                logging::emit(String::new());
+                if qr {
+                    let uri = HostUri::new(public_key, alias)
+                        .with_description(key.description.clone())
+                        .to_string();
+                    logging::emit(format!("{}\n{}\n", render_qr(&uri)?, uri.bold()));
+                }
             } else {
                 warning!("Key '{}' not found", name);
             }
@@ -267,7 +474,643 @@ pub async fn handle_key_command(cmd: KeyCommand, key_manager: &KeyManager) -> Re
             key_manager.set_default_key(&name)?;
             success!("Set '{}' as default key", name.bold());
         }
+
+        KeyCommand::Rotate { name, keep_old } => {
+            let (old, rotated) = key_manager.rotate_key(&name, keep_old, || {
+                Ok(secret::read_passphrase(
+                    "Passphrase for the rotated key",
+                    true,
+                )?)
+            })?;
+            let old_public_key = old.public_key()?;
+            success!(
+                "Rotated key '{}', new public key {}",
+                name.bold(),
+                rotated.public_key()?.reduced()
+            );
+            if keep_old {
+                info!(
+                    "Kept the old key as '{}'",
+                    format!("{}-old-{}", name, rotated.created_at).bold()
+                );
+            }
+            warning!(
+                "{}",
+                "The public key changed, hosts that saved the old one must re-add the new one"
+                    .bold()
+            );
+            warning!(
+                "  {} {} -> {} {}",
+                "old".dimmed(),
+                old_public_key.reduced(),
+                "new".dimmed(),
+                rotated.public_key()?.reduced()
+            );
+        }
+
+        KeyCommand::Rename { old_name, new_name } => {
+            key_manager.rename_key(&old_name, new_name.clone())?;
+            success!("Renamed key '{}' to '{}'", old_name.bold(), new_name.bold());
+        }
+
+        KeyCommand::Export {
+            name,
+            all: _,
+            output,
+            format,
+        } => {
+            let config = key_manager.export(name.as_deref())?;
+            let content = match format {
+                KeyFormat::Toml => facet_toml::to_string(&config)?,
+                KeyFormat::Json => facet_json::to_string(&config),
+            };
+            match output {
+                Some(path) => {
+                    fs::write(&path, content)?;
+                    success!(
+                        "Exported {} key(s) to {}",
+                        config.keys.len(),
+                        path.display().bold()
+                    );
+                }
+                None => println!("{}", content),
+            }
+        }
+
+        KeyCommand::Import { file } => {
+            let content = if file == Path::new("-") {
+                io::read_to_string(io::stdin())?
+            } else {
+                fs::read_to_string(&file)?
+            };
+            // JSON exports are objects, anything else is taken as TOML
+            let config: KeyConfig = if content.trim_start().starts_with('{') {
+                facet_json::from_str(&content)
+                    .map_err(|e| crate::error!("Invalid key export: {}", e))?
+            } else {
+                facet_toml::from_str(&content)
+                    .map_err(|e| crate::error!("Invalid key export: {}", e))?
+            };
+            if config.keys.is_empty() {
+                warning!("No keys to import");
+                return Ok(());
+            }
+
+            for key in key_manager.import(config)? {
+                success!(
+                    "Imported key '{}' with public key {}",
+                    key.name.bold(),
+                    key.public_key()?.reduced()
+                );
+            }
+        }
+
+        KeyCommand::Use { name } => {
+            key_manager.set_default_key(&name)?;
+            // Only the export line goes to stdout so the output can be eval'd
+            logging::emit_stderr(format!("{} Switched to key '{}'", "✓".green(), name.bold()));
+            logging::emit(format!("export POOF_KEY='{}'", name.replace('\'', "'\\''")));
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn handle_history_command(
+    cmd: HistoryCommand,
+    history_manager: &HistoryManager,
+) -> Result<()> {
+    match cmd {
+        HistoryCommand::List { host } => {
+            let entries = history_manager.list(host.as_deref())?;
+            if entries.is_empty() {
+                info!("No catches recorded");
+                return Ok(());
+            }
+
+            logging::emit(format!("\n{}", "Recent Catches:".bold().underline()));
+            for entry in entries {
+                let transfer = if !entry.has_transfer() {
+                    String::new()
+                } else if entry.filename.is_empty() {
+                    format!(
+                        "{} in {} ",
+                        format_bytes(entry.bytes),
+                        format_duration(entry.duration())
+                    )
+                } else {
+                    format!(
+                        "{}, {} in {} ",
+                        entry.filename,
+                        format_bytes(entry.bytes),
+                        format_duration(entry.duration())
+                    )
+                };
+                logging::emit(format!(
+                    "  {} {} {} {}{}",
+                    "•".blue(),
+                    entry.query.bold(),
+                    format!("from {}", entry.host).dimmed(),
+                    transfer,
+                    format!(
+                        "({} ago)",
+                        format_duration(entry.caught_at().elapsed().unwrap_or_default())
+                    )
+                    .dimmed()
+                ));
+            }
+            logging::emit(String::new());
+        }
+
+        HistoryCommand::Clear => {
+            let cleared = history_manager.clear()?;
+            success!("Cleared {} history entries", cleared);
+        }
+    }
+
+    Ok(())
+}
+
+/// A host as printed by `host list --format json`.
+#[derive(Debug, Facet)]
+struct HostOutput {
+    alias: String,
+    public_key: String,
+    description: Option<String>,
+    added_at: String,
+    last_seen: Option<String>,
+    metadata: HashMap<String, String>,
+}
+
+impl HostOutput {
+    fn new(host: &Host) -> Self {
+        Self {
+            alias: host.alias.clone(),
+            public_key: host.public_key.clone(),
+            description: host.description.clone(),
+            added_at: rfc3339(host.added_at()),
+            last_seen: host.last_seen().map(rfc3339),
+            metadata: host.metadata.clone(),
+        }
+    }
+}
+
+/// A key as printed by `key list --format json`.
+#[derive(Debug, Facet)]
+struct KeyOutput {
+    name: String,
+    /// Missing when the stored key is broken
+    public_key: Option<String>,
+    default: bool,
+    encrypted: bool,
+    description: Option<String>,
+    created_at: String,
+    last_used: Option<String>,
+    /// Only included with `--show-secret`
+    secret_key: Option<String>,
+}
+
+impl KeyOutput {
+    fn new(key: &HostKey, default_key: Option<&str>, show_secret: bool) -> Self {
+        Self {
+            name: key.name.clone(),
+            public_key: key.public_key().ok().map(|key| key.to_string()),
+            default: default_key == Some(key.name.as_str()),
+            encrypted: key.is_encrypted(),
+            description: key.description.clone(),
+            created_at: rfc3339(key.created_at()),
+            last_used: key.last_used().map(rfc3339),
+            secret_key: show_secret.then(|| key.secret_key.clone()),
+        }
     }
+}
+
+fn rfc3339(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+#[derive(Debug, Facet)]
+struct Stats {
+    hosts: usize,
+    keys: usize,
+    default_key: Option<String>,
+    config_directory: String,
+    config_size: u64,
+    oldest_host: Option<String>,
+    newest_host: Option<String>,
+}
+
+pub async fn handle_stats_command(
+    json: bool,
+    host_manager: &HostManager,
+    key_manager: &KeyManager,
+) -> Result<()> {
+    let hosts = host_manager.list_hosts(None)?;
+    let key_config = key_manager.load()?;
+
+    let oldest = hosts.iter().min_by_key(|host| host.added_at);
+    let newest = hosts.iter().max_by_key(|host| host.added_at);
+
+    let stats = Stats {
+        hosts: hosts.len(),
+        keys: key_config.keys.len(),
+        default_key: key_config.default_key.clone(),
+        config_directory: CONFIG_DIRECTORY.display().to_string(),
+        config_size: directory_size(&CONFIG_DIRECTORY)?,
+        oldest_host: oldest.map(|host| host.alias.clone()),
+        newest_host: newest.map(|host| host.alias.clone()),
+    };
+
+    if json {
+        println!("{}", facet_json::to_string(&stats));
+        return Ok(());
+    }
+
+    let age = |added_at: SystemTime| format_duration(added_at.elapsed().unwrap_or_default());
+
+    logging::emit(format!("\n{}", "Poof Stats:".bold().underline()));
+    logging::emit(format!("  {}: {}", "Hosts".dimmed(), stats.hosts));
+    logging::emit(format!("  {}: {}", "Keys".dimmed(), stats.keys));
+    logging::emit(format!(
+        "  {}: {}",
+        "Default Key".dimmed(),
+        stats.default_key.as_deref().unwrap_or("-")
+    ));
+    logging::emit(format!(
+        "  {}: {} ({})",
+        "Config".dimmed(),
+        stats.config_directory,
+        format_bytes(stats.config_size)
+    ));
+    if let (Some(oldest), Some(newest)) = (oldest, newest) {
+        logging::emit(format!(
+            "  {}: {} ({} ago)",
+            "Oldest Host".dimmed(),
+            oldest.alias.bold(),
+            age(oldest.added_at())
+        ));
+        logging::emit(format!(
+            "  {}: {} ({} ago)",
+            "Newest Host".dimmed(),
+            newest.alias.bold(),
+            age(newest.added_at())
+        ));
+    }
+    logging::emit(String::new());
 
     Ok(())
 }
+
+pub fn directory_size(path: &Path) -> Result<u64> {
+    if !path.exists() {
+        return Ok(0);
+    }
+
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            directory_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
+}
+
+pub async fn handle_config_command(
+    cmd: ConfigCommand,
+    host_manager: &HostManager,
+    key_manager: &KeyManager,
+    settings_manager: &SettingsManager,
+) -> Result<()> {
+    match cmd {
+        ConfigCommand::Edit { file } => {
+            let path = match file {
+                ConfigFile::Hosts => {
+                    if !host_manager.exists() {
+                        host_manager.save(&HostConfig::default())?;
+                    }
+                    host_manager.config_path()
+                }
+                ConfigFile::Keys => {
+                    if !key_manager.exists() {
+                        key_manager.save(&KeyConfig::default())?;
+                    }
+                    key_manager.config_path()
+                }
+                ConfigFile::Config => {
+                    if !settings_manager.exists() {
+                        settings_manager.save(&Settings::default())?;
+                    }
+                    settings_manager.config_path()
+                }
+            };
+
+            edit_config(&path, || match file {
+                ConfigFile::Hosts => host_manager.load().map(|_| ()),
+                ConfigFile::Keys => key_manager.load().map(|_| ()),
+                ConfigFile::Config => settings_manager.load()?.validate(),
+            })?;
+            success!("Updated {}", path.display().bold());
+        }
+
+        ConfigCommand::Get { key } => match settings_manager.get(key)? {
+            Some(value) => println!("{}", value),
+            None => info!("{} is not set", key.bold()),
+        },
+
+        ConfigCommand::Set { key, value } => {
+            settings_manager.set(key, &value)?;
+            success!("Set {} to '{}'", key.bold(), value);
+        }
+
+        ConfigCommand::Unset { key } => match settings_manager.unset(key)? {
+            Some(previous) => success!("Unset {} (was '{}')", key.bold(), previous),
+            None => info!("{} was not set", key.bold()),
+        },
+
+        ConfigCommand::Path => {
+            logging::emit(format!("{}", settings_manager.config_path().display()))
+        }
+    }
+
+    Ok(())
+}
+
+/// Opens `path` in the user's editor and restores the previous contents if
+/// `validate` rejects the edited file.
+fn edit_config(path: &Path, validate: impl Fn() -> Result<()>) -> Result<()> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .map_err(|_| crate::error!("No editor configured, set $EDITOR to edit config files"))?;
+    let mut args = editor.split_whitespace();
+    let program = args
+        .next()
+        .ok_or_else(|| crate::error!("No editor configured, set $EDITOR to edit config files"))?;
+
+    let backup = path.with_extension("toml.bak");
+    fs::copy(path, &backup)?;
+
+    let status = process::Command::new(program)
+        .args(args)
+        .arg(path)
+        .status()?;
+    if !status.success() {
+        fs::rename(&backup, path)?;
+        return Err(crate::error!(
+            "Editor exited with {}, changes discarded",
+            status
+        ));
+    }
+
+    if let Err(e) = validate() {
+        fs::rename(&backup, path)?;
+        return Err(crate::error!(
+            source = e,
+            "Edited config is invalid, restored the previous version"
+        ));
+    }
+
+    fs::remove_file(&backup)?;
+    Ok(())
+}
+
+/// How long `poof doctor` waits for the relay and direct addresses.
+const DOCTOR_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Prints checks as they complete and counts the failed ones.
+#[derive(Default)]
+struct Checklist {
+    failures: usize,
+}
+
+impl Checklist {
+    fn pass(&self, msg: String) {
+        logging::message(Level::Success, msg);
+    }
+
+    fn fail(&mut self, msg: String) {
+        self.failures += 1;
+        logging::message(Level::Error, msg);
+    }
+
+    /// Fails on every broken entry, or passes once for all of them.
+    fn entries<T>(
+        &mut self,
+        what: &str,
+        entries: &Result<Vec<T>>,
+        check: impl Fn(&T) -> Result<()>,
+    ) {
+        let entries = match entries {
+            Ok(entries) => entries,
+            Err(e) => return self.fail(format!("Could not read {}: {}", what, e)),
+        };
+        let failures = self.failures;
+        for entry in entries {
+            if let Err(e) = check(entry) {
+                self.fail(e.to_string());
+            }
+        }
+        if self.failures == failures {
+            self.pass(format!("{} {} parse", entries.len(), what));
+        }
+    }
+}
+
+/// Collects the disk store, keeping what the saved tickets of the last drop
+/// point at while they haven't expired since `drop --resume` serves them.
+pub async fn handle_gc_command(older_than: Option<Duration>, dry_run: bool) -> Result<()> {
+    let dir = CONFIG_DIRECTORY.join(BLOBS_DIRECTORY);
+    if !dir.exists() {
+        info!("The blob store is empty");
+        return Ok(());
+    }
+    let Some(_lock) = store::lock(&dir)? else {
+        return Err(crate::error!(
+            "The blob store is in use by another poof process"
+        ));
+    };
+    let cutoff = match older_than {
+        Some(age) => Some(SystemTime::now().checked_sub(age).ok_or_else(|| {
+            crate::error!(
+                "--older-than {} is too far back",
+                humantime::format_duration(age)
+            )
+        })?),
+        None => None,
+    };
+    let live = TicketStore::persistent()
+        .saved()?
+        .into_iter()
+        .filter(|ticket| !ticket.is_expired())
+        .map(|ticket| {
+            let format = if ticket.is_collection() {
+                BlobFormat::HashSeq
+            } else {
+                BlobFormat::Raw
+            };
+            Ok(HashAndFormat::new(ticket.hash()?, format))
+        })
+        .collect::<Result<HashSet<_>>>()?;
+
+    let disk = iroh_blobs::store::fs::Store::load(&dir).await?;
+    let collected = store::collect(&disk, &live, cutoff, dry_run).await;
+    disk.shutdown().await;
+    let collected = collected?;
+
+    for (hash, size) in &collected.blobs {
+        logging::emit(format!(
+            "  {} {}",
+            hash.fmt_short(),
+            format_bytes(*size).dimmed()
+        ));
+    }
+    let blobs = match collected.blobs.len() {
+        1 => "1 blob".to_string(),
+        n => format!("{} blobs", n),
+    };
+    if dry_run {
+        info!(
+            "Would free {} by removing {}",
+            format_bytes(collected.bytes()).bold(),
+            blobs
+        );
+    } else {
+        success!(
+            "Freed {} by removing {}",
+            format_bytes(collected.bytes()).bold(),
+            blobs
+        );
+    }
+    Ok(())
+}
+
+/// Runs every check, even after one fails, and only errors at the end.
+pub async fn handle_doctor_command(
+    key: Option<&str>,
+    network: &NetworkOpts,
+    host_manager: &HostManager,
+    key_manager: &KeyManager,
+    history_manager: &HistoryManager,
+    settings_manager: &SettingsManager,
+) -> Result<()> {
+    let mut checks = Checklist::default();
+
+    match probe_writable(&CONFIG_DIRECTORY) {
+        Ok(()) => checks.pass(format!(
+            "Config directory {} is writable",
+            CONFIG_DIRECTORY.display()
+        )),
+        Err(e) => checks.fail(format!(
+            "Config directory {} is not writable: {}",
+            CONFIG_DIRECTORY.display(),
+            e
+        )),
+    }
+
+    let keys = key_manager.list_keys(None);
+    // Encrypted keys are only checked against their stored public key, so no
+    // passphrase is asked for
+    checks.entries("keys", &keys, |key| {
+        if key.is_encrypted() {
+            key.public_key().map(|_| ())
+        } else {
+            key.validate()
+        }
+    });
+    checks.entries("hosts", &host_manager.list_hosts(None), |host| {
+        host.public_key().map(|_| ())
+    });
+    match history_manager.load() {
+        Ok(history) => checks.pass(format!("{} history entries parse", history.entries.len())),
+        Err(e) => checks.fail(format!("Could not read history: {}", e)),
+    }
+    match settings_manager
+        .load()
+        .and_then(|settings| settings.validate())
+    {
+        Ok(()) => checks.pass("Config defaults parse".to_string()),
+        Err(e) => checks.fail(format!("Could not read config.toml: {}", e)),
+    }
+
+    let name = match key {
+        Some(key) => Some(key.to_string()),
+        None => key_manager.default_key_name().unwrap_or_default(),
+    };
+    match (name, keys) {
+        (Some(name), Ok(keys)) => match keys.iter().find(|key| key.name == name) {
+            Some(key) => match key.public_key() {
+                Ok(public_key) => {
+                    checks.pass(format!("Node id {} from key '{}'", public_key, name.bold()))
+                }
+                Err(e) => checks.fail(e.to_string()),
+            },
+            None => checks.fail(format!("Key '{}' not found", name)),
+        },
+        (None, Ok(_)) => info!("No default key yet, one is generated on the first drop or catch"),
+        // Already reported above
+        (_, Err(_)) => {}
+    }
+
+    // A throwaway key keeps the check from unlocking or generating one
+    let endpoint = match bind_endpoint(SecretKey::generate(&mut OsRng), network, None).await {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            checks.fail(format!("Could not bind the endpoint: {}", e));
+            return doctor_summary(checks);
+        }
+    };
+    let (v4, v6) = endpoint.bound_sockets();
+    checks.pass(match v6 {
+        Some(v6) => format!("Endpoint bound to {} and {}", v4, v6),
+        None => format!("Endpoint bound to {}", v4),
+    });
+
+    if network.no_relay {
+        info!("Relays are disabled, skipping the relay check");
+    } else {
+        match tokio::time::timeout(DOCTOR_TIMEOUT, endpoint.home_relay().initialized()).await {
+            Ok(Ok(relay)) => checks.pass(format!("Relay {} is reachable", relay)),
+            _ => checks.fail(format!(
+                "No relay reachable within {}, nodes on other networks won't find this one",
+                format_duration(DOCTOR_TIMEOUT)
+            )),
+        }
+    }
+
+    match tokio::time::timeout(DOCTOR_TIMEOUT, endpoint.direct_addresses().initialized()).await {
+        Ok(Ok(addrs)) => checks.pass(format!(
+            "Direct addresses: {}",
+            addrs
+                .iter()
+                .map(|addr| addr.addr.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+        _ => checks.fail(format!(
+            "No direct addresses found within {}",
+            format_duration(DOCTOR_TIMEOUT)
+        )),
+    }
+
+    endpoint.close().await;
+    doctor_summary(checks)
+}
+
+fn doctor_summary(checks: Checklist) -> Result<()> {
+    if checks.failures > 0 {
+        return Err(crate::error!("{} check(s) failed", checks.failures));
+    }
+    success!("Everything looks good");
+    Ok(())
+}
+
+/// Creates the directory if needed and writes a file into it.
+fn probe_writable(dir: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let probe = dir.join(".doctor");
+    fs::write(&probe, b"")?;
+    fs::remove_file(&probe)
+}
@@ -1,11 +1,18 @@
 use crate::Result;
 use facet::Facet;
 use std::fs;
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
-pub trait ConfigManager<'a, T>
+/// Loads and saves a config file. `T` must not borrow from the file's
+/// content, so saving takes any reference to it.
+///
+/// Methods changing the file hold [`ConfigManager::lock`] from loading to
+/// saving, so concurrent poof processes don't undo each other's changes.
+/// Readers skip it, saves replace the file in one step.
+pub trait ConfigManager<T>
 where
-    T: Facet<'a> + Default,
+    T: for<'a> Facet<'a> + Default,
 {
     fn config_path(&self) -> PathBuf;
 
@@ -21,18 +28,112 @@ where
         Ok(config)
     }
 
-    fn save(&self, config: &'a T) -> Result<()> {
+    fn save(&self, config: &T) -> Result<()> {
         let path = self.config_path();
         if let Some(parent) = path.parent() {
             fs::create_dir_all(parent)?;
         }
 
         let content = facet_toml::to_string(config)?;
-        fs::write(&path, content)?;
+        write_atomic(&path, content.as_bytes())?;
         Ok(())
     }
 
+    /// Takes an advisory lock on a `.lock` file next to the config, waiting
+    /// for other processes to release theirs. Held until the file is dropped.
+    fn lock(&self) -> Result<fs::File> {
+        let path = self.config_path();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path.with_file_name(format!("{}.lock", name)))?;
+        file.lock()?;
+        Ok(file)
+    }
+
     fn exists(&self) -> bool {
         self.config_path().exists()
     }
 }
+
+/// Writes `content` to a temporary file next to `path` and renames it over
+/// `path`, so a crash or a concurrent save never leaves a truncated file
+/// behind. The replaced file's permissions are kept.
+fn write_atomic(path: &Path, content: &[u8]) -> Result<()> {
+    let tmp = tmp_path(path);
+    let write = || -> std::io::Result<()> {
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(content)?;
+        file.sync_all()?;
+        if let Ok(metadata) = fs::metadata(path) {
+            fs::set_permissions(&tmp, metadata.permissions())?;
+        }
+        // Replaces the target in one step, on Windows too
+        fs::rename(&tmp, path)
+    };
+    write().inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })?;
+    Ok(())
+}
+
+/// Where [`write_atomic`] stages the new content of `path`, unique per process.
+fn tmp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Default, Facet)]
+    struct Counter {
+        #[facet(default)]
+        count: u64,
+    }
+
+    struct CounterManager(PathBuf);
+
+    impl ConfigManager<Counter> for CounterManager {
+        fn config_path(&self) -> PathBuf {
+            self.0.clone()
+        }
+    }
+
+    #[test]
+    fn interrupted_writes_keep_the_original() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = CounterManager(dir.path().join("counter.toml"));
+        manager.save(&Counter { count: 1 }).unwrap();
+        assert!(!tmp_path(&manager.0).exists());
+
+        // A save that died before renaming leaves its half written file behind
+        fs::write(tmp_path(&manager.0), "count = ").unwrap();
+        assert_eq!(manager.load().unwrap().count, 1);
+
+        manager.save(&Counter { count: 2 }).unwrap();
+        assert_eq!(manager.load().unwrap().count, 2);
+        assert!(!tmp_path(&manager.0).exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn saves_keep_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let manager = CounterManager(dir.path().join("counter.toml"));
+        manager.save(&Counter { count: 1 }).unwrap();
+        fs::set_permissions(&manager.0, fs::Permissions::from_mode(0o600)).unwrap();
+
+        manager.save(&Counter { count: 2 }).unwrap();
+        let mode = fs::metadata(&manager.0).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
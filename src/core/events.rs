@@ -0,0 +1,227 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::SystemTime,
+};
+
+use dashmap::DashMap;
+use facet::Facet;
+use futures_lite::future::Boxed as BoxedFuture;
+use iroh::{NodeId, endpoint::Connection, protocol::ProtocolHandler};
+use iroh_blobs::{
+    net_protocol::Blobs,
+    provider::{self, CustomEventSender},
+    store::Store,
+};
+use owo_colors::OwoColorize;
+use tokio::sync::broadcast;
+
+use crate::utils::format::{ReducedId, format_duration};
+
+const EVENTS_CAPACITY: usize = 128;
+
+#[derive(Debug, Clone, Copy)]
+pub enum EventKind {
+    Accept,
+    Serve,
+    Complete,
+    Error,
+    /// A ticket the host stopped serving
+    Remove,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Accept => "accept",
+            EventKind::Serve => "serve",
+            EventKind::Complete => "complete",
+            EventKind::Error => "error",
+            EventKind::Remove => "remove",
+        }
+    }
+}
+
+/// Something that happened while serving, independent of tracing logs.
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub kind: EventKind,
+    pub timestamp: SystemTime,
+    pub node_id: Option<NodeId>,
+    pub detail: String,
+}
+
+#[derive(Debug, Facet)]
+struct EventLine {
+    timestamp: String,
+    event: String,
+    node_id: Option<String>,
+    detail: String,
+}
+
+impl Event {
+    pub fn to_line(&self, json: bool) -> String {
+        let timestamp = humantime::format_rfc3339_seconds(self.timestamp).to_string();
+        if json {
+            return facet_json::to_string(&EventLine {
+                timestamp,
+                event: self.kind.as_str().to_string(),
+                node_id: self.node_id.map(|id| id.to_string()),
+                detail: self.detail.clone(),
+            });
+        }
+
+        let kind = format!("{:<8}", self.kind.as_str());
+        let kind = match self.kind {
+            EventKind::Error => kind.red().to_string(),
+            EventKind::Complete => kind.green().to_string(),
+            _ => kind.blue().to_string(),
+        };
+        let node = self
+            .node_id
+            .map(|id| id.reduced())
+            .unwrap_or_else(|| "-".to_string());
+        format!("{} {} {} {}", timestamp.dimmed(), kind, node, self.detail)
+    }
+}
+
+/// Broadcasts serving events to whoever subscribed, dropping them when nobody listens.
+#[derive(Debug, Clone)]
+pub struct Events(broadcast::Sender<Event>);
+
+impl Default for Events {
+    fn default() -> Self {
+        Self(broadcast::channel(EVENTS_CAPACITY).0)
+    }
+}
+
+impl Events {
+    pub fn emit(&self, kind: EventKind, node_id: Option<NodeId>, detail: impl Into<String>) {
+        let _ = self.0.send(Event {
+            kind,
+            timestamp: SystemTime::now(),
+            node_id,
+            detail: detail.into(),
+        });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.0.subscribe()
+    }
+}
+
+/// Counts connections still being served, so shutdown can wait for them.
+#[derive(Debug, Clone, Default)]
+pub struct ActiveTransfers(Arc<AtomicUsize>);
+
+impl ActiveTransfers {
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Counts one more transfer until the returned guard is dropped.
+    pub fn track(&self) -> TransferGuard {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        TransferGuard(self.0.clone())
+    }
+}
+
+pub struct TransferGuard(Arc<AtomicUsize>);
+
+impl Drop for TransferGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Translates iroh-blobs provider events into [`Event`]s.
+#[derive(Debug, Clone)]
+pub struct BlobEvents {
+    events: Events,
+    peers: Arc<DashMap<u64, NodeId>>,
+}
+
+impl BlobEvents {
+    pub fn new(events: Events) -> Self {
+        Self {
+            events,
+            peers: Default::default(),
+        }
+    }
+
+    /// Wraps the blobs protocol so transfer events can be attributed to a node.
+    pub fn handler<S: Store>(&self, blobs: Blobs<S>, active: ActiveTransfers) -> BlobsHandler<S> {
+        BlobsHandler {
+            inner: blobs,
+            peers: self.peers.clone(),
+            active,
+        }
+    }
+
+    fn peer(&self, connection_id: u64) -> Option<NodeId> {
+        self.peers.get(&connection_id).map(|id| *id)
+    }
+}
+
+impl CustomEventSender for BlobEvents {
+    fn send(&self, event: provider::Event) -> BoxedFuture<()> {
+        self.try_send(event);
+        Box::pin(async {})
+    }
+
+    fn try_send(&self, event: provider::Event) {
+        match event {
+            provider::Event::TransferCompleted {
+                connection_id,
+                stats,
+                ..
+            } => self.events.emit(
+                EventKind::Complete,
+                self.peer(connection_id),
+                format!(
+                    "sent {} bytes in {}",
+                    stats.send.total().size,
+                    format_duration(stats.duration)
+                ),
+            ),
+            provider::Event::TransferAborted { connection_id, .. } => self.events.emit(
+                EventKind::Error,
+                self.peer(connection_id),
+                "transfer aborted",
+            ),
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BlobsHandler<S> {
+    inner: Blobs<S>,
+    peers: Arc<DashMap<u64, NodeId>>,
+    active: ActiveTransfers,
+}
+
+impl<S: Store> ProtocolHandler for BlobsHandler<S> {
+    fn accept(&self, connection: Connection) -> BoxedFuture<anyhow::Result<()>> {
+        let connection_id = connection.stable_id() as u64;
+        if let Ok(node_id) = connection.remote_node_id() {
+            self.peers.insert(connection_id, node_id);
+        }
+
+        let inner = self.inner.accept(connection);
+        let peers = self.peers.clone();
+        let guard = self.active.track();
+        Box::pin(async move {
+            let _guard = guard;
+            let result = inner.await;
+            peers.remove(&connection_id);
+            result
+        })
+    }
+
+    fn shutdown(&self) -> BoxedFuture<()> {
+        self.inner.shutdown()
+    }
+}
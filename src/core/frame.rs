@@ -0,0 +1,330 @@
+//! The messages catchers and hosts exchange on a poof stream.
+//!
+//! Every payload travels as a frame, its length as a big endian `u32`
+//! followed by that many bytes. Lengths are checked against a cap before
+//! anything is allocated, so a peer can't make the other side reserve
+//! gigabytes by announcing a huge frame.
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::core::ticket::{Opcode, ResponseCode};
+
+/// Longest query a catcher may send.
+pub const MAX_QUERY_SIZE: usize = 4 * 1024;
+/// Longest password a catcher may send.
+pub const MAX_PASSWORD_SIZE: usize = 1024;
+/// Longest ticket a host may send, directory tickets carry every entry name.
+pub const MAX_TICKET_SIZE: usize = 8 * 1024 * 1024;
+/// Longest listing a host may send, large enough for many drops.
+pub const MAX_LISTING_SIZE: usize = 16 * 1024 * 1024;
+/// Longest reason or candidate list sent along an error code.
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum FrameError {
+    #[error("{kind} of {size} bytes exceeds the {max} byte limit")]
+    TooLarge {
+        kind: &'static str,
+        size: usize,
+        max: usize,
+    },
+
+    #[error("Unknown {kind} {value}")]
+    UnknownKind { kind: &'static str, value: u8 },
+
+    #[error("Invalid UTF-8 in {kind}: {source}")]
+    InvalidUtf8 {
+        kind: &'static str,
+        source: std::string::FromUtf8Error,
+    },
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Writes `payload` as a single frame.
+pub async fn write_frame(
+    send: &mut (impl AsyncWrite + Unpin),
+    payload: &[u8],
+) -> Result<(), FrameError> {
+    send.write_u32(payload.len() as u32).await?;
+    send.write_all(payload).await?;
+    Ok(())
+}
+
+/// Reads a frame of at most `max` bytes, `kind` names it in errors.
+pub async fn read_frame(
+    recv: &mut (impl AsyncRead + Unpin),
+    kind: &'static str,
+    max: usize,
+) -> Result<Vec<u8>, FrameError> {
+    let size = recv.read_u32().await? as usize;
+    read_payload(recv, kind, size, max).await
+}
+
+/// Like [`read_frame`], but `None` when the stream ends instead.
+async fn read_optional_frame(
+    recv: &mut (impl AsyncRead + Unpin),
+    kind: &'static str,
+    max: usize,
+) -> Result<Option<Vec<u8>>, FrameError> {
+    match recv.read_u32().await {
+        Ok(size) => Ok(Some(read_payload(recv, kind, size as usize, max).await?)),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn read_payload(
+    recv: &mut (impl AsyncRead + Unpin),
+    kind: &'static str,
+    size: usize,
+    max: usize,
+) -> Result<Vec<u8>, FrameError> {
+    if size > max {
+        return Err(FrameError::TooLarge { kind, size, max });
+    }
+    let mut payload = vec![0; size];
+    recv.read_exact(&mut payload).await?;
+    Ok(payload)
+}
+
+fn into_string(payload: Vec<u8>, kind: &'static str) -> Result<String, FrameError> {
+    String::from_utf8(payload).map_err(|source| FrameError::InvalidUtf8 { kind, source })
+}
+
+/// What a catcher asks for, one per stream.
+#[derive(Debug)]
+pub enum Request {
+    /// The ticket behind `query`, empty when the catcher sent none
+    Query {
+        query: String,
+        password: Option<String>,
+    },
+    /// Every ticket the host serves
+    List,
+    /// Nothing but an empty [`ResponseCode::Ok`]
+    Ping,
+}
+
+impl Request {
+    /// Writes the request, without its opcode for `legacy` hosts which only
+    /// understand queries.
+    pub async fn write(
+        &self,
+        send: &mut (impl AsyncWrite + Unpin),
+        legacy: bool,
+    ) -> Result<(), FrameError> {
+        match self {
+            Request::Query { query, password } => {
+                if !legacy {
+                    send.write_u8(Opcode::Query.to_u8()).await?;
+                }
+                write_frame(send, query.as_bytes()).await?;
+                // The password frame is optional, without one the stream just ends
+                if let Some(password) = password {
+                    write_frame(send, password.as_bytes()).await?;
+                }
+            }
+            Request::List => send.write_u8(Opcode::List.to_u8()).await?,
+            Request::Ping => send.write_u8(Opcode::Ping.to_u8()).await?,
+        }
+        Ok(())
+    }
+
+    /// Reads a request, `legacy` catchers send the query without an opcode.
+    pub async fn read(
+        recv: &mut (impl AsyncRead + Unpin),
+        legacy: bool,
+    ) -> Result<Self, FrameError> {
+        let opcode = if legacy {
+            Opcode::Query
+        } else {
+            let value = recv.read_u8().await?;
+            Opcode::from_u8(value).ok_or(FrameError::UnknownKind {
+                kind: "opcode",
+                value,
+            })?
+        };
+
+        match opcode {
+            Opcode::List => Ok(Request::List),
+            Opcode::Ping => Ok(Request::Ping),
+            Opcode::Query => {
+                let query = read_frame(recv, "Query", MAX_QUERY_SIZE).await?;
+                if query.is_empty() {
+                    return Ok(Request::Query {
+                        query: String::new(),
+                        password: None,
+                    });
+                }
+                let password = read_optional_frame(recv, "Password", MAX_PASSWORD_SIZE)
+                    .await?
+                    .map(|password| into_string(password, "password"))
+                    .transpose()?;
+                Ok(Request::Query {
+                    query: into_string(query, "query")?,
+                    password,
+                })
+            }
+        }
+    }
+}
+
+/// A host's answer to a [`Request`], the payload depending on the code.
+#[derive(Debug)]
+pub struct Response {
+    pub code: ResponseCode,
+    pub payload: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(code: ResponseCode, payload: impl Into<Vec<u8>>) -> Self {
+        Self {
+            code,
+            payload: payload.into(),
+        }
+    }
+
+    /// A response carrying nothing but its code.
+    pub fn empty(code: ResponseCode) -> Self {
+        Self::new(code, Vec::new())
+    }
+
+    pub async fn write(&self, send: &mut (impl AsyncWrite + Unpin)) -> Result<(), FrameError> {
+        send.write_u8(self.code.to_u8()).await?;
+        write_frame(send, &self.payload).await
+    }
+
+    /// Reads a response, whose payload may be up to `max` bytes when it is
+    /// [`ResponseCode::Ok`] and [`MAX_MESSAGE_SIZE`] otherwise.
+    pub async fn read(recv: &mut (impl AsyncRead + Unpin), max: usize) -> Result<Self, FrameError> {
+        let value = recv.read_u8().await?;
+        let code = ResponseCode::from_u8(value).ok_or(FrameError::UnknownKind {
+            kind: "response code",
+            value,
+        })?;
+        let payload = match code {
+            ResponseCode::Ok => read_frame(recv, "Response", max).await?,
+            _ => read_frame(recv, "Message", MAX_MESSAGE_SIZE).await?,
+        };
+        Ok(Self { code, payload })
+    }
+
+    /// The payload as text, for codes that carry a message or candidates.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.payload).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A length prefix announcing the largest frame there can be, with
+    /// nothing behind it: reading past the prefix would fail on EOF instead.
+    const HUGE_PREFIX: [u8; 4] = u32::MAX.to_be_bytes();
+
+    fn assert_too_large<T: std::fmt::Debug>(res: Result<T, FrameError>, expected: &str) {
+        match res {
+            Err(FrameError::TooLarge { kind, size, .. }) => {
+                assert_eq!(kind, expected);
+                assert_eq!(size, u32::MAX as usize);
+            }
+            other => panic!("expected an oversized {expected}, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_frames_before_allocating() {
+        let res = read_frame(&mut &HUGE_PREFIX[..], "Query", MAX_QUERY_SIZE).await;
+        assert_too_large(res, "Query");
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_queries() {
+        let mut request = vec![Opcode::Query.to_u8()];
+        request.extend(HUGE_PREFIX);
+        assert_too_large(Request::read(&mut &request[..], false).await, "Query");
+        // Legacy catchers send the query without an opcode
+        assert_too_large(Request::read(&mut &HUGE_PREFIX[..], true).await, "Query");
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_passwords() {
+        let mut request = vec![Opcode::Query.to_u8()];
+        write_frame(&mut request, b"abcdef").await.unwrap();
+        request.extend(HUGE_PREFIX);
+        assert_too_large(Request::read(&mut &request[..], false).await, "Password");
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_responses() {
+        let mut response = vec![ResponseCode::Ok.to_u8()];
+        response.extend(HUGE_PREFIX);
+        assert_too_large(
+            Response::read(&mut &response[..], MAX_TICKET_SIZE).await,
+            "Response",
+        );
+    }
+
+    #[tokio::test]
+    async fn caps_error_messages_below_the_payload_limit() {
+        let mut response = vec![ResponseCode::Error.to_u8()];
+        response.extend(((MAX_MESSAGE_SIZE + 1) as u32).to_be_bytes());
+        match Response::read(&mut &response[..], MAX_LISTING_SIZE).await {
+            Err(FrameError::TooLarge { kind, max, .. }) => {
+                assert_eq!(kind, "Message");
+                assert_eq!(max, MAX_MESSAGE_SIZE);
+            }
+            other => panic!("expected an oversized message, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_requests_back() {
+        let mut send = Vec::new();
+        let request = Request::Query {
+            query: "abcdef".to_string(),
+            password: Some("hunter2".to_string()),
+        };
+        request.write(&mut send, false).await.unwrap();
+        match Request::read(&mut &send[..], false).await.unwrap() {
+            Request::Query { query, password } => {
+                assert_eq!(query, "abcdef");
+                assert_eq!(password.as_deref(), Some("hunter2"));
+            }
+            other => panic!("expected a query, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_every_response_code_back() {
+        for value in 0..=6 {
+            let code = ResponseCode::from_u8(value).unwrap();
+            let mut send = Vec::new();
+            Response::new(code, "payload")
+                .write(&mut send)
+                .await
+                .unwrap();
+            let response = Response::read(&mut &send[..], MAX_TICKET_SIZE)
+                .await
+                .unwrap();
+            assert_eq!(response.code.to_u8(), value);
+            assert_eq!(response.text(), "payload");
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_response_codes() {
+        let mut response = vec![7];
+        write_frame(&mut response, b"payload").await.unwrap();
+        match Response::read(&mut &response[..], MAX_TICKET_SIZE).await {
+            Err(FrameError::UnknownKind { kind, value }) => {
+                assert_eq!(kind, "response code");
+                assert_eq!(value, 7);
+            }
+            other => panic!("expected an unknown response code, got {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use super::config::ConfigManager;
+use crate::Result;
+use crate::utils::constants::{CONFIG_DIRECTORY, HISTORY_FILE, HISTORY_LIMIT};
+use facet::Facet;
+use iroh::NodeId;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+// Transfer details default to empty so entries recorded before they existed
+// still load. They are not `Option`s, facet-toml writes `None` inside an array
+// of tables as an empty table it can't read back.
+#[derive(Debug, Clone, Facet)]
+pub struct HistoryEntry {
+    /// Host alias, or node id when the host wasn't saved
+    pub host: String,
+    /// Empty for entries without transfer details
+    #[facet(default)]
+    pub node_id: String,
+    pub query: String,
+    /// Empty when the file was written to stdout
+    #[facet(default)]
+    pub filename: String,
+    #[facet(default)]
+    pub bytes: u64,
+    #[facet(default)]
+    pub duration_ms: u64,
+    pub caught_at: u64,
+}
+
+impl HistoryEntry {
+    pub fn new(
+        host: String,
+        node_id: NodeId,
+        query: String,
+        filename: Option<String>,
+        bytes: u64,
+        duration: Duration,
+    ) -> Self {
+        Self {
+            host,
+            node_id: node_id.to_string(),
+            query,
+            filename: filename.unwrap_or_default(),
+            bytes,
+            duration_ms: duration.as_millis() as u64,
+            caught_at: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+
+    pub fn caught_at(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(self.caught_at)
+    }
+
+    pub fn has_transfer(&self) -> bool {
+        !self.node_id.is_empty()
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.duration_ms)
+    }
+}
+
+#[derive(Debug, Clone, Facet, Default)]
+pub struct History {
+    /// Maximum number of entries kept, [`HISTORY_LIMIT`] when unset
+    pub limit: Option<usize>,
+    /// Oldest first
+    #[facet(default)]
+    pub entries: Vec<HistoryEntry>,
+}
+
+impl History {
+    pub fn record(&mut self, entry: HistoryEntry) {
+        self.entries
+            .retain(|e| !(e.host == entry.host && e.query == entry.query));
+        self.entries.push(entry);
+
+        let limit = self.limit.unwrap_or(HISTORY_LIMIT);
+        let overflow = self.entries.len().saturating_sub(limit);
+        self.entries.drain(..overflow);
+    }
+
+    /// Most recent first, optionally restricted to one host
+    pub fn recent(&self, host: Option<&str>) -> Vec<&HistoryEntry> {
+        self.entries
+            .iter()
+            .rev()
+            .filter(|e| host.is_none_or(|host| e.host == host))
+            .collect()
+    }
+}
+
+#[derive(Default)]
+pub struct HistoryManager;
+
+impl ConfigManager<History> for HistoryManager {
+    fn config_path(&self) -> PathBuf {
+        CONFIG_DIRECTORY.join(HISTORY_FILE)
+    }
+}
+
+impl HistoryManager {
+    pub fn new() -> Self {
+        Self
+    }
+
+    pub fn record(&self, entry: HistoryEntry) -> Result<()> {
+        let _lock = self.lock()?;
+        let mut history = self.load()?;
+        history.record(entry);
+        self.save(&history)
+    }
+
+    pub fn list(&self, host: Option<&str>) -> Result<Vec<HistoryEntry>> {
+        let history = self.load()?;
+        Ok(history.recent(host).into_iter().cloned().collect())
+    }
+
+    /// Forgets every entry but keeps the configured limit.
+    pub fn clear(&self) -> Result<usize> {
+        let _lock = self.lock()?;
+        let history = self.load()?;
+        self.save(&History {
+            limit: history.limit,
+            entries: Vec::new(),
+        })?;
+        Ok(history.entries.len())
+    }
+}
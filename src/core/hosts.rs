@@ -1,6 +1,8 @@
 use super::config::ConfigManager;
 use crate::utils::constants::{CONFIG_DIRECTORY, KEYS_FILE};
-use crate::{Result, error};
+use crate::utils::format::Fingerprint;
+use crate::utils::secret;
+use crate::{PoofError, Result, error};
 use facet::Facet;
 use iroh::{PublicKey, SecretKey};
 use std::collections::HashMap;
@@ -42,12 +44,34 @@ impl Host {
         );
     }
 
-    pub fn add_metadata(&mut self, key: String, value: String) {
-        self.metadata.insert(key, value);
+    /// Returns the value `key` held before, if any.
+    pub fn add_metadata(&mut self, key: String, value: String) -> Option<String> {
+        self.metadata.insert(key, value)
     }
 
-    pub fn public_key(&self) -> PublicKey {
-        PublicKey::from_str(&self.public_key).expect("Invalid public key format")
+    pub fn remove_metadata(&mut self, key: &str) -> Option<String> {
+        self.metadata.remove(key)
+    }
+
+    /// Case-insensitive match against the alias, description and metadata values.
+    pub fn matches(&self, filter: &str) -> bool {
+        let filter = filter.to_lowercase();
+        std::iter::once(&self.alias)
+            .chain(&self.description)
+            .chain(self.metadata.values())
+            .any(|field| field.to_lowercase().contains(&filter))
+    }
+
+    pub fn public_key(&self) -> Result<PublicKey> {
+        PublicKey::from_str(&self.public_key).map_err(|e| PoofError::InvalidEntry {
+            message: format!("Host '{}' has an invalid public key", self.alias),
+            file: "hosts",
+            source: Box::new(e),
+        })
+    }
+
+    pub fn fingerprint(&self) -> Result<String> {
+        Ok(self.public_key()?.fingerprint())
     }
 
     pub fn added_at(&self) -> SystemTime {
@@ -59,6 +83,36 @@ impl Host {
     }
 }
 
+/// Longest host alias or key name accepted.
+const MAX_NAME_LENGTH: usize = 64;
+
+/// Checks that a host alias or key name only uses `[a-zA-Z0-9_.-]`, so it
+/// survives URIs, shell completion and config files unquoted. `kind` names
+/// what is checked in the error.
+fn validate_name(kind: &str, name: &str) -> Result<()> {
+    if name.is_empty() {
+        return Err(error!("{} cannot be empty", kind));
+    }
+    if name.len() > MAX_NAME_LENGTH {
+        return Err(error!(
+            "{} '{}' is longer than {} characters",
+            kind, name, MAX_NAME_LENGTH
+        ));
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-')))
+    {
+        return Err(error!(
+            "{} '{}' contains '{}', only letters, digits, '_', '.' and '-' are allowed",
+            kind,
+            name,
+            c.escape_default()
+        ));
+    }
+    Ok(())
+}
+
 #[derive(Debug, Clone, Facet, Default)]
 pub struct HostConfig {
     // alias -> host
@@ -67,6 +121,7 @@ pub struct HostConfig {
 
 impl HostConfig {
     pub fn add_host(&mut self, host: Host) -> Result<()> {
+        validate_name("Host alias", &host.alias)?;
         if self.hosts.contains_key(&host.alias) {
             return Err(error!("Host with alias '{}' already exists", host.alias));
         }
@@ -105,11 +160,20 @@ impl HostConfig {
             .find(|host| host.public_key == public_key.to_string())
     }
 
+    /// Expects `fingerprint` in the form [`Fingerprint`] produces; hosts with
+    /// an invalid public key never match.
+    pub fn find_by_fingerprint(&self, fingerprint: &str) -> Option<&Host> {
+        self.hosts
+            .values()
+            .find(|host| host.fingerprint().is_ok_and(|fp| fp == fingerprint))
+    }
+
     pub fn list_hosts(&self) -> Vec<&Host> {
         self.hosts.values().collect()
     }
 
     pub fn update_host_alias(&mut self, old_alias: &str, new_alias: String) -> Result<()> {
+        validate_name("Host alias", &new_alias)?;
         if self.hosts.contains_key(&new_alias) {
             return Err(error!("Host with alias '{}' already exists", new_alias));
         }
@@ -118,6 +182,25 @@ impl HostConfig {
         host.alias = new_alias;
         self.add_host(host)
     }
+
+    pub fn swap_host_aliases(&mut self, alias_a: &str, alias_b: &str) -> Result<()> {
+        for alias in [alias_a, alias_b] {
+            if !self.hosts.contains_key(alias) {
+                return Err(error!("Host with alias '{}' not found", alias));
+            }
+        }
+        if alias_a == alias_b {
+            return Ok(());
+        }
+
+        let mut host_a = self.remove_host(alias_a)?;
+        let mut host_b = self.remove_host(alias_b)?;
+        host_a.alias = alias_b.to_string();
+        host_b.alias = alias_a.to_string();
+        self.hosts.insert(host_a.alias.clone(), host_a);
+        self.hosts.insert(host_b.alias.clone(), host_b);
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Facet)]
@@ -126,6 +209,9 @@ pub struct HostKey {
     pub secret_key: String,
     pub created_at: u64,
     pub description: Option<String>,
+    /// Kept next to encrypted secrets so listing keys needs no passphrase
+    pub public_key: Option<String>,
+    pub last_used: Option<u64>,
 }
 
 impl HostKey {
@@ -138,20 +224,115 @@ impl HostKey {
                 .unwrap_or_default()
                 .as_secs(),
             description,
+            public_key: None,
+            last_used: None,
+        }
+    }
+
+    pub fn update_last_used(&mut self) {
+        self.last_used = Some(
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        );
+    }
+
+    /// Seals the secret key with `passphrase`, see [`secret::encrypt`].
+    pub fn encrypt(mut self, passphrase: &str) -> Result<Self> {
+        let secret_key = self.secret_key()?;
+        self.secret_key = secret::encrypt(&secret_key.to_bytes(), passphrase)?;
+        self.public_key = Some(secret_key.public().to_string());
+        Ok(self)
+    }
+
+    /// Case-insensitive match against the name and description.
+    pub fn matches(&self, filter: &str) -> bool {
+        let filter = filter.to_lowercase();
+        std::iter::once(&self.name)
+            .chain(&self.description)
+            .any(|field| field.to_lowercase().contains(&filter))
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        secret::is_encrypted(&self.secret_key)
+    }
+
+    /// Returns a copy holding the plain secret key, prompting for the
+    /// passphrase if it is encrypted.
+    pub fn unlock(&self) -> Result<Self> {
+        if !self.is_encrypted() {
+            return Ok(self.clone());
+        }
+        Ok(Self {
+            secret_key: self.secret_key()?.to_string(),
+            public_key: None,
+            ..self.clone()
+        })
+    }
+
+    pub fn public_key(&self) -> Result<PublicKey> {
+        match &self.public_key {
+            Some(public_key) => {
+                PublicKey::from_str(public_key).map_err(|e| PoofError::InvalidEntry {
+                    message: format!("Key '{}' has an invalid public key", self.name),
+                    file: "keys",
+                    source: Box::new(e),
+                })
+            }
+            None => Ok(self.secret_key()?.public()),
         }
     }
 
-    pub fn public_key(&self) -> PublicKey {
-        self.secret_key().public()
+    /// Parses the secret key, decrypting it first if needed.
+    pub fn secret_key(&self) -> Result<SecretKey> {
+        if self.is_encrypted() {
+            let passphrase =
+                secret::read_passphrase(&format!("Passphrase for key '{}'", self.name), false)?;
+            let bytes = secret::decrypt(&self.secret_key, &passphrase)
+                .map_err(|e| error!("Failed to unlock key '{}': {}", self.name, e))?;
+            let bytes: [u8; 32] = bytes.try_into().map_err(|_| PoofError::InvalidEntry {
+                message: format!("Key '{}' has an invalid secret key", self.name),
+                file: "keys",
+                source: "decrypted secret is not 32 bytes".into(),
+            })?;
+            return Ok(SecretKey::from_bytes(&bytes));
+        }
+
+        SecretKey::from_str(&self.secret_key).map_err(|e| PoofError::InvalidEntry {
+            message: format!("Key '{}' has an invalid secret key", self.name),
+            file: "keys",
+            source: Box::new(e),
+        })
     }
 
-    pub fn secret_key(&self) -> SecretKey {
-        SecretKey::from_str(&self.secret_key).expect("Invalid secret key format")
+    pub fn fingerprint(&self) -> Result<String> {
+        Ok(self.public_key()?.fingerprint())
+    }
+
+    /// Checks that the secret key parses and matches the stored public key,
+    /// prompting for the passphrase if it is encrypted.
+    pub fn validate(&self) -> Result<()> {
+        let secret_key = self.secret_key()?;
+        if let Some(public_key) = &self.public_key
+            && *public_key != secret_key.public().to_string()
+        {
+            return Err(error!(
+                "Key '{}' does not match its public key {}",
+                self.name, public_key
+            ));
+        }
+        Ok(())
     }
 
     pub fn created_at(&self) -> SystemTime {
         SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(self.created_at)
     }
+
+    pub fn last_used(&self) -> Option<SystemTime> {
+        self.last_used
+            .map(|ts| SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(ts))
+    }
 }
 
 #[derive(Debug, Clone, Facet, Default)]
@@ -164,6 +345,7 @@ pub struct KeyConfig {
 
 impl KeyConfig {
     pub fn add_key(&mut self, key: HostKey) -> Result<()> {
+        validate_name("Key name", &key.name)?;
         if self.keys.contains_key(&key.name) {
             return Err(error!("Key with name '{}' already exists", key.name));
         }
@@ -186,7 +368,7 @@ impl KeyConfig {
 
         // Update default if we removed it
         if self.default_key.as_ref() == Some(&key.name) {
-            self.default_key = self.keys.keys().next().map(|k| k.clone());
+            self.default_key = self.keys.keys().next().cloned();
         }
 
         Ok(key)
@@ -213,19 +395,52 @@ impl KeyConfig {
     pub fn list_keys(&self) -> Vec<&HostKey> {
         self.keys.values().collect()
     }
+
+    pub fn rename_key(&mut self, old_name: &str, new_name: String) -> Result<()> {
+        validate_name("Key name", &new_name)?;
+        if self.keys.contains_key(&new_name) {
+            return Err(error!("Key with name '{}' already exists", new_name));
+        }
+
+        let mut key = self
+            .keys
+            .remove(old_name)
+            .ok_or_else(|| error!("Key with name '{}' not found", old_name))?;
+        key.name = new_name.clone();
+        self.keys.insert(new_name.clone(), key);
+
+        if self.default_key.as_deref() == Some(old_name) {
+            self.default_key = Some(new_name);
+        }
+
+        Ok(())
+    }
+}
+
+pub struct HostManager {
+    path: PathBuf,
 }
 
-pub struct HostManager;
+impl Default for HostManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-impl ConfigManager<'_, HostConfig> for HostManager {
+impl ConfigManager<HostConfig> for HostManager {
     fn config_path(&self) -> PathBuf {
-        CONFIG_DIRECTORY.join("hosts.toml")
+        self.path.clone()
     }
 }
 
 impl HostManager {
     pub fn new() -> Self {
-        Self
+        Self::at(CONFIG_DIRECTORY.join("hosts.toml"))
+    }
+
+    /// Manages the hosts saved at `path` instead of the config directory.
+    pub fn at(path: PathBuf) -> Self {
+        Self { path }
     }
 
     pub fn add_host(
@@ -234,6 +449,7 @@ impl HostManager {
         public_key: PublicKey,
         description: Option<String>,
     ) -> Result<()> {
+        let _lock = self.lock()?;
         let mut config = self.load()?;
         let host = Host::new(alias, public_key, description);
         config.add_host(host)?;
@@ -241,15 +457,22 @@ impl HostManager {
     }
 
     pub fn remove_host(&self, alias: &str) -> Result<Host> {
+        let _lock = self.lock()?;
         let mut config = self.load()?;
         let host = config.remove_host(alias)?;
         self.save(&config)?;
         Ok(host)
     }
 
-    pub fn list_hosts(&self) -> Result<Vec<Host>> {
+    /// Lists the hosts, only those [`Host::matches`] accepts when filtering.
+    pub fn list_hosts(&self, filter: Option<&str>) -> Result<Vec<Host>> {
         let config = self.load()?;
-        Ok(config.list_hosts().into_iter().cloned().collect())
+        Ok(config
+            .list_hosts()
+            .into_iter()
+            .filter(|host| filter.is_none_or(|filter| host.matches(filter)))
+            .cloned()
+            .collect())
     }
 
     pub fn get_host(&self, alias: &str) -> Result<Option<Host>> {
@@ -262,7 +485,13 @@ impl HostManager {
         Ok(config.find_by_public_key(public_key).cloned())
     }
 
+    pub fn find_by_fingerprint(&self, fingerprint: &str) -> Result<Option<Host>> {
+        let config = self.load()?;
+        Ok(config.find_by_fingerprint(fingerprint).cloned())
+    }
+
     pub fn update_last_seen(&self, alias: &str) -> Result<()> {
+        let _lock = self.lock()?;
         let mut config = self.load()?;
         if let Some(host) = config.get_host_mut(alias) {
             host.update_last_seen();
@@ -271,16 +500,55 @@ impl HostManager {
         Ok(())
     }
 
+    /// Sets `key` on the host, returning the value it replaced.
+    pub fn set_metadata(&self, alias: &str, key: String, value: String) -> Result<Option<String>> {
+        if key.trim().is_empty() {
+            return Err(error!("Metadata key can't be empty"));
+        }
+
+        let _lock = self.lock()?;
+
+        let mut config = self.load()?;
+        let host = config
+            .get_host_mut(alias)
+            .ok_or_else(|| error!("Host with alias '{}' not found", alias))?;
+        let previous = host.add_metadata(key, value);
+        self.save(&config)?;
+        Ok(previous)
+    }
+
+    pub fn remove_metadata(&self, alias: &str, key: &str) -> Result<String> {
+        let _lock = self.lock()?;
+        let mut config = self.load()?;
+        let host = config
+            .get_host_mut(alias)
+            .ok_or_else(|| error!("Host with alias '{}' not found", alias))?;
+        let value = host
+            .remove_metadata(key)
+            .ok_or_else(|| error!("Host '{}' has no metadata '{}'", alias, key))?;
+        self.save(&config)?;
+        Ok(value)
+    }
+
     pub fn rename_host(&self, old_alias: &str, new_alias: String) -> Result<()> {
+        let _lock = self.lock()?;
         let mut config = self.load()?;
         config.update_host_alias(old_alias, new_alias)?;
         self.save(&config)
     }
+
+    pub fn swap_hosts(&self, alias_a: &str, alias_b: &str) -> Result<()> {
+        let _lock = self.lock()?;
+        let mut config = self.load()?;
+        config.swap_host_aliases(alias_a, alias_b)?;
+        self.save(&config)
+    }
 }
 
+#[derive(Default)]
 pub struct KeyManager;
 
-impl ConfigManager<'_, KeyConfig> for KeyManager {
+impl ConfigManager<KeyConfig> for KeyManager {
     fn config_path(&self) -> PathBuf {
         CONFIG_DIRECTORY.join(KEYS_FILE)
     }
@@ -297,44 +565,260 @@ impl KeyManager {
         secret_key: SecretKey,
         description: Option<String>,
     ) -> Result<()> {
+        self.insert_key(HostKey::new(name, secret_key, description))
+    }
+
+    pub fn insert_key(&self, key: HostKey) -> Result<()> {
+        let _lock = self.lock()?;
         let mut config = self.load()?;
-        let key = HostKey::new(name, secret_key, description);
         config.add_key(key)?;
         self.save(&config)
     }
 
     pub fn remove_key(&self, name: &str) -> Result<HostKey> {
+        let _lock = self.lock()?;
         let mut config = self.load()?;
         let key = config.remove_key(name)?;
         self.save(&config)?;
         Ok(key)
     }
 
+    /// Looks up a key, prompting for its passphrase if it is encrypted.
     pub fn get_key(&self, name: &str) -> Result<Option<HostKey>> {
         let config = self.load()?;
-        Ok(config.get_key(name).cloned())
+        config.get_key(name).map(HostKey::unlock).transpose()
     }
 
+    /// Looks up the default key, prompting for its passphrase if it is encrypted.
     pub fn get_default_key(&self) -> Result<Option<HostKey>> {
         let config = self.load()?;
-        Ok(config.get_default_key().cloned())
+        config.get_default_key().map(HostKey::unlock).transpose()
+    }
+
+    /// Records that the key named `name` was just used.
+    pub fn touch_key(&self, name: &str) -> Result<()> {
+        let _lock = self.lock()?;
+        let mut config = self.load()?;
+        if let Some(key) = config.keys.get_mut(name) {
+            key.update_last_used();
+            self.save(&config)?;
+        }
+        Ok(())
+    }
+
+    pub fn default_key_name(&self) -> Result<Option<String>> {
+        Ok(self.load()?.default_key)
     }
 
     pub fn set_default_key(&self, name: &str) -> Result<()> {
+        let _lock = self.lock()?;
         let mut config = self.load()?;
         config.set_default_key(name.to_string())?;
         self.save(&config)
     }
 
-    pub fn list_keys(&self) -> Result<Vec<HostKey>> {
+    pub fn rename_key(&self, old_name: &str, new_name: String) -> Result<()> {
+        let _lock = self.lock()?;
+        let mut config = self.load()?;
+        config.rename_key(old_name, new_name)?;
+        self.save(&config)
+    }
+
+    /// Lists the keys, only those [`HostKey::matches`] accepts when filtering.
+    pub fn list_keys(&self, filter: Option<&str>) -> Result<Vec<HostKey>> {
         let config = self.load()?;
-        Ok(config.list_keys().into_iter().cloned().collect())
+        Ok(config
+            .list_keys()
+            .into_iter()
+            .filter(|key| filter.is_none_or(|filter| key.matches(filter)))
+            .cloned()
+            .collect())
     }
 
-    pub fn generate_key(&self, name: String, description: Option<String>) -> Result<HostKey> {
+    /// Returns a config holding only `name`, or every key when `name` is `None`.
+    pub fn export(&self, name: Option<&str>) -> Result<KeyConfig> {
+        let config = self.load()?;
+        let Some(name) = name else {
+            return Ok(config);
+        };
+        let key = config
+            .get_key(name)
+            .ok_or_else(|| error!("Key with name '{}' not found", name))?;
+        Ok(KeyConfig {
+            keys: HashMap::from([(key.name.clone(), key.clone())]),
+            default_key: None,
+        })
+    }
+
+    /// Adds every key from an exported config, writing nothing unless all of
+    /// them are valid and none collides with an existing name.
+    pub fn import(&self, imported: KeyConfig) -> Result<Vec<HostKey>> {
+        let _lock = self.lock()?;
+        let mut config = self.load()?;
+        let mut keys: Vec<HostKey> = imported.keys.into_values().collect();
+        // Keep the exported default first so it becomes ours if we have none
+        keys.sort_by_key(|key| imported.default_key.as_ref() != Some(&key.name));
+        for key in &keys {
+            // The entry is not in keys.toml yet, so drop the `config edit` hint
+            key.validate()
+                .map_err(|e| error!(source = e, "Invalid key '{}' in import", key.name))?;
+            config.add_key(key.clone())?;
+        }
+        self.save(&config)?;
+        Ok(keys)
+    }
+
+    /// Swaps the secret of `name` for a fresh one in place, returning the old
+    /// and new key. Encrypted keys stay encrypted, with the passphrase from
+    /// `passphrase`. With `keep_old` the old key is archived as
+    /// `<name>-old-<timestamp>`.
+    pub fn rotate_key(
+        &self,
+        name: &str,
+        keep_old: bool,
+        passphrase: impl FnOnce() -> Result<String>,
+    ) -> Result<(HostKey, HostKey)> {
+        use rand::rngs::OsRng;
+        let _lock = self.lock()?;
+        let mut config = self.load()?;
+        let key = config
+            .keys
+            .get_mut(name)
+            .ok_or_else(|| error!("Key with name '{}' not found", name))?;
+
+        let old = key.clone();
+        let mut rotated = HostKey::new(
+            old.name.clone(),
+            SecretKey::generate(&mut OsRng),
+            old.description.clone(),
+        );
+        if old.is_encrypted() {
+            rotated = rotated.encrypt(&passphrase()?)?;
+        }
+        *key = rotated.clone();
+
+        if keep_old {
+            config.add_key(HostKey {
+                name: format!("{}-old-{}", name, rotated.created_at),
+                ..old.clone()
+            })?;
+        }
+        self.save(&config)?;
+        Ok((old, rotated))
+    }
+
+    pub fn generate_key(
+        &self,
+        name: String,
+        description: Option<String>,
+        passphrase: Option<&str>,
+    ) -> Result<HostKey> {
         use rand::rngs::OsRng;
         let secret_key = SecretKey::generate(&mut OsRng);
-        self.add_key(name.clone(), secret_key.clone(), description)?;
-        Ok(HostKey::new(name, secret_key, None))
+        let mut key = HostKey::new(name, secret_key, description);
+        if let Some(passphrase) = passphrase {
+            key = key.encrypt(passphrase)?;
+        }
+        self.insert_key(key.clone())?;
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+
+    use super::*;
+
+    fn host(alias: &str) -> Host {
+        Host::new(
+            alias.to_string(),
+            SecretKey::generate(&mut OsRng).public(),
+            Some(format!("{alias}'s laptop")),
+        )
+    }
+
+    #[test]
+    fn swapping_aliases_keeps_everything_else() {
+        let mut alice = host("alice");
+        alice.added_at = 100;
+        alice.last_seen = Some(200);
+        alice.add_metadata("location".to_string(), "office".to_string());
+        let mut bob = host("bob");
+        bob.added_at = 300;
+        bob.add_metadata("location".to_string(), "home".to_string());
+
+        let mut config = HostConfig::default();
+        config.add_host(alice.clone()).unwrap();
+        config.add_host(bob.clone()).unwrap();
+        config.swap_host_aliases("alice", "bob").unwrap();
+
+        let swapped = config.get_host("bob").unwrap();
+        assert_eq!(swapped.alias, "bob");
+        assert_eq!(swapped.public_key, alice.public_key);
+        assert_eq!(swapped.description, alice.description);
+        assert_eq!(swapped.added_at, 100);
+        assert_eq!(swapped.last_seen, Some(200));
+        assert_eq!(swapped.metadata, alice.metadata);
+
+        let swapped = config.get_host("alice").unwrap();
+        assert_eq!(swapped.alias, "alice");
+        assert_eq!(swapped.public_key, bob.public_key);
+        assert_eq!(swapped.added_at, 300);
+        assert_eq!(swapped.last_seen, None);
+        assert_eq!(swapped.metadata, bob.metadata);
+    }
+
+    #[test]
+    fn swapping_needs_both_hosts() {
+        let mut config = HostConfig::default();
+        config.add_host(host("alice")).unwrap();
+        let err = config.swap_host_aliases("alice", "carol").unwrap_err();
+        assert!(err.to_string().contains("'carol' not found"), "{err}");
+        // Nothing moved
+        assert!(config.get_host("alice").is_some());
+        assert_eq!(config.hosts.len(), 1);
+    }
+
+    #[test]
+    fn validates_names() {
+        assert!(validate_name("Alias", "a.b-c_1").is_ok());
+        assert!(validate_name("Alias", &"a".repeat(MAX_NAME_LENGTH)).is_ok());
+
+        let err = validate_name("Alias", "").unwrap_err();
+        assert!(err.to_string().contains("cannot be empty"), "{err}");
+        let err = validate_name("Alias", &"a".repeat(MAX_NAME_LENGTH + 1)).unwrap_err();
+        assert!(err.to_string().contains("longer than 64"), "{err}");
+        for (name, c) in [("a/b", "/"), ("a b", " "), ("a\tb", "\\t")] {
+            let err = validate_name("Alias", name).unwrap_err();
+            assert!(
+                err.to_string().contains(&format!("contains '{c}'")),
+                "{err}"
+            );
+        }
+    }
+
+    #[test]
+    fn concurrent_adds_lose_no_host() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hosts.toml");
+        let barrier = std::sync::Barrier::new(2);
+        std::thread::scope(|scope| {
+            for side in ["left", "right"] {
+                let (path, barrier) = (path.clone(), &barrier);
+                scope.spawn(move || {
+                    let manager = HostManager::at(path);
+                    barrier.wait();
+                    for i in 0..10 {
+                        let alias = format!("{side}-{i}");
+                        let public_key = SecretKey::generate(&mut OsRng).public();
+                        manager.add_host(alias, public_key, None).unwrap();
+                    }
+                });
+            }
+        });
+
+        let hosts = HostManager::at(path).list_hosts(None).unwrap();
+        assert_eq!(hosts.len(), 20);
     }
 }
@@ -0,0 +1,174 @@
+use std::{any::Any, fmt, str::FromStr, sync::Arc, time::Instant};
+
+use iroh::endpoint::{Controller, ControllerFactory};
+use iroh_quinn_proto::congestion::CubicConfig;
+
+use crate::utils::format::{format_bytes, parse_bytes};
+
+/// Smallest burst a limited connection may send at once, so a low rate still
+/// moves whole packets.
+const MIN_BURST: u64 = 16 * 1024;
+
+/// A transfer rate in bytes per second, parsed from strings like `2MiB/s`,
+/// `500k` or `1.5MB/s`. `0` means unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate(pub u64);
+
+impl Rate {
+    pub fn is_unlimited(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl FromStr for Rate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let trimmed = s.trim();
+        let amount = trimmed.strip_suffix("/s").unwrap_or(trimmed);
+        parse_bytes(amount).map(Self)
+    }
+}
+
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/s", format_bytes(self.0))
+    }
+}
+
+/// Builds a rate limited congestion controller for every connection.
+#[derive(Debug)]
+pub struct RateLimit {
+    rate: Rate,
+    inner: Arc<CubicConfig>,
+}
+
+impl RateLimit {
+    pub fn new(rate: Rate) -> Self {
+        Self {
+            rate,
+            inner: Arc::new(CubicConfig::default()),
+        }
+    }
+}
+
+impl ControllerFactory for RateLimit {
+    fn build(self: Arc<Self>, now: Instant, current_mtu: u16) -> Box<dyn Controller> {
+        let rate = self.rate.0;
+        Box::new(Limited {
+            inner: self.inner.clone().build(now, current_mtu),
+            rate,
+            burst: (rate / 10).max(MIN_BURST),
+            budget: MIN_BURST as i64,
+            in_flight: 0,
+            mtu: current_mtu,
+            refilled_at: now,
+        })
+    }
+}
+
+/// Wraps the regular congestion controller in a token bucket: the window only
+/// ever grows by what the rate allows since the last refill.
+struct Limited {
+    inner: Box<dyn Controller>,
+    /// Bytes per second
+    rate: u64,
+    /// Most the budget can save up while idle
+    burst: u64,
+    /// Bytes that may still be sent, negative after overshooting by a packet
+    budget: i64,
+    in_flight: u64,
+    mtu: u16,
+    refilled_at: Instant,
+}
+
+impl Clone for Limited {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone_box(),
+            ..*self
+        }
+    }
+}
+
+impl Limited {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        let earned = (self.rate as f64 * elapsed.as_secs_f64()) as i64;
+        if earned > 0 {
+            self.budget = (self.budget + earned).min(self.burst as i64);
+            self.refilled_at = now;
+        }
+    }
+}
+
+impl Controller for Limited {
+    fn on_sent(&mut self, now: Instant, bytes: u64, last_packet_number: u64) {
+        self.refill(now);
+        self.budget -= bytes as i64;
+        self.in_flight += bytes;
+        self.inner.on_sent(now, bytes, last_packet_number);
+    }
+
+    fn on_ack(
+        &mut self,
+        now: Instant,
+        sent: Instant,
+        bytes: u64,
+        app_limited: bool,
+        rtt: &iroh_quinn_proto::RttEstimator,
+    ) {
+        self.inner.on_ack(now, sent, bytes, app_limited, rtt);
+    }
+
+    fn on_end_acks(
+        &mut self,
+        now: Instant,
+        in_flight: u64,
+        app_limited: bool,
+        largest_packet_num_acked: Option<u64>,
+    ) {
+        self.refill(now);
+        self.in_flight = in_flight;
+        self.inner
+            .on_end_acks(now, in_flight, app_limited, largest_packet_num_acked);
+    }
+
+    fn on_congestion_event(
+        &mut self,
+        now: Instant,
+        sent: Instant,
+        is_persistent_congestion: bool,
+        lost_bytes: u64,
+    ) {
+        self.inner
+            .on_congestion_event(now, sent, is_persistent_congestion, lost_bytes);
+    }
+
+    fn on_mtu_update(&mut self, new_mtu: u16) {
+        self.mtu = new_mtu;
+        self.inner.on_mtu_update(new_mtu);
+    }
+
+    fn window(&self) -> u64 {
+        let mut allowed = self.budget.max(0) as u64;
+        // With nothing in flight no ack would come to refill the budget, so
+        // always leave room for a packet then
+        if self.in_flight == 0 {
+            allowed = allowed.max(2 * self.mtu as u64);
+        }
+        self.inner.window().min(self.in_flight + allowed)
+    }
+
+    fn clone_box(&self) -> Box<dyn Controller> {
+        Box::new(self.clone())
+    }
+
+    fn initial_window(&self) -> u64 {
+        self.inner.initial_window()
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
@@ -1,108 +1,1480 @@
-use std::str::FromStr;
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::IsTerminal,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use crate::{
-    cli::Opts,
+    PoofError,
+    cli::{IpVersion, NetworkOpts, Opts, ServeOpts, StoreKind, TicketFormat},
     core::{
-        commands::{handle_host_command, handle_key_command},
-        hosts::{HostManager, KeyManager},
-        protocol::{ALPN, PoofProtocol},
+        commands::{
+            directory_size, handle_config_command, handle_doctor_command, handle_gc_command,
+            handle_history_command, handle_host_command, handle_key_command, handle_stats_command,
+        },
+        config::ConfigManager,
+        events::{ActiveTransfers, BlobEvents, Events},
+        history::{HistoryEntry, HistoryManager},
+        hosts::{Host, HostManager, KeyManager},
+        limit::{Rate, RateLimit},
+        protocol::{
+            ALPN, DEFAULT_RETRIES, DEFAULT_RETRY_DELAY, DropSource, LEGACY_ALPN, Overwrite,
+            PoofProtocol, ReceiveOptions, RetryPolicy, SendOptions, preview,
+        },
+        settings::{Settings, SettingsManager},
+        ticket::{Ticket, TicketFile},
+        tickets::TicketStore,
+        uri::PoofUri,
     },
     info, success,
-    utils::format::ReducedId,
+    utils::{
+        clipboard,
+        constants::{self, BLOBS_DIRECTORY, CONFIG_DIRECTORY},
+        format::{ReducedId, format_bytes, format_duration, format_rate, render_qr},
+        logging::{self, Level},
+    },
+    warning,
 };
-use iroh::{Endpoint, NodeId, SecretKey, protocol::Router};
-use iroh_blobs::net_protocol::Blobs;
+use clap::CommandFactory;
+use dashmap::DashMap;
+use facet::Facet;
+use futures_lite::StreamExt;
+use iroh::{
+    Endpoint, NodeAddr, NodeId, RelayMode, SecretKey, endpoint::TransportConfig, protocol::Router,
+};
+use iroh_base::ticket::NodeTicket;
+use iroh_blobs::{net_protocol::Blobs, store::GcConfig};
+use owo_colors::OwoColorize;
 use rand::rngs::OsRng;
+use tokio::sync::broadcast::error::RecvError;
 use tracing::debug;
 
 pub mod commands;
 pub mod config;
+pub mod events;
+pub mod frame;
+pub mod history;
 pub mod hosts;
+pub mod limit;
 pub mod protocol;
+pub mod settings;
+pub mod store;
+pub mod template;
 pub mod ticket;
+pub mod tickets;
+pub mod uri;
+
+/// A dropped ticket as reported under `--json` and `--print-ticket json`.
+#[derive(Debug, Facet)]
+struct DropOutput {
+    node_id: String,
+    query: String,
+    hash: String,
+    filename: Option<String>,
+}
+
+impl DropOutput {
+    fn new(node_id: NodeId, ticket: &Ticket) -> Self {
+        Self {
+            node_id: node_id.to_string(),
+            query: ticket.query.clone(),
+            hash: ticket.hash.clone(),
+            filename: ticket.filename.clone(),
+        }
+    }
+}
+
+/// A finished catch as reported under `--json`, `path` is unset for stdout.
+#[derive(Debug, Facet)]
+struct CatchOutput {
+    path: Option<String>,
+    bytes: u64,
+    duration_ms: u64,
+}
+
+/// A ticket checked by `catch --verify-only` as reported under `--json`.
+#[derive(Debug, Facet)]
+struct CheckOutput {
+    query: String,
+    filename: Option<String>,
+    size: Option<u64>,
+    hash: String,
+    note: Option<String>,
+}
+
+/// How often a drop with `--expire` checks for tickets past due.
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long `ping` waits between two pings.
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+/// How many hosts `host ping-all` pings at the same time.
+const MAX_CONCURRENT_PINGS: usize = 8;
+/// How often a shutting down drop checks whether its transfers are done.
+const TRANSFER_DRAIN_INTERVAL: Duration = Duration::from_millis(100);
+/// Drops smaller than this stay in memory with `--store auto`.
+const DEFAULT_STORE_THRESHOLD: u64 = 64 * 1024 * 1024;
 
-pub async fn run(opts: Opts) -> crate::Result<()> {
-    crate::utils::logging::init()?;
+pub async fn run(mut opts: Opts) -> crate::Result<()> {
+    if let Some(dir) = &opts.config_dir
+        && !constants::set_config_directory(std::path::absolute(dir)?)
+    {
+        return Err(crate::error!("The config directory was already in use"));
+    }
+    if opts.json {
+        logging::use_json();
+    }
+    if let crate::cli::Command::Drop { serve, .. } | crate::cli::Command::Serve { serve, .. } =
+        &opts.command
+        && serve.print_ticket.is_some()
+    {
+        logging::use_stderr();
+    }
+    if let crate::cli::Command::Catch {
+        output: Some(output),
+        ..
+    } = &opts.command
+        && output == "-"
+    {
+        logging::use_stderr();
+    }
+    logging::use_color(opts.color);
+    let color = logging::is_color_stderr();
+    miette::set_hook(Box::new(move |_| {
+        Box::new(miette::MietteHandlerOpts::new().color(color).build())
+    }))
+    .map_err(|e| crate::error!("Failed to set up error reporting: {}", e))?;
+    logging::init(opts.log_options())?;
 
     debug!("{opts:?}");
 
     let hosts = HostManager::new();
     let keys = KeyManager::new();
-
-    let sk = if let Some(key) = opts.key {
-        if let Some(hk) = keys.get_key(&key)? {
-            hk.secret_key().clone()
-        } else {
-            return Err(crate::error!("Key '{}' not found", key));
+    let history = HistoryManager::new();
+    let settings_manager = SettingsManager::new();
+    let settings = match settings_manager
+        .load()
+        .and_then(|settings| settings.validate().map(|_| settings))
+    {
+        Ok(settings) => settings,
+        // A broken config.toml must not lock out the commands that fix or report it
+        Err(_)
+            if matches!(
+                opts.command,
+                crate::cli::Command::Config(_) | crate::cli::Command::Doctor
+            ) =>
+        {
+            Settings::default()
         }
-    } else if let Some(hk) = keys.get_default_key()? {
-        hk.secret_key().clone()
-    } else {
-        // Generate a new secret key if no key is provided and no default key exists
-        let sk = SecretKey::generate(&mut OsRng);
-        keys.add_key("default".to_string(), sk.clone(), None)?;
-        info!("No key provided, generated a new default key");
-        sk
+        Err(e) => return Err(e),
     };
 
-    let endpoint = Endpoint::builder()
-        .discovery_n0()
-        .discovery_local_network()
-        .secret_key(sk)
-        .bind()
-        .await?;
-
-    let blobs = Blobs::memory().build(&endpoint);
-    let client = blobs.client();
-
-    let proto = PoofProtocol::new(client.clone(), endpoint.clone());
-
-    let router = Router::builder(endpoint.clone())
-        .accept(iroh_blobs::ALPN, blobs)
-        .accept(ALPN, proto.clone())
-        .spawn();
+    // Defaults from config.toml only fill in flags that weren't given
+    if opts.key.is_none() {
+        opts.key = settings.default_key.clone();
+    }
+    if opts.network.relay.is_none() && !opts.network.no_relay {
+        opts.network.relay = settings.relay()?;
+    }
+    if opts.network.store_threshold.is_none() {
+        opts.network.store_threshold = settings.store_threshold()?;
+    }
 
+    let serving = matches!(opts.command, crate::cli::Command::Serve { .. });
     match opts.command {
+        crate::cli::Command::Host(crate::cli::HostCommand::PingAll { timeout }) => {
+            handle_ping_all(opts.key, &keys, &opts.network, &hosts, timeout).await?
+        }
         crate::cli::Command::Host(cmd) => handle_host_command(cmd, &hosts).await?,
         crate::cli::Command::Key(cmd) => handle_key_command(cmd, &keys).await?,
-        crate::cli::Command::Drop { file } => {
+        crate::cli::Command::Config(cmd) => {
+            handle_config_command(cmd, &hosts, &keys, &settings_manager).await?
+        }
+        crate::cli::Command::History(cmd) => handle_history_command(cmd, &history).await?,
+        crate::cli::Command::Stats => handle_stats_command(opts.json, &hosts, &keys).await?,
+        crate::cli::Command::Gc {
+            older_than,
+            dry_run,
+        } => handle_gc_command(older_than, dry_run).await?,
+        crate::cli::Command::Doctor => {
+            handle_doctor_command(
+                opts.key.as_deref(),
+                &opts.network,
+                &hosts,
+                &keys,
+                &history,
+                &settings_manager,
+            )
+            .await?
+        }
+        crate::cli::Command::Completions { shell } => clap_complete::generate(
+            shell,
+            &mut Opts::command(),
+            env!("CARGO_PKG_NAME"),
+            &mut std::io::stdout(),
+        ),
+        crate::cli::Command::Drop { files, serve }
+        | crate::cli::Command::Serve { files, serve } => {
+            let ServeOpts {
+                hash,
+                resume,
+                name,
+                wrap,
+                follow_symlinks,
+                preserve_meta,
+                list,
+                query,
+                ticket_out,
+                max_retries_total,
+                idle_timeout,
+                limit,
+                expire,
+                exit_on_expire,
+                password,
+                note,
+                qr,
+                copy,
+                print_node_addr,
+                events,
+                print_ticket,
+                dry_run,
+            } = serve;
+            let expire = match expire {
+                Some(expire) => Some(expire),
+                None => settings.expire()?,
+            };
+            if exit_on_expire && expire.is_none() {
+                return Err(crate::error!(
+                    "--exit-on-expire needs --expire, or expire set in config.toml"
+                ));
+            }
+            if files.len() > 1 {
+                if !query.is_empty() {
+                    return Err(crate::error!("--query only supports a single file"));
+                }
+                if name.is_some() {
+                    return Err(crate::error!("--name only supports a single file"));
+                }
+                if ticket_out.is_some() {
+                    return Err(crate::error!("--ticket-out only supports a single file"));
+                }
+                if files.iter().filter(|file| file.as_os_str() == "-").count() > 1 {
+                    return Err(crate::error!("stdin can only be dropped once"));
+                }
+            }
+            if dry_run && files.is_empty() {
+                return Err(crate::error!("--dry-run needs files to hash"));
+            }
+            if hash.is_some() && opts.network.store == StoreKind::Memory {
+                return Err(crate::error!(
+                    "--hash serves blobs from the disk store, the memory store starts empty"
+                ));
+            }
+            if resume && opts.network.store == StoreKind::Memory {
+                return Err(crate::error!(
+                    "--resume serves tickets from the disk store, the memory store starts empty"
+                ));
+            }
+            // Dropping stdin leaves nothing to read commands from
+            let interactive = std::io::stdin().is_terminal()
+                && !opts.json
+                && !files.iter().any(|file| file.as_os_str() == "-");
+            if ticket_out.is_some() && query.len() > 1 {
+                return Err(crate::error!("--ticket-out only supports a single query"));
+            }
+            let options = || SendOptions {
+                queries: query.clone(),
+                follow_symlinks,
+                expire,
+                password: password.as_ref().map(|password| password.0.clone()),
+                name: name.clone(),
+                wrap,
+                note: note.clone(),
+                preserve_meta,
+            };
+            if dry_run {
+                let node_id = secret_key(opts.key, &keys)?.public();
+                let dropped = preview_drop(files, options).await?;
+                info!("Dry run, nothing is served");
+                report_dropped(node_id, &dropped, opts.json, print_ticket, qr)?;
+                return Ok(());
+            }
+            if opts.network.store == StoreKind::Auto {
+                let threshold = opts
+                    .network
+                    .store_threshold
+                    .unwrap_or(DEFAULT_STORE_THRESHOLD);
+                // Resuming and dropping by hash read what an earlier drop stored
+                let size = drop_size(&files).filter(|_| hash.is_none() && !resume);
+                opts.network.store = auto_store(size, threshold);
+                match (opts.network.store, size) {
+                    (StoreKind::Memory, Some(size)) => {
+                        info!(
+                            "Keeping blobs in memory, the drop is {}",
+                            format_bytes(size)
+                        )
+                    }
+                    _ => info!("Keeping blobs in the disk store"),
+                }
+            }
+            let Node {
+                endpoint,
+                proto,
+                router,
+                store_lock: _store_lock,
+            } = start_node(opts.key, &keys, &opts.network, limit, NodeRole::Serve).await?;
+            if let Some(limit) = limit.filter(|limit| !limit.is_unlimited()) {
+                info!("Uploads are limited to {} per connection", limit.bold());
+            }
+            proto.set_max_failures(max_retries_total);
+            proto.set_idle_timeout(idle_timeout);
+            if events {
+                let mut events = proto.events.subscribe();
+                tokio::spawn(async move {
+                    loop {
+                        match events.recv().await {
+                            Ok(event) => logging::emit(event.to_line(opts.json)),
+                            Err(RecvError::Lagged(missed)) => {
+                                tracing::warn!("Event feed lagged, skipped {} events", missed)
+                            }
+                            Err(RecvError::Closed) => break,
+                        }
+                    }
+                });
+            }
             info!("Node started with ID: {}", endpoint.node_id());
-            let file_path = file.canonicalize()?;
-            let file_name = file_path.file_name().unwrap_or_default().to_string_lossy();
-            let ticket = proto.send(file_path.clone()).await?;
-            success!(
-                "Dropped file '{}' with ticket {}",
-                file_name.bold(),
-                ticket.query.blue().bold()
-            );
-            tokio::signal::ctrl_c().await?;
+            if opts.network.bind.is_some() || opts.network.ip_version != IpVersion::Both {
+                let (v4, v6) = endpoint.bound_sockets();
+                let active = std::iter::once(v4)
+                    .chain(v6)
+                    .filter(|addr| !addr.ip().is_loopback())
+                    .map(|addr| addr.bold().to_string())
+                    .collect::<Vec<_>>();
+                info!("Listening on {}", active.join(" and "));
+            }
+            if print_node_addr {
+                let node_addr = endpoint.node_addr().await?;
+                info!("Node address: {}", NodeTicket::new(node_addr).bold());
+
+                let endpoint = endpoint.clone();
+                tokio::spawn(async move {
+                    let mut updates = endpoint.direct_addresses().stream_updates_only();
+                    while let Some(addrs) = updates.next().await {
+                        if addrs.is_none() {
+                            continue;
+                        }
+                        if let Ok(node_addr) = endpoint.node_addr().await {
+                            info!(
+                                "Node address changed: {}",
+                                NodeTicket::new(node_addr).bold()
+                            );
+                        }
+                    }
+                });
+            }
+            let mut dropped = Vec::new();
+            if resume {
+                if !proto.tickets.is_persistent() {
+                    return Err(crate::error!(
+                        "Can't resume while another poof process uses the blob store"
+                    ));
+                }
+                let resumed = proto.resume().await?;
+                info!("Resumed {} ticket(s) from the last session", resumed.len());
+                dropped.extend(resumed.into_iter().map(|ticket| vec![ticket]));
+            }
+            match hash {
+                Some(hash) => dropped.push(proto.send_by_hash(hash, options()).await?),
+                None if files.is_empty() => {}
+                None => dropped.extend(
+                    drop_files(files, options, async |source, options| {
+                        proto.send(source, options).await
+                    })
+                    .await?,
+                ),
+            }
+            if dropped.is_empty() && !serving {
+                return Err(crate::error!(
+                    "No tickets left to resume from the last session"
+                ));
+            }
+            if let Some(path) = ticket_out {
+                let Some(ticket) = dropped.first().and_then(|tickets| tickets.first()) else {
+                    return Err(crate::error!("--ticket-out needs something to serve"));
+                };
+                let size = proto.content_size(ticket).await?;
+                TicketFile::new(endpoint.node_id(), ticket, size).write(&path)?;
+                info!("Wrote ticket to {}", path.display());
+            }
+            if dropped.is_empty() {
+                info!("Nothing is served yet");
+            } else {
+                report_dropped(endpoint.node_id(), &dropped, opts.json, print_ticket, qr)?;
+            }
+            if copy && !dropped.is_empty() {
+                let uris = dropped
+                    .iter()
+                    .flatten()
+                    .map(|ticket| PoofUri::new(endpoint.node_id(), &ticket.query).to_string())
+                    .collect::<Vec<_>>();
+                if clipboard::copy(&uris.join("\n")) {
+                    info!("Copied to the clipboard");
+                }
+            }
+            if list {
+                list_served(&proto).await?;
+            }
+
+            let expiry = async {
+                let mut interval = tokio::time::interval(EXPIRY_CHECK_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    for ticket in proto.prune_expired() {
+                        info!("Ticket {} expired", ticket.query.blue().bold());
+                    }
+                    if exit_on_expire && proto.tickets.is_empty() {
+                        break;
+                    }
+                }
+            };
+            let session = drop_session(&proto, endpoint.node_id(), || SendOptions {
+                queries: Vec::new(),
+                name: None,
+                ..options()
+            });
+            tokio::select! {
+                res = tokio::signal::ctrl_c() => res?,
+                _ = expiry, if expire.is_some() => info!("All tickets expired, shutting down"),
+                res = session, if interactive => res?,
+            }
+            wait_for_transfers(&proto).await?;
+            router.shutdown().await?;
+        }
+        crate::cli::Command::Ping {
+            host,
+            count,
+            timeout,
+        } => {
+            let Node {
+                endpoint,
+                proto,
+                router,
+                store_lock: _store_lock,
+            } = start_node(opts.key, &keys, &opts.network, None, NodeRole::Ping).await?;
+            let (node_id, _) = resolve_host(&host, &hosts, &endpoint, &opts.network)?;
+            let retry = RetryPolicy {
+                retries: settings.retries.unwrap_or(DEFAULT_RETRIES),
+                base_delay: settings.retry_delay()?.unwrap_or(DEFAULT_RETRY_DELAY),
+                ..Default::default()
+            };
+            let mut rtts = Vec::with_capacity(count as usize);
+            for sent in 0..count {
+                if sent > 0 {
+                    tokio::time::sleep(PING_INTERVAL).await;
+                }
+                match proto.ping(node_id, retry, timeout).await {
+                    Ok(rtt) => {
+                        success!(
+                            "Reply from {} in {}",
+                            node_id.reduced(),
+                            format_duration(rtt).bold()
+                        );
+                        rtts.push(rtt);
+                    }
+                    Err(e) if count == 1 => {
+                        router.shutdown().await?;
+                        return Err(e.into());
+                    }
+                    Err(e) => warning!("{}", e),
+                }
+            }
+            if count > 1 {
+                let (Some(min), Some(max)) = (rtts.iter().min(), rtts.iter().max()) else {
+                    router.shutdown().await?;
+                    return Err(crate::error!("Node {} answered none of the pings", node_id));
+                };
+                info!(
+                    "{} of {} answered, min {}, avg {}, max {}",
+                    rtts.len(),
+                    count,
+                    format_duration(*min),
+                    format_duration(rtts.iter().sum::<Duration>() / rtts.len() as u32).bold(),
+                    format_duration(*max)
+                );
+            }
+            router.shutdown().await?;
         }
         crate::cli::Command::Catch {
             host,
             output,
-            query,
+            queries,
+            ticket_in,
+            list,
+            verify_only,
+            expect_name,
+            range,
+            password,
+            no_history,
+            no_resume,
+            timeout,
+            retries,
+            retry_delay,
+            force,
+            no_clobber,
+            output_template,
+            confirm_above,
+            yes,
+            output_dir,
+            save_host,
         } => {
-            let node_id = if let Some(host) = hosts.get_host(&host)? {
+            let Node {
+                endpoint,
+                proto,
+                router,
+                store_lock: _store_lock,
+            } = start_node(opts.key, &keys, &opts.network, None, NodeRole::Catch).await?;
+            let ticket_file = ticket_in.map(|path| TicketFile::read(&path)).transpose()?;
+            let (host, queries) = match &ticket_file {
+                Some(file) => (file.node_id.clone(), vec![file.query.clone()]),
+                // clap guarantees the host is present without `--ticket-in`
+                None => match (host.unwrap_or_default(), queries) {
+                    (host, queries) if PoofUri::is_uri(&host) => {
+                        if !queries.is_empty() {
+                            return Err(crate::error!(
+                                "The query is already part of the URI {}",
+                                host
+                            ));
+                        }
+                        let uri = PoofUri::from_str(&host)?;
+                        (uri.node_id.to_string(), vec![uri.query])
+                    }
+                    (host, queries) => (host, queries),
+                },
+            };
+
+            let (node_id, saved) = resolve_host(&host, &hosts, &endpoint, &opts.network)?;
+
+            let retry = RetryPolicy {
+                retries: retries.or(settings.retries).unwrap_or(DEFAULT_RETRIES),
+                base_delay: match retry_delay {
+                    Some(delay) => delay,
+                    None => settings.retry_delay()?.unwrap_or(DEFAULT_RETRY_DELAY),
+                },
+                ..Default::default()
+            };
+            let timeout = (!timeout.is_zero()).then_some(timeout);
+            if list {
+                let listings = proto.list(node_id, retry, timeout).await?;
+                if listings.is_empty() {
+                    info!("Node {} serves no drops", node_id.reduced());
+                } else {
+                    logging::emit(format!("\n{}", "Available Drops:".bold().underline()));
+                    for listing in listings {
+                        logging::emit(format!(
+                            "  {} {} {} ({}){}",
+                            "•".blue(),
+                            listing.query.bold(),
+                            listing.filename.as_deref().unwrap_or("<unnamed>"),
+                            format_bytes(listing.size),
+                            if listing.protected {
+                                " protected".dimmed().to_string()
+                            } else {
+                                String::new()
+                            }
+                        ));
+                    }
+                    logging::emit(String::new());
+                }
+                router.shutdown().await?;
+                return Ok(());
+            }
+            if queries.is_empty() {
+                return Err(crate::error!("Missing query for host {}", host));
+            }
+            if queries.len() > 1 && (output.is_some() || expect_name.is_some() || range.is_some()) {
+                return Err(crate::error!(
+                    "--output, --expect-name and --range apply to a single query, use --output-dir to catch several"
+                ));
+            }
+
+            let options = ReceiveOptions {
+                output,
+                output_template,
+                output_dir: output_dir.or_else(|| settings.output_dir()),
+                expected: ticket_file,
+                expect_name,
+                range,
+                password: password.map(|password| password.0),
+                retry,
+                overwrite: if force {
+                    Overwrite::Force
+                } else if no_clobber {
+                    Overwrite::NoClobber
+                } else {
+                    Overwrite::Ask
+                },
+                resume: !no_resume,
+                timeout,
+                confirm_above: (!yes).then_some(confirm_above),
+            };
+            if verify_only {
+                let mut missing = 0;
+                for query in &queries {
+                    let (ticket, size) = match proto.check(node_id, query, &options).await {
+                        Ok(served) => served,
+                        Err(e) if queries.len() == 1 => return Err(e.into()),
+                        Err(e) => {
+                            logging::message(Level::Error, format!("{}: {}", query.bold(), e));
+                            missing += 1;
+                            continue;
+                        }
+                    };
+                    logging::message_with(
+                        Level::Success,
+                        format!(
+                            "Node {} serves {} {} ({})",
+                            node_id.reduced(),
+                            ticket.query.bold(),
+                            ticket.filename.as_deref().unwrap_or("<unnamed>"),
+                            size.map_or_else(|| "unknown size".to_string(), format_bytes)
+                        ),
+                        &CheckOutput {
+                            query: ticket.query.clone(),
+                            filename: ticket.filename.clone(),
+                            size,
+                            hash: ticket.hash.clone(),
+                            note: ticket.note.clone(),
+                        },
+                    );
+                    if !logging::is_json() {
+                        logging::emit(format!("    {}: {}", "Hash".dimmed(), ticket.hash));
+                        if let Some(note) = &ticket.note {
+                            logging::emit(format!("    {}: {}", "Note".dimmed(), note));
+                        }
+                    }
+                }
+                router.shutdown().await?;
+                if missing > 0 {
+                    return Err(crate::error!(
+                        "{} of {} queries are not served",
+                        missing,
+                        queries.len()
+                    ));
+                }
+                return Ok(());
+            }
+
+            let save_host = match (save_host, &saved) {
+                (Some(_), Some(saved)) => {
+                    warning!("Host is already saved as '{}'", saved.alias.bold());
+                    None
+                }
+                (save_host, _) => save_host,
+            };
+            let host = saved.map_or_else(|| node_id.to_string(), |host| host.alias);
+            let mut caught = Vec::with_capacity(queries.len());
+            for query in &queries {
+                info!(
+                    "Catching file with query '{}' from node {}",
+                    query.bold(),
+                    node_id.reduced()
+                );
+                let started = Instant::now();
+                let received = proto
+                    .receive(node_id, query.clone(), options.clone())
+                    .await
+                    .map_err(|e| match PoofError::from(e) {
+                        PoofError::Connection { node_id, source } if opts.network.no_relay => {
+                            PoofError::Error {
+                                message: format!(
+                                    "Could not reach node {} without a relay, pass its address with --direct-addr",
+                                    node_id
+                                ),
+                                source: Some(source),
+                            }
+                        }
+                        e => e,
+                    });
+                let received = match received {
+                    Ok(received) => received,
+                    Err(e) if queries.len() == 1 => return Err(e),
+                    Err(e) => {
+                        logging::message(
+                            Level::Error,
+                            format!("Failed to catch '{}': {}", query, e),
+                        );
+                        caught.push((query, Err(e)));
+                        continue;
+                    }
+                };
+                let elapsed = started.elapsed();
+                logging::message_with(
+                    Level::Success,
+                    format!(
+                        "File received successfully, {} in {} ({})",
+                        format_bytes(received.bytes),
+                        format_duration(elapsed),
+                        format_rate(received.bytes, elapsed)
+                    ),
+                    &CatchOutput {
+                        path: received
+                            .path
+                            .as_ref()
+                            .map(|path| path.display().to_string()),
+                        bytes: received.bytes,
+                        duration_ms: elapsed.as_millis() as u64,
+                    },
+                );
+
+                if !no_history {
+                    let filename = received
+                        .path
+                        .as_ref()
+                        .and_then(|path| path.file_name())
+                        .map(|name| name.to_string_lossy().into_owned());
+                    history.record(HistoryEntry::new(
+                        host.clone(),
+                        node_id,
+                        query.clone(),
+                        filename,
+                        received.bytes,
+                        elapsed,
+                    ))?;
+                }
+                caught.push((query, Ok(received)));
+            }
+
+            if let Some(alias) = save_host
+                && caught.iter().any(|(_, res)| res.is_ok())
+            {
+                match hosts.add_host(alias.clone(), node_id, None) {
+                    Ok(()) => success!(
+                        "Saved node {} as host '{}'",
+                        node_id.reduced(),
+                        alias.bold()
+                    ),
+                    Err(e) => warning!("Not saving host '{}': {}", alias, e),
+                }
+            }
+
+            if caught.len() > 1 {
+                let failed = caught.iter().filter(|(_, res)| res.is_err()).count();
+                info!(
+                    "Caught {} of {} queries from node {}",
+                    caught.len() - failed,
+                    caught.len(),
+                    node_id.reduced()
+                );
+                for (query, res) in &caught {
+                    match res {
+                        Ok(received) => logging::message(
+                            Level::Success,
+                            format!(
+                                "{} {} ({})",
+                                query.bold(),
+                                received.path.as_ref().map_or_else(
+                                    || "stdout".to_string(),
+                                    |path| path.display().to_string()
+                                ),
+                                format_bytes(received.bytes)
+                            ),
+                        ),
+                        Err(e) => logging::message(Level::Error, format!("{} {}", query.bold(), e)),
+                    }
+                }
+                if failed > 0 {
+                    router.shutdown().await?;
+                    return Err(crate::error!(
+                        "{} of {} queries failed",
+                        failed,
+                        caught.len()
+                    ));
+                }
+            }
+            router.shutdown().await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs `host ping-all` on a node of its own, started only when there are
+/// hosts to ping.
+async fn handle_ping_all(
+    key: Option<String>,
+    keys: &KeyManager,
+    network: &NetworkOpts,
+    hosts: &HostManager,
+    timeout: Duration,
+) -> crate::Result<()> {
+    let saved = hosts.list_hosts(None)?;
+    if saved.is_empty() {
+        info!("No hosts configured");
+        return Ok(());
+    }
+    let Node { proto, router, .. } = start_node(key, keys, network, None, NodeRole::Ping).await?;
+    let res = ping_all(&proto, hosts, saved, timeout).await;
+    router.shutdown().await?;
+    res
+}
+
+/// Pings every host in `saved` with at most [`MAX_CONCURRENT_PINGS`] in
+/// flight, then prints them in alias order. Errors when any was unreachable.
+async fn ping_all(
+    proto: &Arc<PoofProtocol>,
+    hosts: &HostManager,
+    mut saved: Vec<Host>,
+    timeout: Duration,
+) -> crate::Result<()> {
+    saved.sort_by(|a, b| a.alias.cmp(&b.alias));
+    // A single attempt, the timeout already bounds how long a host may take
+    let retry = RetryPolicy {
+        retries: 0,
+        ..Default::default()
+    };
+
+    let mut results = Vec::with_capacity(saved.len());
+    let mut pings = tokio::task::JoinSet::new();
+    for (index, host) in saved.iter().enumerate() {
+        let node_id = match host.public_key() {
+            Ok(node_id) => node_id,
+            Err(e) => {
+                results.push((index, Err(e.to_string())));
+                continue;
+            }
+        };
+        if pings.len() >= MAX_CONCURRENT_PINGS
+            && let Some(done) = pings.join_next().await
+        {
+            results.push(done.map_err(anyhow::Error::from)?);
+        }
+        let proto = proto.clone();
+        pings.spawn(async move {
+            let rtt = proto.ping(node_id, retry, timeout).await;
+            (index, rtt.map_err(|e| e.to_string()))
+        });
+    }
+    while let Some(done) = pings.join_next().await {
+        results.push(done.map_err(anyhow::Error::from)?);
+    }
+    results.sort_by_key(|(index, _)| *index);
+
+    let width = saved.iter().map(|host| host.alias.len()).max().unwrap_or(0);
+    let mut reachable = 0;
+    logging::emit(String::new());
+    for (index, rtt) in results {
+        let host = &saved[index];
+        let alias = format!("{:width$}", host.alias);
+        match rtt {
+            Ok(rtt) => {
+                reachable += 1;
                 hosts.update_last_seen(&host.alias)?;
-                host.public_key().into()
+                logging::emit(format!(
+                    "  {} {}  {}",
+                    "✓".green(),
+                    alias.bold(),
+                    format_duration(rtt)
+                ));
+            }
+            Err(e) => logging::emit(format!("  {} {}  {}", "✗".red(), alias.bold(), e.dimmed())),
+        }
+    }
+    logging::emit(String::new());
+
+    let unreachable = saved.len() - reachable;
+    if unreachable > 0 {
+        return Err(crate::error!(
+            "{} of {} hosts unreachable",
+            unreachable,
+            saved.len()
+        ));
+    }
+    success!("All {} hosts reachable", saved.len());
+    Ok(())
+}
+
+/// Finds the node behind a host alias, node id or node address, along with
+/// the saved host the alias names. Known addresses are handed to `endpoint`
+/// so no discovery is needed to reach them.
+fn resolve_host(
+    host: &str,
+    hosts: &HostManager,
+    endpoint: &Endpoint,
+    opts: &NetworkOpts,
+) -> crate::Result<(NodeId, Option<Host>)> {
+    let saved = hosts.get_host(host)?;
+    let node_id = if let Some(host) = &saved {
+        hosts.update_last_seen(&host.alias)?;
+        host.public_key()?
+    } else if let Ok(node_id) = NodeId::from_str(host) {
+        node_id
+    } else if let Ok(ticket) = NodeTicket::from_str(host) {
+        // Direct addresses let us connect without any discovery service
+        endpoint.add_node_addr(ticket.node_addr().clone())?;
+        ticket.node_addr().node_id
+    } else {
+        return Err(crate::error!("Invalid host: {}", host));
+    };
+
+    if !opts.direct_addr.is_empty() {
+        endpoint.add_node_addr(
+            NodeAddr::new(node_id).with_direct_addresses(opts.direct_addr.clone()),
+        )?;
+    }
+    Ok((node_id, saved))
+}
+
+/// The tickets dropping `files` would get, computed from their content
+/// alone, without an endpoint or a blob store.
+async fn preview_drop(
+    files: Vec<PathBuf>,
+    options: impl Fn() -> SendOptions,
+) -> crate::Result<Vec<Vec<Ticket>>> {
+    let tickets = DashMap::new();
+    drop_files(files, options, async |source, options| {
+        preview(&tickets, source, options).await
+    })
+    .await
+}
+
+/// Drops each of `files` with `send`, only failing outright when a single
+/// file was given or none of them could be dropped.
+async fn drop_files(
+    files: Vec<PathBuf>,
+    options: impl Fn() -> SendOptions,
+    send: impl AsyncFn(DropSource, SendOptions) -> anyhow::Result<Vec<Ticket>>,
+) -> crate::Result<Vec<Vec<Ticket>>> {
+    let single = files.len() == 1;
+    let mut dropped = Vec::with_capacity(files.len());
+    for file in files {
+        let result: crate::Result<_> = async {
+            let source = if file.as_os_str() == "-" {
+                DropSource::Stdin
             } else {
-                if let Ok(node_id) = NodeId::from_str(&host) {
-                    node_id
+                DropSource::Path(file.canonicalize()?)
+            };
+            Ok(send(source, options()).await?)
+        }
+        .await;
+        match result {
+            // The same file given twice is shared once
+            Ok(tickets)
+                if dropped
+                    .iter()
+                    .any(|other: &Vec<Ticket>| other[0].query == tickets[0].query) => {}
+            Ok(tickets) => dropped.push(tickets),
+            Err(e) if single => return Err(e),
+            Err(e) => warning!("Failed to drop '{}': {}", file.display().bold(), e),
+        }
+    }
+    if dropped.is_empty() {
+        return Err(crate::error!("None of the files could be dropped"));
+    }
+    Ok(dropped)
+}
+
+/// Prints every ticket `proto` serves, as `drop --list` does.
+async fn list_served(proto: &PoofProtocol) -> crate::Result<()> {
+    logging::emit(format!("\n{}", "Active Tickets:".bold().underline()));
+    for ticket in proto.list_tickets() {
+        let size = proto.content_size(&ticket).await?;
+        logging::emit(format!(
+            "  {} {} {} ({})",
+            "•".blue(),
+            ticket.query.bold(),
+            ticket.filename.as_deref().unwrap_or("<unnamed>"),
+            format_bytes(size)
+        ));
+        logging::emit(format!("    {}: {}", "Hash".dimmed(), ticket.hash));
+    }
+    logging::emit(String::new());
+    Ok(())
+}
+
+/// Reads commands from the terminal while a drop is served, returning once
+/// asked to quit or stdin closes.
+async fn drop_session(
+    proto: &PoofProtocol,
+    node_id: NodeId,
+    options: impl Fn() -> SendOptions,
+) -> crate::Result<()> {
+    info!("Type {} to list the commands", "help".bold());
+    let mut lines = stdin_lines();
+    loop {
+        eprint!("> ");
+        let Some(line) = lines.recv().await else {
+            return Ok(());
+        };
+        let (command, argument) = match line.trim().split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line.trim(), ""),
+        };
+        match (command, argument) {
+            ("", _) => {}
+            ("help", _) => {
+                for (usage, description) in SESSION_COMMANDS {
+                    logging::emit(format!("  {:<12} {}", usage.bold(), description));
+                }
+            }
+            ("add", "") => warning!("Usage: add <file>"),
+            ("add", file) => {
+                match drop_files(
+                    vec![PathBuf::from(file)],
+                    &options,
+                    async |source, options| proto.send(source, options).await,
+                )
+                .await
+                {
+                    Ok(dropped) => report_dropped(node_id, &dropped, false, None, false)?,
+                    Err(e) => warning!("Failed to drop '{}': {}", file.bold(), e),
+                }
+            }
+            ("list", _) => list_served(proto).await?,
+            ("rm", "") => warning!("Usage: rm <query>"),
+            ("rm", query) => {
+                if proto.remove_ticket(query) {
+                    success!("No longer serving {}", query.blue().bold());
                 } else {
-                    return Err(crate::error!("Invalid host: {}", host));
+                    warning!("No ticket with query {}", query.bold());
                 }
-            };
+            }
+            ("quit" | "exit", _) => {
+                info!("Shutting down");
+                return Ok(());
+            }
+            (other, _) => warning!(
+                "Unknown command '{}', type {} to list the commands",
+                other,
+                "help".bold()
+            ),
+        }
+    }
+}
+
+/// Commands understood by [`drop_session`] with their description.
+const SESSION_COMMANDS: [(&str, &str); 5] = [
+    ("add <file>", "Drop another file or directory"),
+    ("list", "List the tickets being served"),
+    ("rm <query>", "Stop serving a ticket"),
+    ("help", "Print this list"),
+    ("quit", "Stop serving and exit"),
+];
+
+/// Lines typed on stdin, read on a plain thread which, unlike tokio's blocking
+/// stdin reads, doesn't hold up the shutdown of the runtime.
+fn stdin_lines() -> tokio::sync::mpsc::UnboundedReceiver<String> {
+    let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else { break };
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
 
-            info!(
-                "Catching file with query '{}' from node {}",
-                query.bold(),
-                node_id.reduced()
+/// Prints what was dropped and how to catch it, with the tickets alone on
+/// stdout under `--print-ticket`.
+fn report_dropped(
+    node_id: NodeId,
+    dropped: &[Vec<Ticket>],
+    json: bool,
+    print_ticket: Option<TicketFormat>,
+    qr: bool,
+) -> crate::Result<()> {
+    let describe = |ticket: &Ticket| match ticket.entries.as_ref().map(Vec::len) {
+        None => "file".to_string(),
+        Some(1) => "directory (1 file)".to_string(),
+        Some(n) => format!("directory ({} files)", n),
+    };
+    let file_name = |ticket: &Ticket| ticket.filename.clone().unwrap_or("<unnamed>".into());
+    if json {
+        for ticket in dropped.iter().flatten() {
+            logging::message_with(
+                Level::Success,
+                format!(
+                    "Dropped '{}' with ticket {}",
+                    file_name(ticket),
+                    ticket.query
+                ),
+                &DropOutput::new(node_id, ticket),
             );
-            proto.receive(node_id, query, output).await?;
-            success!("File received successfully");
+        }
+    } else if let [tickets] = dropped {
+        let queries = tickets
+            .iter()
+            .map(|ticket| ticket.query.blue().bold().to_string())
+            .collect::<Vec<_>>();
+        success!(
+            "Dropped {} '{}' with {} {}",
+            describe(&tickets[0]),
+            file_name(&tickets[0]).bold(),
+            if queries.len() == 1 {
+                "ticket"
+            } else {
+                "tickets"
+            },
+            queries.join(", ")
+        );
+    } else {
+        success!("Dropped {} files", dropped.len());
+        let width = dropped
+            .iter()
+            .map(|tickets| file_name(&tickets[0]).chars().count())
+            .max()
+            .unwrap_or_default();
+        for tickets in dropped {
+            let ticket = &tickets[0];
+            logging::emit(format!(
+                "  {} {}  {}",
+                format!("{:<width$}", file_name(ticket)).bold(),
+                ticket.query.blue().bold(),
+                describe(ticket).dimmed()
+            ));
         }
     }
+    if let Some(format) = print_ticket {
+        for ticket in dropped.iter().flatten() {
+            match format {
+                TicketFormat::Json => println!(
+                    "{}",
+                    facet_json::to_string(&DropOutput::new(node_id, ticket))
+                ),
+                TicketFormat::Uri => {
+                    println!("{}", PoofUri::new(node_id, &ticket.query))
+                }
+            }
+        }
+    }
+    if !json {
+        for ticket in dropped.iter().flatten() {
+            let uri = PoofUri::new(node_id, &ticket.query);
+            info!("Catch it with: {}", format!("poof catch {}", uri).bold());
+        }
+    }
+    if qr {
+        for ticket in dropped.iter().flatten() {
+            let uri = PoofUri::new(node_id, &ticket.query).to_string();
+            logging::emit(format!("\n{}\n{}", render_qr(&uri)?, uri.bold()));
+        }
+    }
+    Ok(())
+}
 
-    router.shutdown().await?;
+/// Stops handing out tickets and waits for the transfers still running, unless
+/// Ctrl-C comes again.
+async fn wait_for_transfers(proto: &PoofProtocol) -> crate::Result<()> {
+    // Saved before clearing so `drop --resume` serves them again
+    proto.tickets.save();
+    proto.tickets.clear();
+    let active = proto.active_transfers.count();
+    if active == 0 {
+        return Ok(());
+    }
+
+    warning!(
+        "Waiting for {} transfer(s) to finish (Ctrl-C again to force)",
+        active
+    );
+    let drained = async {
+        while proto.active_transfers.count() > 0 {
+            tokio::time::sleep(TRANSFER_DRAIN_INTERVAL).await;
+        }
+    };
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => {
+            res?;
+            warning!("Forcing shutdown, cutting {} transfer(s)", proto.active_transfers.count());
+        }
+        _ = drained => info!("All transfers finished, shutting down"),
+    }
     Ok(())
 }
+
+/// The networking side of poof, only started by commands that need it.
+struct Node {
+    endpoint: Endpoint,
+    proto: Arc<PoofProtocol>,
+    router: Router,
+    /// Held until the process exits so concurrent runs never share a store
+    store_lock: Option<File>,
+}
+
+/// What a node is started for, deciding what it does with the blob store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NodeRole {
+    /// Drops, pruning the disk store and collecting its garbage
+    Serve,
+    /// Catches, leaving the disk store as it found it
+    Catch,
+    /// Transfers no blobs, so never takes the disk store from a running drop
+    Ping,
+}
+
+/// Binds the endpoint with the secret key named `key`, or the default one,
+/// and starts serving blobs and tickets from the store `role` calls for.
+async fn start_node(
+    key: Option<String>,
+    keys: &KeyManager,
+    opts: &NetworkOpts,
+    limit: Option<Rate>,
+    role: NodeRole,
+) -> crate::Result<Node> {
+    let endpoint = bind_endpoint(secret_key(key, keys)?, opts, limit).await?;
+
+    let events = Events::default();
+    let blob_events = BlobEvents::new(events.clone());
+    let active_transfers = ActiveTransfers::default();
+    let store_dir = CONFIG_DIRECTORY.join(BLOBS_DIRECTORY);
+    let store = match role {
+        NodeRole::Ping => StoreKind::Memory,
+        NodeRole::Serve | NodeRole::Catch => opts.store,
+    };
+    let store_lock = match store {
+        StoreKind::Disk | StoreKind::Auto => {
+            let lock = store::lock(&store_dir)?;
+            if lock.is_none() {
+                warning!("Blob store is in use by another poof process, keeping blobs in memory");
+            }
+            lock
+        }
+        StoreKind::Memory => None,
+    };
+
+    // Saved tickets point into the blob store, so only its owner keeps them
+    let tickets = if store_lock.is_some() {
+        TicketStore::persistent()
+    } else {
+        TicketStore::default()
+    };
+
+    let router = Router::builder(endpoint.clone());
+    let (client, router) = if store_lock.is_some() {
+        let blobs = Blobs::persistent(&store_dir)
+            .await?
+            .events(blob_events.clone().into())
+            .build(&endpoint);
+        let client = blobs.client().clone();
+        if role == NodeRole::Serve {
+            // The content of the last drop stays for `drop --resume` and `--hash`
+            let saved = tickets
+                .saved()?
+                .iter()
+                .map(Ticket::hash)
+                .collect::<crate::Result<HashSet<_>>>()?;
+            store::prune(&client, &saved).await?;
+            blobs.start_gc(GcConfig {
+                period: store::GC_PERIOD,
+                done_callback: None,
+            })?;
+        }
+        (
+            client,
+            router.accept(
+                iroh_blobs::ALPN,
+                blob_events.handler(blobs, active_transfers.clone()),
+            ),
+        )
+    } else {
+        let blobs = Blobs::memory()
+            .events(blob_events.clone().into())
+            .build(&endpoint);
+        (
+            blobs.client().clone(),
+            router.accept(
+                iroh_blobs::ALPN,
+                blob_events.handler(blobs, active_transfers.clone()),
+            ),
+        )
+    };
+
+    let proto = PoofProtocol::new(client, endpoint.clone(), events, active_transfers, tickets);
+    let router = router
+        .accept(ALPN, proto.clone())
+        .accept(LEGACY_ALPN, proto.clone())
+        .spawn();
+
+    Ok(Node {
+        endpoint,
+        proto,
+        router,
+        store_lock,
+    })
+}
+
+/// The store `--store auto` keeps a drop of `size` in, the disk one when the
+/// size isn't known up front.
+fn auto_store(size: Option<u64>, threshold: u64) -> StoreKind {
+    match size {
+        Some(size) if size < threshold => StoreKind::Memory,
+        _ => StoreKind::Disk,
+    }
+}
+
+/// How much dropping `files` adds to the store, unknown for stdin, for a
+/// session starting empty and when a file can't be read.
+fn drop_size(files: &[PathBuf]) -> Option<u64> {
+    if files.is_empty() || files.iter().any(|file| file.as_os_str() == "-") {
+        return None;
+    }
+    files.iter().try_fold(0, |total, file| {
+        let metadata = std::fs::metadata(file).ok()?;
+        let size = if metadata.is_dir() {
+            directory_size(file).ok()?
+        } else {
+            metadata.len()
+        };
+        Some(total + size)
+    })
+}
+
+/// Loads the secret key named `key`, or the default one, generating and
+/// saving a default key when there is none yet.
+fn secret_key(key: Option<String>, keys: &KeyManager) -> crate::Result<SecretKey> {
+    let (name, sk) = if let Some(key) = key {
+        if let Some(hk) = keys.get_key(&key)? {
+            (hk.name.clone(), hk.secret_key()?)
+        } else {
+            return Err(crate::error!("Key '{}' not found", key));
+        }
+    } else if let Some(hk) = keys.get_default_key()? {
+        (hk.name.clone(), hk.secret_key()?)
+    } else {
+        // Generate a new secret key if no key is provided and no default key exists
+        let sk = SecretKey::generate(&mut OsRng);
+        keys.add_key("default".to_string(), sk.clone(), None)?;
+        info!("No key provided, generated a new default key");
+        ("default".to_string(), sk)
+    };
+    keys.touch_key(&name)?;
+    Ok(sk)
+}
+
+/// Builds and binds the endpoint according to the network options, capping
+/// every connection's send rate at `limit`.
+async fn bind_endpoint(
+    sk: SecretKey,
+    opts: &NetworkOpts,
+    limit: Option<Rate>,
+) -> crate::Result<Endpoint> {
+    let mut builder = Endpoint::builder().discovery_local_network().secret_key(sk);
+    if let Some(limit) = limit.filter(|limit| !limit.is_unlimited()) {
+        let mut transport = TransportConfig::default();
+        // Same keep-alive iroh's default transport config uses
+        transport.keep_alive_interval(Some(Duration::from_secs(1)));
+        transport.congestion_controller_factory(Arc::new(RateLimit::new(limit)));
+        builder = builder.transport_config(transport);
+    }
+    builder = match (opts.no_relay, opts.relay.clone()) {
+        // n0 discovery only publishes the relay a node is reachable through
+        (true, _) => builder.relay_mode(RelayMode::Disabled),
+        (false, Some(relay)) => builder
+            .discovery_n0()
+            .relay_mode(RelayMode::Custom(relay.into())),
+        (false, None) => builder.discovery_n0(),
+    };
+    match (opts.bind, opts.ip_version) {
+        (Some(addr @ SocketAddr::V4(_)), IpVersion::V6) => {
+            return Err(crate::error!("--bind {} is not an IPv6 address", addr));
+        }
+        (Some(addr @ SocketAddr::V6(_)), IpVersion::V4) => {
+            return Err(crate::error!("--bind {} is not an IPv4 address", addr));
+        }
+        _ => {}
+    }
+    // iroh always opens both sockets, so the unwanted stack is confined to loopback
+    builder = match opts.ip_version {
+        IpVersion::V4 => builder.bind_addr_v6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 0, 0, 0)),
+        IpVersion::V6 => builder.bind_addr_v4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)),
+        IpVersion::Both => builder,
+    };
+    builder = match opts.bind {
+        Some(SocketAddr::V4(addr)) => builder.bind_addr_v4(addr),
+        Some(SocketAddr::V6(addr)) => builder.bind_addr_v6(addr),
+        None => builder,
+    };
+    let endpoint = builder.bind().await.map_err(|e| match opts.bind {
+        Some(addr) => crate::error!("Failed to bind to {}: {:#}", addr, e),
+        None => e.into(),
+    })?;
+
+    if opts.ip_version == IpVersion::V6 && endpoint.bound_sockets().1.is_none() {
+        return Err(crate::error!("IPv6 is not available on this host"));
+    }
+
+    if let Some(addr) = opts.bind {
+        // iroh silently falls back to a random port when the requested one is taken
+        let bound = match addr {
+            SocketAddr::V4(_) => Some(endpoint.bound_sockets().0),
+            SocketAddr::V6(_) => endpoint.bound_sockets().1,
+        };
+        if addr.port() != 0 && bound.is_none_or(|bound| bound.port() != addr.port()) {
+            return Err(crate::error!(
+                "Could not bind to {}, is the port already in use?",
+                addr
+            ));
+        }
+    }
+
+    Ok(endpoint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[tokio::test]
+    async fn dry_runs_only_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let notes = dir.path().join("notes.txt");
+        std::fs::write(&notes, "notes").unwrap();
+        let todo = dir.path().join("todo.txt");
+        std::fs::write(&todo, "todo").unwrap();
+
+        // The same file twice is dropped once, like for a real drop
+        let files = vec![notes.clone(), todo, notes];
+        let dropped = preview_drop(files, SendOptions::default).await.unwrap();
+        assert_eq!(dropped.len(), 2);
+        for (tickets, content) in dropped.iter().zip(["notes", "todo"]) {
+            assert_eq!(tickets.len(), 1);
+            let ticket = &tickets[0];
+            assert_eq!(ticket.hash().unwrap(), iroh_blobs::Hash::new(content));
+            assert_eq!(ticket.filename.as_deref(), Some(&*format!("{content}.txt")));
+        }
+    }
+
+    #[test]
+    fn sizes_drops_up_front() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("notes.txt");
+        std::fs::write(&file, "notes").unwrap();
+        let nested = dir.path().join("album").join("side-a");
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::write(nested.join("track.ogg"), "track").unwrap();
+
+        assert_eq!(drop_size(std::slice::from_ref(&file)), Some(5));
+        assert_eq!(
+            drop_size(&[file.clone(), dir.path().join("album")]),
+            Some(10)
+        );
+        assert_eq!(drop_size(&[]), None);
+        assert_eq!(drop_size(&[file.clone(), PathBuf::from("-")]), None);
+        assert_eq!(drop_size(&[file, dir.path().join("missing")]), None);
+    }
+
+    #[test]
+    fn small_drops_stay_on_disk_unless_asked() {
+        let opts = Opts::parse_from(["poof", "drop", "notes.txt"]);
+        assert_eq!(opts.network.store, StoreKind::Disk);
+
+        assert_eq!(auto_store(Some(5), 10), StoreKind::Memory);
+        assert_eq!(auto_store(Some(10), 10), StoreKind::Disk);
+        assert_eq!(auto_store(None, 10), StoreKind::Disk);
+    }
+}
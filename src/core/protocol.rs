@@ -1,215 +1,2715 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    collections::HashSet,
+    fmt,
+    io::IsTerminal,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
+};
 
 use anyhow::bail;
-use dashmap::DashMap;
+use bao_tree::{ChunkNum, ChunkRanges, io::BaoContentItem};
+use dashmap::{DashMap, mapref::entry::Entry};
 use facet_pretty::FacetPretty;
-use futures_lite::future::Boxed as BoxedFuture;
-use iroh::{NodeId, protocol::ProtocolHandler};
-use iroh_blobs::rpc::client::blobs::MemClient;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use futures_lite::{StreamExt, future::Boxed as BoxedFuture};
+use iroh::{
+    NodeId,
+    endpoint::{Connection, ConnectionError, RecvStream, SendStream, TransportErrorCode},
+    protocol::ProtocolHandler,
+};
+use iroh_blobs::{
+    Hash, HashAndFormat,
+    format::collection::Collection,
+    get::{
+        Stats,
+        db::DownloadProgress as BytesDownloadProgress,
+        fsm::{self, BlobContentNext},
+    },
+    protocol::{GetRequest, RangeSpecSeq},
+    rpc::client::blobs::{BlobStatus, DownloadProgress, MemClient, WrapOption},
+    store::ExportFormat,
+    util::{SetTagOption, fs::relative_canonicalized_path_to_string},
+};
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use crate::{
-    core::ticket::{ResponseCode, Ticket},
-    info,
-    utils::format::ReducedId,
+    PoofError,
+    core::{
+        events::{ActiveTransfers, EventKind, Events},
+        frame::{FrameError, MAX_LISTING_SIZE, MAX_TICKET_SIZE, Request, Response},
+        store,
+        template::OutputTemplate,
+        ticket::{Listing, Listings, ResponseCode, Ticket, TicketFile},
+        tickets::TicketStore,
+    },
+    info, success,
+    utils::{
+        format::{ReducedId, format_bytes, format_duration},
+        progress::TransferBar,
+    },
+    warning,
 };
 
-pub const ALPN: &[u8] = b"poof/0";
+pub const ALPN: &[u8] = b"poof/1";
+/// Protocol spoken before requests started with an [`Opcode`], still served for older peers.
+pub const LEGACY_ALPN: &[u8] = b"poof/0";
+pub const DEFAULT_MAX_FAILURES: usize = 10;
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_RETRIES: usize = 3;
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Chunk size used when hashing a blob outside of the store.
+const VERIFY_BUFFER_SIZE: usize = 64 * 1024;
+/// Consecutive failures only count towards tripping the breaker within this window.
+const FAILURE_WINDOW: Duration = Duration::from_secs(60);
+/// How long incoming connections are turned away once the breaker trips.
+const FAILURE_BACKOFF: Duration = Duration::from_secs(30);
+/// How far ahead of the local clock a ticket may be created before the
+/// clocks are assumed to disagree.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+
+/// Tracks consecutive failed connections so a faulty node backs off
+/// instead of logging every single failure.
+#[derive(Debug)]
+struct CircuitBreaker {
+    threshold: usize,
+    failures: usize,
+    window_start: Option<Instant>,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    fn new(threshold: usize) -> Self {
+        Self {
+            threshold,
+            failures: 0,
+            window_start: None,
+            open_until: None,
+        }
+    }
+
+    /// How much longer connections are turned away, `None` once closed.
+    fn open_for(&mut self) -> Option<Duration> {
+        let remaining = self
+            .open_until?
+            .checked_duration_since(Instant::now())
+            .filter(|remaining| !remaining.is_zero());
+        if remaining.is_none() {
+            self.open_until = None;
+        }
+        remaining
+    }
+
+    fn record_success(&mut self) {
+        self.failures = 0;
+        self.window_start = None;
+    }
+
+    /// Records a failure, returning `true` if this failure tripped the breaker.
+    fn record_failure(&mut self) -> bool {
+        let now = Instant::now();
+        if self
+            .window_start
+            .is_none_or(|start| now.duration_since(start) > FAILURE_WINDOW)
+        {
+            self.window_start = Some(now);
+            self.failures = 0;
+        }
+
+        self.failures += 1;
+        if self.threshold == 0 || self.failures < self.threshold {
+            return false;
+        }
+
+        self.open_until = Some(now + FAILURE_BACKOFF);
+        self.record_success();
+        true
+    }
+}
+
+/// How [`PoofProtocol::receive`] retries failed connections, backing off
+/// exponentially with jitter.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt, 0 tries exactly once
+    pub retries: usize,
+    /// Delay before the first retry
+    pub base_delay: Duration,
+    /// Factor applied to the delay after each retry
+    pub multiplier: f64,
+    /// Upper bound for a single delay
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: DEFAULT_RETRIES,
+            base_delay: DEFAULT_RETRY_DELAY,
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retry number `attempt` (starting at 0), spread over the
+    /// upper half of the backoff so concurrent catchers don't retry in lockstep.
+    fn delay(&self, attempt: usize) -> Duration {
+        // Capping the exponent keeps the factor finite, overflowing durations saturate below
+        let factor = self.multiplier.powi(attempt.min(64) as i32);
+        let backoff = Duration::try_from_secs_f64(self.base_delay.as_secs_f64() * factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+/// Where a drop reads its content from.
+#[derive(Debug, Clone)]
+pub enum DropSource {
+    Path(PathBuf),
+    Stdin,
+}
+
+/// Optional knobs for [`PoofProtocol::send`].
+#[derive(Debug, Default)]
+pub struct SendOptions {
+    /// Serve the drop under each of these queries instead of one derived from its hash
+    pub queries: Vec<String>,
+    /// Follow symlinks inside a dropped directory instead of skipping them
+    pub follow_symlinks: bool,
+    /// Stop serving the drop once this much time has passed
+    pub expire: Option<Duration>,
+    /// Passphrase catchers must present
+    pub password: Option<String>,
+    /// Filename announced to catchers instead of the dropped file's own
+    pub name: Option<String>,
+    /// Wrap files in a collection that carries their name, see [`Ticket::wrapped`]
+    pub wrap: bool,
+    /// Note shown to catchers, see [`Ticket::note`]
+    pub note: Option<String>,
+    /// Send the mode and modification time of dropped files, see [`Ticket::mode`]
+    pub preserve_meta: bool,
+}
+
+/// Optional knobs for [`PoofProtocol::receive`].
+#[derive(Debug, Default, Clone)]
+pub struct ReceiveOptions {
+    /// Destination path, defaults to the ticket's filename in the current directory
+    pub output: Option<PathBuf>,
+    /// Destination named after the ticket, used when `output` is unset
+    pub output_template: Option<OutputTemplate>,
+    /// Directory bare filenames and relative outputs are written to, the current one if unset
+    pub output_dir: Option<PathBuf>,
+    /// Ticket file the served ticket and blob must match
+    pub expected: Option<TicketFile>,
+    /// Filename the served ticket must carry
+    pub expect_name: Option<String>,
+    /// Only fetch and write this slice of the file
+    pub range: Option<ByteRange>,
+    /// Passphrase for protected drops
+    pub password: Option<String>,
+    /// How to retry connecting to the host
+    pub retry: RetryPolicy,
+    /// What to do when the destination already exists
+    pub overwrite: Overwrite,
+    /// Continue from what an interrupted catch left in the blob store
+    pub resume: bool,
+    /// Give up when connecting and fetching the ticket takes longer than this
+    pub timeout: Option<Duration>,
+    /// Ask before downloading more than this many bytes, never when unset
+    pub confirm_above: Option<u64>,
+}
+
+/// How [`PoofProtocol::receive`] treats an existing destination.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Overwrite {
+    /// Ask on an interactive terminal, refuse otherwise
+    #[default]
+    Ask,
+    /// Replace the existing file
+    Force,
+    /// Write next to it under a numbered name
+    NoClobber,
+}
+
+/// What [`PoofProtocol::receive`] wrote.
+#[derive(Debug)]
+pub struct Received {
+    /// Where the content was written, `None` for stdout
+    pub path: Option<PathBuf>,
+    pub bytes: u64,
+}
+
+/// A byte range given as `offset:length`.
+#[derive(Debug, Clone, Copy)]
+pub struct ByteRange {
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl ByteRange {
+    pub fn end(&self) -> u64 {
+        self.offset + self.len
+    }
+}
+
+impl FromStr for ByteRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (offset, len) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected OFFSET:LENGTH, got '{s}'"))?;
+        let offset = offset
+            .parse()
+            .map_err(|e| format!("invalid offset '{offset}': {e}"))?;
+        let len: u64 = len
+            .parse()
+            .map_err(|e| format!("invalid length '{len}': {e}"))?;
+        if len == 0 {
+            return Err("length must be greater than 0".to_string());
+        }
+        if u64::checked_add(offset, len).is_none() {
+            return Err("range overflows".to_string());
+        }
+        Ok(Self { offset, len })
+    }
+}
+
+impl fmt::Display for ByteRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.offset, self.len)
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct PoofProtocol {
     pub endpoint: iroh::Endpoint,
     pub blobs: MemClient,
-    pub tickets: Arc<DashMap<String, Ticket>>,
+    pub tickets: TicketStore,
+    pub events: Events,
+    /// Connections being served, waited on before shutting down
+    pub active_transfers: ActiveTransfers,
+    /// Connections kept open to hosts so several requests share one
+    connections: Arc<DashMap<NodeId, Connection>>,
+    breaker: Arc<Mutex<CircuitBreaker>>,
+    /// How long a catcher may take to send its query, in milliseconds
+    idle_timeout: Arc<AtomicU64>,
 }
 
 impl PoofProtocol {
-    pub fn new(blobs: MemClient, endpoint: iroh::Endpoint) -> Arc<Self> {
+    pub fn new(
+        blobs: MemClient,
+        endpoint: iroh::Endpoint,
+        events: Events,
+        active_transfers: ActiveTransfers,
+        tickets: TicketStore,
+    ) -> Arc<Self> {
         Arc::new(PoofProtocol {
             endpoint,
             blobs,
-            tickets: Default::default(),
+            tickets,
+            events,
+            active_transfers,
+            connections: Default::default(),
+            breaker: Arc::new(Mutex::new(CircuitBreaker::new(DEFAULT_MAX_FAILURES))),
+            idle_timeout: Arc::new(AtomicU64::new(DEFAULT_IDLE_TIMEOUT.as_millis() as u64)),
         })
     }
 
-    pub async fn send(&self, file_path: PathBuf) -> anyhow::Result<Ticket> {
-        tracing::debug!("Dropping file: {:?}", file_path);
-        let res = self
+    /// Sets how long a connected catcher may stay silent before it is dropped.
+    pub fn set_idle_timeout(&self, timeout: Duration) {
+        self.idle_timeout
+            .store(timeout.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Sets how many consecutive failed connections trip the circuit breaker, `0` disables it.
+    pub fn set_max_failures(&self, threshold: usize) {
+        self.breaker
+            .lock()
+            .expect("circuit breaker poisoned")
+            .threshold = threshold;
+    }
+
+    /// Adds the dropped content to the blob store and serves it under each of
+    /// the given queries, or under a query derived from its hash when none are given.
+    pub async fn send(
+        &self,
+        source: DropSource,
+        options: SendOptions,
+    ) -> anyhow::Result<Vec<Ticket>> {
+        tracing::debug!("Dropping: {:?}", source);
+        check_queries(&self.tickets, &options.queries)?;
+
+        let (ticket, filename) = match source {
+            DropSource::Stdin => {
+                if options.wrap {
+                    bail!("Only files can be wrapped, stdin has no name to keep");
+                }
+                let res = self
+                    .blobs
+                    .add_reader(tokio::io::stdin(), SetTagOption::Auto)
+                    .await?
+                    .await?;
+                (Ticket::new(res.hash)?, None)
+            }
+            DropSource::Path(file_path) => {
+                let metadata = tokio::fs::metadata(&file_path).await?;
+                let file_type = metadata.file_type();
+                let filename = file_path
+                    .file_name()
+                    .and_then(|s| s.to_str())
+                    .map(|s| s.to_string());
+                // Directories are collections already, their entries keep their names
+                let ticket = if file_type.is_dir() {
+                    let (hash, entries) = self
+                        .add_directory(&file_path, options.follow_symlinks)
+                        .await?;
+                    if options.preserve_meta {
+                        warning!(
+                            "Only files keep their metadata, not the entries of '{}'",
+                            file_path.display()
+                        );
+                    }
+                    Ticket::new(hash)?.with_entries(entries)
+                } else if file_type.is_file() {
+                    let res = self
+                        .blobs
+                        .add_from_path(
+                            file_path.clone(),
+                            true,
+                            SetTagOption::Auto,
+                            if options.wrap {
+                                WrapOption::Wrap {
+                                    name: options.name.clone().or(filename.clone()),
+                                }
+                            } else {
+                                WrapOption::NoWrap
+                            },
+                        )
+                        .await?
+                        .await?;
+                    let ticket = Ticket::new(res.hash)?.with_wrapped(options.wrap);
+                    if options.preserve_meta {
+                        ticket.with_meta(&metadata)
+                    } else {
+                        ticket
+                    }
+                } else {
+                    bail!("Cannot drop a {}", describe_file_type(&file_type));
+                };
+                (ticket, filename)
+            }
+        };
+        let size = self.content_size(&ticket).await?;
+        let tickets = register(&self.tickets, ticket.with_size(size), filename, options)?;
+        self.tickets.save();
+        for ticket in &tickets {
+            tracing::debug!("File dropped with ticket: {}", ticket.public().pretty());
+        }
+
+        Ok(tickets)
+    }
+
+    /// Serves a blob already in the store, as left by an earlier drop to the
+    /// disk store, without adding anything.
+    pub async fn send_by_hash(
+        &self,
+        hash: Hash,
+        options: SendOptions,
+    ) -> anyhow::Result<Vec<Ticket>> {
+        tracing::debug!("Dropping stored blob: {}", hash);
+        check_queries(&self.tickets, &options.queries)?;
+
+        let size = match self.blobs.status(hash).await? {
+            BlobStatus::Complete { size } => size,
+            BlobStatus::Partial { .. } => bail!("Blob {} is only partially stored", hash),
+            BlobStatus::NotFound => bail!(
+                "Blob {} is not in the store, only the content of the last drop is kept between sessions",
+                hash
+            ),
+        };
+        // Blobs no saved ticket points at lost their tags, without one the
+        // blob would be collected while it is served
+        store::tag(&self.blobs, HashAndFormat::raw(hash)).await?;
+        let ticket = Ticket::new(hash)?.with_size(size);
+        let tickets = register(&self.tickets, ticket, None, options)?;
+        self.tickets.save();
+        for ticket in &tickets {
+            tracing::debug!("Blob dropped with ticket: {}", ticket.public().pretty());
+        }
+
+        Ok(tickets)
+    }
+
+    /// Serves the tickets saved by the last session again, skipping the
+    /// expired ones and those whose content is gone from the store.
+    pub async fn resume(&self) -> anyhow::Result<Vec<Ticket>> {
+        let mut resumed = Vec::new();
+        for ticket in self.tickets.saved()? {
+            if ticket.is_expired() {
+                tracing::debug!("Not resuming expired ticket: {}", ticket.query);
+                continue;
+            }
+            if let Err(e) = self.content_size(&ticket).await {
+                tracing::debug!("Content of {} is missing: {}", ticket.query, e);
+                warning!(
+                    "Not resuming {}: its content is no longer in the store",
+                    ticket.query.bold()
+                );
+                continue;
+            }
+            let hash = ticket.hash()?;
+            let content = if ticket.is_collection() {
+                HashAndFormat::hash_seq(hash)
+            } else {
+                HashAndFormat::raw(hash)
+            };
+            store::tag(&self.blobs, content).await?;
+            match self.tickets.entry(ticket.query.clone()) {
+                Entry::Vacant(entry) => {
+                    entry.insert(ticket.clone());
+                    resumed.push(ticket);
+                }
+                Entry::Occupied(_) => {
+                    warning!(
+                        "Not resuming {}: the query is already in use",
+                        ticket.query.bold()
+                    )
+                }
+            }
+        }
+        // Also writes out the pruning of what couldn't be resumed
+        self.tickets.save();
+        Ok(resumed)
+    }
+
+    /// Adds every file below `root` and wraps them in a collection,
+    /// returning the collection hash and its entry names.
+    async fn add_directory(
+        &self,
+        root: &Path,
+        follow_symlinks: bool,
+    ) -> anyhow::Result<(Hash, Vec<String>)> {
+        let files = scan_directory(root, follow_symlinks)?;
+        if files.is_empty() {
+            bail!("Directory '{}' contains no files to drop", root.display());
+        }
+
+        let mut collection = Collection::default();
+        let mut tags = Vec::with_capacity(files.len());
+        for (name, path) in files {
+            let res = self
+                .blobs
+                .add_from_path(path, true, SetTagOption::Auto, WrapOption::NoWrap)
+                .await?
+                .await?;
+            collection.push(name, res.hash);
+            tags.push(res.tag);
+        }
+
+        let entries = collection.iter().map(|(name, _)| name.clone()).collect();
+        // The collection tag keeps the entries alive, so their own tags can go
+        let (hash, _) = self
             .blobs
-            .add_from_path(
-                file_path.clone(),
-                true,
-                iroh_blobs::util::SetTagOption::Auto,
-                iroh_blobs::rpc::client::blobs::WrapOption::NoWrap,
-            )
-            .await?
+            .create_collection(collection, SetTagOption::Auto, tags)
             .await?;
+        Ok((hash, entries))
+    }
 
-        let ticket = Ticket::new(res.hash).with_filename(
-            file_path
-                .file_name()
-                .and_then(|s| s.to_str())
-                .map(|s| s.to_string()),
+    /// Stops serving expired tickets, returning the ones that were removed.
+    pub fn prune_expired(&self) -> Vec<Ticket> {
+        let expired = self
+            .tickets
+            .iter()
+            .filter(|entry| entry.value().is_expired())
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+
+        let expired = expired
+            .into_iter()
+            .filter_map(|query| self.tickets.remove(&query).map(|(_, ticket)| ticket))
+            .inspect(|ticket| self.report_expired(ticket, None))
+            .collect::<Vec<_>>();
+        if !expired.is_empty() {
+            self.tickets.save();
+        }
+        expired
+    }
+
+    /// Reports `ticket` as removed for having expired, when `node_id` asked
+    /// for it or while pruning.
+    fn report_expired(&self, ticket: &Ticket, node_id: Option<NodeId>) {
+        self.events.emit(
+            EventKind::Remove,
+            node_id,
+            format!("ticket expired: {}", ticket.query),
         );
+    }
 
-        tracing::debug!("File dropped with ticket: {}", ticket.pretty());
-        self.tickets
-            .insert(ticket.query.to_string(), ticket.clone());
+    /// Every ticket currently served by this node, sorted by query.
+    pub fn list_tickets(&self) -> Vec<Ticket> {
+        let mut tickets = self
+            .tickets
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect::<Vec<_>>();
+        tickets.sort_by(|a, b| a.query.cmp(&b.query));
+        tickets
+    }
 
-        Ok(ticket)
+    /// Stops serving `query`, returning whether it was served. Catchers
+    /// asking for it afterwards get [`ResponseCode::NotFound`], transfers
+    /// already started keep going.
+    pub fn remove_ticket(&self, query: &str) -> bool {
+        let Some((_, ticket)) = self.tickets.remove(query) else {
+            return false;
+        };
+        self.tickets.save();
+        tracing::debug!("Removed ticket: {}", ticket.public().pretty());
+        self.events.emit(
+            EventKind::Remove,
+            None,
+            format!("ticket removed: {}", ticket.query),
+        );
+        true
     }
 
-    pub async fn receive(
-        &self,
-        node_id: NodeId,
-        query: String,
-        out_file: Option<PathBuf>,
-    ) -> anyhow::Result<()> {
-        tracing::debug!("Receiving file for node: {}, query: {}", node_id, query);
-        let connection = self
-            .connect_with_retry(node_id, 3)
-            .await
-            .map_err(|e| crate::error!("Failed to connect to node: {}", e))?;
-        let (mut send, mut recv) = connection.open_bi().await?;
+    /// Total size of the content behind `ticket`, summed over entries for collections.
+    pub async fn content_size(&self, ticket: &Ticket) -> anyhow::Result<u64> {
+        if !ticket.is_collection() {
+            return self.blob_size(ticket.hash()?).await;
+        }
 
-        tracing::debug!("Sending query: {}", query);
-        send.write_u32(query.len() as u32).await?;
-        send.write_all(query.as_bytes()).await?;
+        let collection = self.blobs.get_collection(ticket.hash()?).await?;
+        let mut total = 0;
+        for (_, hash) in collection.iter() {
+            total += self.blob_size(*hash).await?;
+        }
+        Ok(total)
+    }
 
-        send.finish()?;
-        send.stopped().await?;
+    async fn blob_size(&self, hash: Hash) -> anyhow::Result<u64> {
+        match self.blobs.status(hash).await? {
+            BlobStatus::Complete { size } => Ok(size),
+            _ => bail!("Blob {} is not complete", hash),
+        }
+    }
 
-        let response_code = ResponseCode::from_u8(recv.read_u8().await?);
-        tracing::debug!("Received response code: {:?}", response_code);
+    /// Asks `node_id` for the ticket behind `query` without downloading
+    /// anything, along with the size of its content when the host can list it.
+    pub async fn check(
+        &self,
+        node_id: NodeId,
+        query: &str,
+        options: &ReceiveOptions,
+    ) -> anyhow::Result<(Ticket, Option<u64>)> {
+        let request = self.request_ticket(node_id, query, options);
+        let ticket = with_timeout(options.timeout, request, || {
+            format!("Requesting '{}' from node {}", query, node_id)
+        })
+        .await?;
+        check_expected(&ticket, options)?;
+        let size = self.served_size(node_id, &ticket, options).await?;
+        Ok((ticket, size))
+    }
 
-        match response_code {
-            Some(ResponseCode::Ok) => {
-                let size = recv.read_u32().await? as usize;
-                tracing::debug!("Received ticket size: {}", size);
-                let mut buffer = vec![0; size];
-                recv.read_exact(&mut buffer).await?;
+    /// Size of the content behind `ticket`, asking `node_id` for its listing
+    /// when the ticket comes from a host too old to carry it.
+    async fn served_size(
+        &self,
+        node_id: NodeId,
+        ticket: &Ticket,
+        options: &ReceiveOptions,
+    ) -> anyhow::Result<Option<u64>> {
+        if ticket.size.is_some() {
+            return Ok(ticket.size);
+        }
+        // Only kept connections speak poof/1, older hosts can't list
+        if self.kept_connection(node_id).is_none() {
+            return Ok(None);
+        }
+        Ok(self
+            .list(node_id, options.retry, options.timeout)
+            .await?
+            .into_iter()
+            .find(|listing| listing.query == ticket.query)
+            .map(|listing| listing.size))
+    }
 
-                let ticket: Ticket = facet_msgpack::from_slice(&buffer)
-                    .map_err(|e| crate::error!("Failed to deserialize ticket: {}", e))?;
+    pub async fn receive(
+        &self,
+        node_id: NodeId,
+        query: String,
+        options: ReceiveOptions,
+    ) -> anyhow::Result<Received> {
+        tracing::debug!("Receiving file for node: {}, query: {}", node_id, query);
+        let request = self.request_ticket(node_id, &query, &options);
+        let ticket = with_timeout(options.timeout, request, || {
+            format!("Requesting '{}' from node {}", query, node_id)
+        })
+        .await?;
+        check_expected(&ticket, &options)?;
+        if let Some(note) = &ticket.note {
+            info!("Note from node {}: {}", node_id.reduced(), note);
+        }
 
-                let res = self
-                    .blobs
-                    .download(ticket.hash(), node_id.into())
-                    .await?
-                    .await?;
-                tracing::debug!("Downloading file with ticket: {:?}", res);
-                let file = if let Some(ref out_file) = out_file {
-                    if out_file.is_absolute() {
-                        out_file.clone()
-                    } else {
-                        std::env::current_dir()
-                            .unwrap_or_else(|_| PathBuf::from("."))
-                            .join(out_file)
+        let to_stdout = options.output.as_deref() == Some(Path::new("-"));
+        let name = match ticket.filename.as_deref() {
+            Some(name) => name,
+            None => ticket
+                .hash
+                .get(..8)
+                .ok_or_else(|| crate::error!("Ticket for query '{}' has an invalid hash", query))?,
+        };
+        if let Some(size) = ticket.size {
+            info!("{} is {}", name.bold(), format_bytes(size));
+        }
+        // A range is as large as asked for, no need to confirm it
+        if options.range.is_none()
+            && let Some(threshold) = options.confirm_above
+            && let Some(size) = self.served_size(node_id, &ticket, &options).await?
+            && size > threshold
+        {
+            confirm_download(&query, size)?;
+        }
+        let templated = options
+            .output_template
+            .as_ref()
+            .map(|template| PathBuf::from(template.expand(&ticket, name)));
+        if let Some(templated) = &templated {
+            check_template_output(templated)?;
+        }
+        let output = options.output.as_ref().or(templated.as_ref());
+        let file = match output {
+            Some(out_file) if out_file.is_absolute() || to_stdout => out_file.clone(),
+            // Relative outputs land in the output directory like bare filenames do
+            out_file => {
+                let dir = match &options.output_dir {
+                    Some(dir) => {
+                        std::fs::create_dir_all(dir).map_err(|e| PoofError::Export {
+                            path: dir.display().to_string(),
+                            source: e.into(),
+                        })?;
+                        dir.clone()
                     }
-                } else {
-                    std::env::current_dir()
-                        .unwrap_or_else(|_| PathBuf::from("."))
-                        .join(ticket.filename.as_deref().unwrap_or(&ticket.hash[..8]))
+                    None => std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")),
                 };
+                match out_file {
+                    Some(out_file) => dir.join(out_file),
+                    // The name comes from the host, it must not climb out of the directory
+                    None => entry_path(&dir, name)?,
+                }
+            }
+        };
+        // An output naming a directory, existing or with a trailing slash,
+        // receives the content under its own name like `cp` would
+        let file = match output {
+            Some(out_file) if !to_stdout && (file.is_dir() || names_directory(out_file)) => {
+                std::fs::create_dir_all(&file).map_err(|e| PoofError::Export {
+                    path: file.display().to_string(),
+                    source: e.into(),
+                })?;
+                entry_path(&file, name)?
+            }
+            _ => file,
+        };
 
-                tracing::debug!("Writing file to {:?}", file);
-                self.blobs
-                    .export(
-                        ticket.hash(),
-                        file,
-                        iroh_blobs::store::ExportFormat::Blob,
-                        iroh_blobs::store::ExportMode::Copy,
-                    )
-                    .await?
-                    .await?;
+        let file = if to_stdout {
+            file
+        } else {
+            // Templates may name directories that don't exist yet
+            if templated.is_some()
+                && let Some(parent) = file.parent()
+            {
+                std::fs::create_dir_all(parent).map_err(|e| PoofError::Export {
+                    path: parent.display().to_string(),
+                    source: e.into(),
+                })?;
             }
-            Some(ResponseCode::NotFound) => {
-                bail!("Ticket not found for query: {}", query)
+            resolve_destination(file, options.overwrite)?
+        };
+
+        if ticket.is_directory() {
+            if options.range.is_some() {
+                bail!("--range is not supported for directory drops");
             }
-            Some(ResponseCode::Error) => {
-                bail!("An error occurred while processing the request");
+            if to_stdout {
+                bail!("Directory drops can't be written to stdout");
             }
-            None => {
-                bail!("Received invalid response code");
+            let bytes = self
+                .receive_directory(node_id, &ticket, &file, &options)
+                .await?;
+            return Ok(Received {
+                path: Some(file),
+                bytes,
+            });
+        }
+
+        if ticket.wrapped {
+            if options.range.is_some() {
+                bail!("--range is not supported for wrapped drops");
             }
+            return self
+                .receive_wrapped(node_id, &ticket, file, to_stdout, &options)
+                .await;
         }
 
-        Ok(())
+        if let Some(range) = options.range {
+            let written = if to_stdout {
+                let mut out = tokio::io::stdout();
+                self.receive_range(node_id, ticket.hash()?, range, &mut out)
+                    .await?
+            } else {
+                let mut out = tokio::fs::File::create(&file).await?;
+                self.receive_range(node_id, ticket.hash()?, range, &mut out)
+                    .await?
+            };
+            tracing::debug!("Wrote {} bytes of range {} to {:?}", written, range, file);
+            return Ok(Received {
+                path: (!to_stdout).then_some(file),
+                bytes: written,
+            });
+        }
+
+        let resumed = self
+            .prepare_download(ticket.hash()?, false, options.resume)
+            .await?;
+        let download = async {
+            track_download(self.blobs.download(ticket.hash()?, node_id.into()).await?).await
+        };
+        let res = download.await.map_err(|e| PoofError::Download {
+            hash: ticket.hash.clone(),
+            source: e.into(),
+        })?;
+        tracing::debug!("Downloading file with ticket: {:?}", res);
+        if resumed {
+            info!(
+                "Resumed from stored data, fetched {} of {}",
+                format_bytes(res.bytes_read),
+                format_bytes(self.blob_size(ticket.hash()?).await?)
+            );
+        }
+
+        if let Some(expected) = &options.expected
+            && let BlobStatus::Complete { size } = self.blobs.status(ticket.hash()?).await?
+            && size != expected.size
+        {
+            bail!(
+                "Size mismatch: expected {} bytes, but received {}",
+                expected.size,
+                size
+            );
+        }
+
+        self.verify(ticket.hash()?).await?;
+        success!("Verified {}", ticket.hash);
+        store::release(&self.blobs, ticket.hash()?).await?;
+        let bytes = self.blob_size(ticket.hash()?).await?;
+
+        if to_stdout {
+            let mut reader = self.blobs.read(ticket.hash()?).await?;
+            let mut out = tokio::io::stdout();
+            tokio::io::copy(&mut reader, &mut out).await?;
+            out.flush().await?;
+            return Ok(Received { path: None, bytes });
+        }
+
+        tracing::debug!("Writing file to {:?}", file);
+        self.export(ticket.hash()?, &file, ExportFormat::Blob)
+            .await?;
+        restore_meta(&ticket, &file);
+        Ok(Received {
+            path: Some(file),
+            bytes,
+        })
     }
 
-    async fn connect_with_retry(
+    /// Connects to `node_id`, keeping the connection for later requests when
+    /// the host can serve several over one.
+    async fn open_connection(
         &self,
         node_id: NodeId,
-        retries: usize,
-    ) -> anyhow::Result<iroh::endpoint::Connection> {
-        let mut attempts = 0;
-        loop {
-            match self.endpoint.connect(node_id, ALPN).await {
-                Ok(connection) => return Ok(connection),
-                Err(_) if attempts < retries => {
-                    tracing::warn!(
-                        "Connection failed, retrying... ({}/{})",
-                        attempts + 1,
-                        retries
-                    );
-                    attempts += 1;
-                    tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+        retry: RetryPolicy,
+    ) -> anyhow::Result<Connection> {
+        let connection = self.connect_with_retry(node_id, retry).await.map_err(|e| {
+            if is_alpn_mismatch(&e) {
+                PoofError::IncompatibleProtocol {
+                    node_id: node_id.to_string(),
+                    alpn: String::from_utf8_lossy(ALPN).to_string(),
+                }
+            } else {
+                PoofError::Connection {
+                    node_id: node_id.to_string(),
+                    source: e.into(),
                 }
-                Err(e) => return Err(e.into()),
             }
+        })?;
+        // Older hosts close the connection after a single request
+        if !is_legacy(&connection) {
+            self.connections.insert(node_id, connection.clone());
         }
+        Ok(connection)
     }
-}
 
-impl ProtocolHandler for PoofProtocol {
-    fn accept(&self, connection: iroh::endpoint::Connection) -> BoxedFuture<anyhow::Result<()>> {
-        let this = self.clone();
-        Box::pin(async move {
-            tracing::debug!("Accepted blob ticket connection: {:?}", connection);
+    /// The connection kept from an earlier request to `node_id`, if still open.
+    fn kept_connection(&self, node_id: NodeId) -> Option<Connection> {
+        self.connections
+            .get(&node_id)
+            .map(|connection| connection.clone())
+            .filter(|connection| connection.close_reason().is_none())
+    }
 
-            let (mut send, mut recv) = connection.accept_bi().await?;
+    /// Opens the stream a request is sent on.
+    async fn open_stream(
+        connection: &Connection,
+        node_id: NodeId,
+    ) -> anyhow::Result<(SendStream, RecvStream)> {
+        Ok(connection
+            .open_bi()
+            .await
+            .map_err(|e| PoofError::Connection {
+                node_id: node_id.to_string(),
+                source: e.into(),
+            })?)
+    }
 
-            let query_size = recv.read_u32().await?;
-            tracing::debug!("Received query size: {}", query_size);
-            if query_size == 0 {
-                tracing::warn!("Received empty query, closing connection");
-                send.write_u8(ResponseCode::Error.to_u8()).await?;
-                send.write_u32(0).await?;
-                send.finish()?;
-                return Ok(());
+    /// Asks `node_id` for every ticket it currently serves.
+    pub async fn list(
+        &self,
+        node_id: NodeId,
+        retry: RetryPolicy,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<Vec<Listing>> {
+        let request = async {
+            let connection = match self.kept_connection(node_id) {
+                Some(connection) => connection,
+                None => self.open_connection(node_id, retry).await?,
+            };
+            if is_legacy(&connection) {
+                bail!(
+                    "Node {} runs an older poof that can't list its drops",
+                    node_id
+                );
             }
 
-            let query = {
-                let mut buf = vec![0; query_size as usize];
-                recv.read_exact(&mut buf).await?;
-                String::from_utf8(buf).map_err(|e| crate::error!("Invalid UTF-8: {}", e))?
-            };
+            let (mut send, mut recv) = Self::open_stream(&connection, node_id).await?;
+            Request::List.write(&mut send, false).await?;
+            send.finish()?;
+            send.stopped().await?;
+
+            let response = read_response(&connection, &mut recv, MAX_LISTING_SIZE).await?;
+            match response.code {
+                ResponseCode::Ok => {
+                    let listings: Listings = facet_msgpack::from_slice(&response.payload)
+                        .map_err(|e| crate::error!("Failed to deserialize listing: {}", e))?;
+                    Ok(listings.tickets)
+                }
+                _ => bail!("Node {} refused to list its drops", node_id),
+            }
+        };
+        with_timeout(timeout, request, || {
+            format!("Listing the drops of node {}", node_id)
+        })
+        .await
+    }
 
-            tracing::debug!("Received query: {}", query);
-
-            if let Some(ticket) = this.tickets.get(&query) {
-                tracing::debug!("Found ticket: {}", ticket.pretty());
-                send.write_u8(ResponseCode::Ok.to_u8()).await?;
-                let ticket = ticket.value();
-                let bytes = facet_msgpack::to_vec(ticket);
-                send.write_u32(bytes.len() as u32).await?;
-                send.write_all(&bytes).await?;
-                info!(
-                    "Node {} requested ticket: {}",
-                    connection.remote_node_id()?.reduced(),
-                    ticket.query.blue().bold()
+    /// Sends `node_id` a ping over the connection kept from an earlier one,
+    /// returning the time its answer took.
+    pub async fn ping(
+        &self,
+        node_id: NodeId,
+        retry: RetryPolicy,
+        timeout: Duration,
+    ) -> anyhow::Result<Duration> {
+        // Connecting is part of the wait, an unreachable node would otherwise
+        // hold the ping up for as long as discovery keeps trying
+        let request = async {
+            let connection = match self.kept_connection(node_id) {
+                Some(connection) => connection,
+                None => self.open_connection(node_id, retry).await?,
+            };
+            if is_legacy(&connection) {
+                bail!(
+                    "Node {} runs an older poof that can't answer pings",
+                    node_id
                 );
-            } else {
-                tracing::warn!("Ticket not found for query: {}", query);
-                send.write_u8(ResponseCode::NotFound.to_u8()).await?;
-                send.write_u32(0).await?;
             }
 
+            let started = Instant::now();
+            let (mut send, mut recv) = Self::open_stream(&connection, node_id).await?;
+            Request::Ping.write(&mut send, false).await?;
             send.finish()?;
+            // Hosts that predate pings drop the connection instead of answering
+            let response = read_response(&connection, &mut recv, 0)
+                .await
+                .map_err(|e| {
+                    crate::error!(
+                        "Node {} didn't answer the ping, it may run an older poof: {}",
+                        node_id,
+                        e
+                    )
+                })?;
+            match response.code {
+                ResponseCode::Ok => Ok(started.elapsed()),
+                code => bail!("Node {} answered the ping with {:?}", node_id, code),
+            }
+        };
+        with_timeout(Some(timeout), request, || {
+            format!("Pinging node {}", node_id)
+        })
+        .await
+    }
 
-            send.stopped().await?;
+    /// Asks `node_id` for the ticket behind `query`, over the connection of an
+    /// earlier request when there is one.
+    async fn request_ticket(
+        &self,
+        node_id: NodeId,
+        query: &str,
+        options: &ReceiveOptions,
+    ) -> anyhow::Result<Ticket> {
+        if let Some(connection) = self.kept_connection(node_id) {
+            match self.ask_ticket(&connection, node_id, query, options).await {
+                // The host drops connections that stay idle for too long
+                Err(e) if connection.close_reason().is_some() => {
+                    tracing::debug!(
+                        "Kept connection to {} closed ({}), reconnecting",
+                        node_id,
+                        e
+                    )
+                }
+                res => return res,
+            }
+        }
+        let connection = self.open_connection(node_id, options.retry).await?;
+        self.ask_ticket(&connection, node_id, query, options).await
+    }
 
-            Ok(())
-        })
+    async fn ask_ticket(
+        &self,
+        connection: &Connection,
+        node_id: NodeId,
+        query: &str,
+        options: &ReceiveOptions,
+    ) -> anyhow::Result<Ticket> {
+        let (mut send, mut recv) = Self::open_stream(connection, node_id).await?;
+
+        tracing::debug!("Sending query: {}", query);
+        let request = Request::Query {
+            query: query.to_string(),
+            password: options.password.clone(),
+        };
+        request.write(&mut send, is_legacy(connection)).await?;
+        send.finish()?;
+        send.stopped().await?;
+
+        let response = read_response(connection, &mut recv, MAX_TICKET_SIZE).await?;
+        tracing::debug!("Received response code: {:?}", response.code);
+
+        match response.code {
+            ResponseCode::Ok => {
+                let ticket: Ticket = facet_msgpack::from_slice(&response.payload)
+                    .map_err(|e| crate::error!("Failed to deserialize ticket: {}", e))?;
+                // Reject malformed hashes from the remote before anything relies on them
+                ticket.hash()?;
+                if let Some(note) = &ticket.note {
+                    Ticket::check_note(note)
+                        .map_err(|e| crate::error!("Node {} sent an invalid {}", node_id, e))?;
+                }
+                warn_clock_skew(node_id, &ticket);
+                Ok(ticket)
+            }
+            ResponseCode::NotFound => {
+                bail!("Ticket not found for query: {}", query)
+            }
+            ResponseCode::Expired => {
+                bail!("Ticket for query '{}' has expired", query)
+            }
+            ResponseCode::Unauthorized => {
+                if options.password.is_some() {
+                    bail!("Wrong password for query '{}'", query)
+                }
+                bail!(
+                    "Query '{}' is password protected, pass it with --password",
+                    query
+                )
+            }
+            ResponseCode::Ambiguous => {
+                let candidates = response.text().lines().collect::<Vec<_>>().join(", ");
+                bail!(
+                    "Several drops are named '{}', catch one of them by query: {}",
+                    query,
+                    candidates
+                )
+            }
+            ResponseCode::RateLimited if response.payload.is_empty() => {
+                bail!("Node {} is busy, try again later", node_id)
+            }
+            ResponseCode::RateLimited => {
+                bail!(
+                    "Node {} is busy, try again later: {}",
+                    node_id,
+                    response.text()
+                )
+            }
+            // Older hosts send no reason
+            ResponseCode::Error if response.payload.is_empty() => {
+                bail!("An error occurred while processing the request");
+            }
+            ResponseCode::Error => {
+                bail!("Node {} rejected the request: {}", node_id, response.text());
+            }
+        }
+    }
+
+    /// Downloads a directory drop and recreates its tree under `root`.
+    async fn receive_directory(
+        &self,
+        node_id: NodeId,
+        ticket: &Ticket,
+        root: &Path,
+        options: &ReceiveOptions,
+    ) -> anyhow::Result<u64> {
+        let (collection, size) = self.download_collection(node_id, ticket, options).await?;
+
+        for (name, hash) in collection.iter() {
+            let path = entry_path(root, name)?;
+            tracing::debug!("Writing entry {} to {:?}", name, path);
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            self.export(*hash, &path, ExportFormat::Blob).await?;
+        }
+
+        Ok(size)
+    }
+
+    /// Downloads a file wrapped in a single-entry collection. iroh-blobs
+    /// restores the entry's name itself unless the destination renames it.
+    async fn receive_wrapped(
+        &self,
+        node_id: NodeId,
+        ticket: &Ticket,
+        file: PathBuf,
+        to_stdout: bool,
+        options: &ReceiveOptions,
+    ) -> anyhow::Result<Received> {
+        let (collection, bytes) = self.download_collection(node_id, ticket, options).await?;
+        let mut entries = collection.iter();
+        let (Some((name, hash)), None) = (entries.next(), entries.next()) else {
+            bail!(
+                "Wrapped drop {} should hold exactly one file, found {}",
+                ticket.hash,
+                collection.len()
+            );
+        };
+
+        if to_stdout {
+            let mut reader = self.blobs.read(*hash).await?;
+            let mut out = tokio::io::stdout();
+            tokio::io::copy(&mut reader, &mut out).await?;
+            out.flush().await?;
+            return Ok(Received { path: None, bytes });
+        }
+
+        tracing::debug!("Writing wrapped entry {} to {:?}", name, file);
+        match file.parent() {
+            Some(parent) if file.file_name() == Some(name.as_ref()) => {
+                self.export(ticket.hash()?, parent, ExportFormat::Collection)
+                    .await?
+            }
+            _ => self.export(*hash, &file, ExportFormat::Blob).await?,
+        }
+        restore_meta(ticket, &file);
+        Ok(Received {
+            path: Some(file),
+            bytes,
+        })
+    }
+
+    /// Downloads the collection behind `ticket` and verifies its entries,
+    /// returning it along with the total size of the entries.
+    async fn download_collection(
+        &self,
+        node_id: NodeId,
+        ticket: &Ticket,
+        options: &ReceiveOptions,
+    ) -> anyhow::Result<(Collection, u64)> {
+        let resumed = self
+            .prepare_download(ticket.hash()?, true, options.resume)
+            .await?;
+        let download = async {
+            track_download(
+                self.blobs
+                    .download_hash_seq(ticket.hash()?, node_id.into())
+                    .await?,
+            )
+            .await
+        };
+        let stats = download.await.map_err(|e| PoofError::Download {
+            hash: ticket.hash.clone(),
+            source: e.into(),
+        })?;
+
+        let size = self.content_size(ticket).await?;
+        if resumed {
+            info!(
+                "Resumed from stored data, fetched {} of {}",
+                format_bytes(stats.bytes_read),
+                format_bytes(size)
+            );
+        }
+        if let Some(expected) = &options.expected
+            && size != expected.size
+        {
+            bail!(
+                "Size mismatch: expected {} bytes, but received {}",
+                expected.size,
+                size
+            );
+        }
+
+        let collection = self.blobs.get_collection(ticket.hash()?).await?;
+        for (_, hash) in collection.iter() {
+            self.verify(*hash).await?;
+        }
+        if ticket.is_directory() {
+            success!("Verified {} ({} files)", ticket.hash, collection.len());
+        } else {
+            success!("Verified {}", ticket.hash);
+        }
+        store::release(&self.blobs, ticket.hash()?).await?;
+
+        Ok((collection, size))
+    }
+
+    /// Writes a stored blob to `path`, or the entries of a collection into the
+    /// directory `path`. iroh-blobs won't copy over an existing file, so any
+    /// that [`resolve_destination`] let through is removed first.
+    async fn export(&self, hash: Hash, path: &Path, format: ExportFormat) -> crate::Result<()> {
+        let export = async {
+            let targets = match format {
+                ExportFormat::Blob => vec![path.to_path_buf()],
+                ExportFormat::Collection => self
+                    .blobs
+                    .get_collection(hash)
+                    .await?
+                    .iter()
+                    .map(|(name, _)| path.join(name))
+                    .collect(),
+            };
+            for target in targets {
+                if tokio::fs::try_exists(&target).await? {
+                    tokio::fs::remove_file(&target).await?;
+                }
+            }
+            self.blobs
+                .export(
+                    hash,
+                    path.to_path_buf(),
+                    format,
+                    iroh_blobs::store::ExportMode::Copy,
+                )
+                .await?
+                .await
+        };
+        export.await.map_err(|e| PoofError::Export {
+            path: path.display().to_string(),
+            source: e.into(),
+        })?;
+        Ok(())
+    }
+
+    /// Looks for data an interrupted catch left in the store. iroh-blobs only
+    /// requests the chunks it is missing, so keeping it resumes the download.
+    /// Without `resume` it is deleted instead, along with the entries of a
+    /// collection when they are known. Returns whether stored data is reused.
+    ///
+    /// Either way the content is kept until [`store::release`]d once the
+    /// download completes, so the collector of a later drop doesn't sweep
+    /// away what an interrupted one leaves.
+    async fn prepare_download(
+        &self,
+        hash: Hash,
+        collection: bool,
+        resume: bool,
+    ) -> anyhow::Result<bool> {
+        let resumed = match self.blobs.status(hash).await? {
+            BlobStatus::NotFound => false,
+            _ if !resume => {
+                tracing::debug!("Discarding stored data for {}", hash);
+                if collection && let Ok(entries) = self.blobs.get_collection(hash).await {
+                    for (_, entry) in entries.iter() {
+                        self.blobs.delete_blob(*entry).await?;
+                    }
+                }
+                self.blobs.delete_blob(hash).await?;
+                false
+            }
+            _ => {
+                tracing::debug!("Resuming from stored data for {}", hash);
+                true
+            }
+        };
+
+        let content = if collection {
+            HashAndFormat::hash_seq(hash)
+        } else {
+            HashAndFormat::raw(hash)
+        };
+        store::keep(&self.blobs, content).await?;
+        Ok(resumed)
+    }
+
+    /// Fetches only the chunks covering `range` straight from the host and
+    /// writes the requested bytes to `out`, bypassing the local blob store.
+    async fn receive_range(
+        &self,
+        node_id: NodeId,
+        hash: Hash,
+        range: ByteRange,
+        out: &mut (impl AsyncWrite + Unpin),
+    ) -> anyhow::Result<u64> {
+        let connection = self.endpoint.connect(node_id, iroh_blobs::ALPN).await?;
+        let chunks =
+            ChunkRanges::from(ChunkNum::full_chunks(range.offset)..ChunkNum::chunks(range.end()));
+        let request = GetRequest::new(hash, RangeSpecSeq::from_ranges([chunks]));
+
+        let connected = fsm::start(connection, request).next().await?;
+        let fsm::ConnectedNext::StartRoot(root) = connected.next().await? else {
+            bail!("Unexpected response to range request");
+        };
+        let (mut content, size) = root.next().next().await?;
+        if range.end() > size {
+            bail!("Range {} is out of bounds for a {} byte file", range, size);
+        }
+
+        let mut written = 0;
+        let end = loop {
+            match content.next().await {
+                BlobContentNext::More((next, item)) => {
+                    if let BaoContentItem::Leaf(leaf) = item? {
+                        let leaf_end = leaf.offset + leaf.data.len() as u64;
+                        let start = range.offset.max(leaf.offset);
+                        let stop = range.end().min(leaf_end);
+                        if start < stop {
+                            let slice =
+                                (start - leaf.offset) as usize..(stop - leaf.offset) as usize;
+                            out.write_all(&leaf.data[slice]).await?;
+                            written += stop - start;
+                        }
+                    }
+                    content = next;
+                }
+                BlobContentNext::Done(end) => break end,
+            }
+        };
+        out.flush().await?;
+
+        if let fsm::EndBlobNext::Closing(closing) = end.next() {
+            closing.next().await?;
+        }
+        Ok(written)
+    }
+
+    /// Rehashes a stored blob, so a corrupt store or transfer never reaches the output.
+    async fn verify(&self, hash: Hash) -> anyhow::Result<()> {
+        let (actual, _) = hash_reader(self.blobs.read(hash).await?).await?;
+        if actual != hash {
+            return Err(PoofError::IntegrityMismatch {
+                expected: hash.to_string(),
+                actual: actual.to_string(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Connects with [`ALPN`], falling back to [`LEGACY_ALPN`] for older hosts.
+    async fn connect(&self, node_id: NodeId) -> anyhow::Result<Connection> {
+        match self.endpoint.connect(node_id, ALPN).await {
+            Err(e) if is_alpn_mismatch(&e) => {
+                tracing::debug!(
+                    "Node {} doesn't speak poof/1, retrying with poof/0",
+                    node_id
+                );
+                self.endpoint.connect(node_id, LEGACY_ALPN).await
+            }
+            res => res,
+        }
+    }
+
+    async fn connect_with_retry(
+        &self,
+        node_id: NodeId,
+        policy: RetryPolicy,
+    ) -> anyhow::Result<iroh::endpoint::Connection> {
+        let mut attempts = 0;
+        loop {
+            match self.connect(node_id).await {
+                Ok(connection) => return Ok(connection),
+                Err(e) if attempts < policy.retries && !is_alpn_mismatch(&e) => {
+                    let delay = policy.delay(attempts);
+                    tracing::warn!(
+                        "Connection failed, retrying in {}... ({}/{})",
+                        format_duration(delay),
+                        attempts + 1,
+                        policy.retries
+                    );
+                    attempts += 1;
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Looks up the ticket served under `query`, falling back to the drops
+    /// whose filename is `query`. Several drops of different content sharing
+    /// that filename yield their queries instead.
+    fn find_ticket(&self, query: &str) -> Result<Option<Ticket>, Vec<String>> {
+        if let Some(ticket) = self.tickets.get(query) {
+            return Ok(Some(ticket.clone()));
+        }
+
+        let mut matches = self
+            .tickets
+            .iter()
+            .filter(|entry| entry.filename.as_deref() == Some(query))
+            .map(|entry| entry.value().clone())
+            .collect::<Vec<_>>();
+        matches.sort_by(|a, b| a.query.cmp(&b.query));
+        // Queries of the same content are interchangeable
+        matches.dedup_by(|a, b| a.hash == b.hash);
+
+        match matches.len() {
+            0 => Ok(None),
+            1 => Ok(matches.pop()),
+            _ => Err(matches.into_iter().map(|ticket| ticket.query).collect()),
+        }
+    }
+
+    /// Answers an [`Opcode::List`] request with every ticket still served.
+    async fn send_listing(&self, node_id: NodeId, mut send: SendStream) -> anyhow::Result<()> {
+        let mut tickets = Vec::new();
+        for ticket in self.list_tickets() {
+            if ticket.is_expired() {
+                continue;
+            }
+            let size = match ticket.size {
+                Some(size) => size,
+                None => self.content_size(&ticket).await?,
+            };
+            tickets.push(Listing {
+                size,
+                protected: ticket.password_hash.is_some(),
+                query: ticket.query,
+                filename: ticket.filename,
+            });
+        }
+
+        info!(
+            "Node {} listed {} ticket(s)",
+            node_id.reduced(),
+            tickets.len()
+        );
+        self.events.emit(
+            EventKind::Serve,
+            Some(node_id),
+            format!("listed {} ticket(s)", tickets.len()),
+        );
+        Response::new(
+            ResponseCode::Ok,
+            facet_msgpack::to_vec(&Listings { tickets }),
+        )
+        .write(&mut send)
+        .await?;
+        send.finish()?;
+        send.stopped().await?;
+        Ok(())
+    }
+
+    async fn handle_connection(
+        &self,
+        connection: iroh::endpoint::Connection,
+    ) -> anyhow::Result<()> {
+        tracing::debug!("Accepted blob ticket connection: {:?}", connection);
+        let node_id = connection.remote_node_id()?;
+        self.events
+            .emit(EventKind::Accept, Some(node_id), "connection accepted");
+
+        let idle_timeout = Duration::from_millis(self.idle_timeout.load(Ordering::Relaxed));
+        let legacy = is_legacy(&connection);
+        // Catchers may send several requests, each on its own stream
+        let mut served = 0;
+        loop {
+            let read_request = async {
+                let (send, mut recv) = match connection.accept_bi().await {
+                    Ok(streams) => streams,
+                    // Closed by the catcher once it asked for everything
+                    Err(_) if served > 0 => return Ok(None),
+                    Err(e) => return Err(e.into()),
+                };
+                anyhow::Ok(Some((send, Request::read(&mut recv, legacy).await)))
+            };
+
+            let (mut send, request) = match tokio::time::timeout(idle_timeout, read_request).await {
+                Ok(res) => match res? {
+                    Some(request) => request,
+                    None => return Ok(()),
+                },
+                Err(_) => {
+                    connection.close(0u32.into(), b"idle timeout");
+                    if served > 0 {
+                        return Ok(());
+                    }
+                    bail!(
+                        "Node {} sent no query within {}",
+                        node_id,
+                        format_duration(idle_timeout)
+                    );
+                }
+            };
+            let request = match request {
+                Ok(request) => request,
+                // Tell the catcher why instead of just dropping the stream
+                Err(e @ FrameError::TooLarge { .. }) => {
+                    Response::new(ResponseCode::Error, e.to_string())
+                        .write(&mut send)
+                        .await?;
+                    send.finish()?;
+                    send.stopped().await?;
+                    bail!("Node {} sent an oversized request: {}", node_id, e);
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            self.answer(node_id, send, request).await?;
+            served += 1;
+            // Older catchers close the connection after a single request
+            if legacy {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Answers the first request on `connection` with
+    /// [`ResponseCode::RateLimited`], saying when to come back.
+    async fn turn_away(&self, connection: &Connection, open_for: Duration) -> anyhow::Result<()> {
+        let idle_timeout = Duration::from_millis(self.idle_timeout.load(Ordering::Relaxed));
+        let legacy = is_legacy(connection);
+        let answer = async {
+            let (mut send, mut recv) = connection.accept_bi().await?;
+            Request::read(&mut recv, legacy).await?;
+            let reason = format!("backing off for {}", format_duration(open_for));
+            Response::new(ResponseCode::RateLimited, reason)
+                .write(&mut send)
+                .await?;
+            send.finish()?;
+            send.stopped().await?;
+            anyhow::Ok(())
+        };
+        tokio::time::timeout(idle_timeout, answer).await?
+    }
+
+    /// Answers a single request read by [`Self::handle_connection`].
+    async fn answer(
+        &self,
+        node_id: NodeId,
+        mut send: SendStream,
+        request: Request,
+    ) -> anyhow::Result<()> {
+        let (query, password) = match request {
+            Request::List => return self.send_listing(node_id, send).await,
+            Request::Ping => {
+                tracing::debug!("Answering ping from {}", node_id);
+                Response::empty(ResponseCode::Ok).write(&mut send).await?;
+                send.finish()?;
+                return Ok(());
+            }
+            Request::Query { query, .. } if query.is_empty() => {
+                tracing::warn!("Received empty query, closing connection");
+                Response::new(ResponseCode::Error, "empty query")
+                    .write(&mut send)
+                    .await?;
+                send.finish()?;
+                return Ok(());
+            }
+            Request::Query { query, password } => (query, password),
+        };
+
+        tracing::debug!("Received query: {}", query);
+
+        // Cloned so the map isn't locked while we write to the stream
+        let ticket = match self.find_ticket(&query) {
+            Ok(ticket) => ticket,
+            Err(candidates) => {
+                tracing::warn!("Query '{}' matches several drops", query);
+                self.events.emit(
+                    EventKind::Error,
+                    Some(node_id),
+                    format!("ambiguous query: {}", query),
+                );
+                Response::new(ResponseCode::Ambiguous, candidates.join("\n"))
+                    .write(&mut send)
+                    .await?;
+                send.finish()?;
+                send.stopped().await?;
+                return Ok(());
+            }
+        };
+        // Blobs can go missing under a running drop, e.g. with the disk store
+        // pruned by another process, so say so instead of a ticket that fails
+        let missing = match &ticket {
+            Some(ticket) => self.content_size(ticket).await.err(),
+            None => None,
+        };
+        if let Some(ticket) = ticket.as_ref().filter(|ticket| ticket.is_expired()) {
+            tracing::debug!("Ticket expired: {}", ticket.public().pretty());
+            self.tickets.remove(&ticket.query);
+            self.tickets.save();
+            self.report_expired(ticket, Some(node_id));
+            Response::empty(ResponseCode::Expired)
+                .write(&mut send)
+                .await?;
+        } else if let Some(ticket) = ticket
+            .as_ref()
+            .filter(|ticket| !ticket.check_password(password.as_deref()))
+        {
+            tracing::warn!("Rejected password for query: {}", ticket.query);
+            self.events.emit(
+                EventKind::Error,
+                Some(node_id),
+                format!("wrong password for {}", query),
+            );
+            Response::empty(ResponseCode::Unauthorized)
+                .write(&mut send)
+                .await?;
+        } else if let Some(e) = missing {
+            warning!("Can't serve {}: {}", query.bold(), e);
+            self.events.emit(
+                EventKind::Error,
+                Some(node_id),
+                format!("content missing: {}", query),
+            );
+            Response::new(
+                ResponseCode::Error,
+                format!("the content of '{}' is no longer in the store", query),
+            )
+            .write(&mut send)
+            .await?;
+        } else if let Some(ticket) = ticket {
+            let ticket = ticket.public();
+            tracing::debug!("Found ticket: {}", ticket.pretty());
+            Response::new(ResponseCode::Ok, facet_msgpack::to_vec(&ticket))
+                .write(&mut send)
+                .await?;
+            info!(
+                "Node {} requested ticket: {}",
+                node_id.reduced(),
+                ticket.query.blue().bold()
+            );
+            self.events.emit(
+                EventKind::Serve,
+                Some(node_id),
+                format!(
+                    "{} ({})",
+                    ticket.query,
+                    ticket.filename.as_deref().unwrap_or("unnamed")
+                ),
+            );
+        } else {
+            tracing::warn!("Ticket not found for query: {}", query);
+            self.events.emit(
+                EventKind::Error,
+                Some(node_id),
+                format!("ticket not found: {}", query),
+            );
+            Response::empty(ResponseCode::NotFound)
+                .write(&mut send)
+                .await?;
+        }
+
+        send.finish()?;
+
+        send.stopped().await?;
+
+        Ok(())
+    }
+}
+
+/// Gives `path` the mode and modification time `ticket` carries, warning
+/// when they can't be set since the content itself arrived fine. The mode is
+/// only applied on Unix, without its setuid, setgid and sticky bits.
+fn restore_meta(ticket: &Ticket, path: &Path) {
+    let restore = || -> std::io::Result<()> {
+        // Before the mode, which may leave the file read-only
+        if let Some(modified) = ticket.modified_at() {
+            std::fs::File::options()
+                .write(true)
+                .open(path)?
+                .set_modified(modified)?;
+        }
+        #[cfg(unix)]
+        if let Some(mode) = ticket.mode {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777))?;
+        }
+        Ok(())
+    };
+    if let Err(e) = restore() {
+        warning!(
+            "Failed to restore the metadata of {}: {}",
+            path.display(),
+            e
+        );
+    }
+}
+
+/// Fails when one of `queries` can't be served next to `tickets`.
+fn check_queries(tickets: &DashMap<String, Ticket>, queries: &[String]) -> anyhow::Result<()> {
+    for query in queries {
+        if query.is_empty() {
+            bail!("Queries cannot be empty");
+        }
+        if tickets.contains_key(query) {
+            bail!("Query '{}' is already in use", query);
+        }
+    }
+    Ok(())
+}
+
+/// Finishes the ticket of freshly added content and adds it to `tickets`,
+/// under each requested query or one derived from its hash. Without queries,
+/// content already served the same way keeps its ticket instead.
+fn register(
+    tickets: &DashMap<String, Ticket>,
+    ticket: Ticket,
+    filename: Option<String>,
+    options: SendOptions,
+) -> anyhow::Result<Vec<Ticket>> {
+    let ticket = ticket
+        .with_filename(options.name.or(filename))
+        .with_expiry(options.expire.map(|expire| SystemTime::now() + expire))
+        .with_password(options.password.as_deref())?
+        .with_note(options.note);
+
+    if options.queries.is_empty() {
+        if let Some(shared) = find_shared(tickets, &ticket) {
+            info!(
+                "'{}' is already shared as {}",
+                shared.filename.as_deref().unwrap_or("<unnamed>"),
+                shared.query.blue().bold()
+            );
+            return Ok(vec![shared]);
+        }
+        return Ok(vec![insert_unique(tickets, ticket)]);
+    }
+
+    let mut registered = Vec::with_capacity(options.queries.len());
+    for query in options.queries {
+        match tickets.entry(query.clone()) {
+            Entry::Vacant(entry) => {
+                let ticket = ticket.clone().with_query(query);
+                entry.insert(ticket.clone());
+                registered.push(ticket);
+            }
+            // Another drop claimed it while this one was being added
+            Entry::Occupied(_) => {
+                for ticket in &registered {
+                    tickets.remove(&ticket.query);
+                }
+                bail!("Query '{}' is already in use", query);
+            }
+        }
+    }
+    Ok(registered)
+}
+
+/// The ticket serving the same content as `ticket` to the same catchers,
+/// with nothing a new drop could have changed. Protected or expiring drops
+/// always get their own ticket.
+fn find_shared(tickets: &DashMap<String, Ticket>, ticket: &Ticket) -> Option<Ticket> {
+    let unrestricted =
+        |ticket: &Ticket| ticket.password_hash.is_none() && ticket.expires_at.is_none();
+    if !unrestricted(ticket) {
+        return None;
+    }
+    tickets
+        .iter()
+        .map(|entry| entry.value().clone())
+        .filter(|shared| {
+            unrestricted(shared)
+                && shared.hash == ticket.hash
+                && shared.filename == ticket.filename
+                && shared.entries == ticket.entries
+                && shared.wrapped == ticket.wrapped
+                && shared.note == ticket.note
+                && shared.mode == ticket.mode
+                && shared.modified_at == ticket.modified_at
+        })
+        .min_by(|a, b| a.query.cmp(&b.query))
+}
+
+/// Inserts a ticket under its derived query, taking more of the hash while
+/// another drop holds the prefix, and numbering it when the same content
+/// is already served.
+fn insert_unique(tickets: &DashMap<String, Ticket>, ticket: Ticket) -> Ticket {
+    let base = ticket.query.clone();
+    let mut query = base.clone();
+    let mut suffix = 1;
+    loop {
+        match tickets.entry(query.clone()) {
+            Entry::Vacant(entry) => {
+                let ticket = ticket.with_query(query);
+                entry.insert(ticket.clone());
+                return ticket;
+            }
+            Entry::Occupied(entry) => {
+                let longer = ticket.hash.get(..query.len() + 1);
+                query = match longer {
+                    Some(longer) if entry.get().hash != ticket.hash && suffix == 1 => {
+                        longer.to_string()
+                    }
+                    _ => {
+                        suffix += 1;
+                        format!("{}-{}", base, suffix)
+                    }
+                };
+            }
+        }
+    }
+}
+
+/// Computes the tickets [`PoofProtocol::send`] would create for `source` next
+/// to `tickets`, only hashing the content instead of storing and serving it.
+pub async fn preview(
+    tickets: &DashMap<String, Ticket>,
+    source: DropSource,
+    options: SendOptions,
+) -> anyhow::Result<Vec<Ticket>> {
+    check_queries(tickets, &options.queries)?;
+
+    let (ticket, filename) = match source {
+        DropSource::Stdin => {
+            if options.wrap {
+                bail!("Only files can be wrapped, stdin has no name to keep");
+            }
+            let (hash, size) = hash_reader(tokio::io::stdin()).await?;
+            (Ticket::new(hash)?.with_size(size), None)
+        }
+        DropSource::Path(file_path) => {
+            let file_type = tokio::fs::metadata(&file_path).await?.file_type();
+            let filename = file_path
+                .file_name()
+                .and_then(|s| s.to_str())
+                .map(|s| s.to_string());
+            let ticket = if file_type.is_dir() {
+                let files = scan_directory(&file_path, options.follow_symlinks)?;
+                if files.is_empty() {
+                    bail!(
+                        "Directory '{}' contains no files to drop",
+                        file_path.display()
+                    );
+                }
+                let mut collection = Collection::default();
+                let mut total = 0;
+                for (name, path) in files {
+                    let (hash, size) = hash_reader(tokio::fs::File::open(path).await?).await?;
+                    collection.push(name, hash);
+                    total += size;
+                }
+                let entries = collection.iter().map(|(name, _)| name.clone()).collect();
+                Ticket::new(collection_hash(collection).await?)?
+                    .with_entries(entries)
+                    .with_size(total)
+            } else if file_type.is_file() {
+                let (hash, size) = hash_reader(tokio::fs::File::open(&file_path).await?).await?;
+                let ticket = if options.wrap {
+                    let Some(name) = options.name.clone().or(filename.clone()) else {
+                        bail!("'{}' has no name to wrap", file_path.display());
+                    };
+                    let collection = Collection::from_iter([(name, hash)]);
+                    Ticket::new(collection_hash(collection).await?)?.with_wrapped(true)
+                } else {
+                    Ticket::new(hash)?
+                };
+                ticket.with_size(size)
+            } else {
+                bail!("Cannot drop a {}", describe_file_type(&file_type));
+            };
+            (ticket, filename)
+        }
+    };
+    register(tickets, ticket, filename, options)
+}
+
+/// Hashes everything `reader` yields, matching the hash the blob store would
+/// give it, and counts the bytes read.
+async fn hash_reader(mut reader: impl AsyncRead + Unpin) -> anyhow::Result<(Hash, u64)> {
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0; VERIFY_BUFFER_SIZE];
+    let mut size = 0;
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+    Ok((Hash::from(hasher.finalize()), size))
+}
+
+/// The hash `collection` gets once stored, only its small metadata blobs are
+/// kept in a throwaway store to compute it.
+async fn collection_hash(collection: Collection) -> anyhow::Result<Hash> {
+    let store = iroh_blobs::store::mem::Store::new();
+    Ok(*collection.store(&store).await?.hash())
+}
+
+/// Reads a host's response, closing the connection on one too large to trust
+/// the host with further requests.
+async fn read_response(
+    connection: &Connection,
+    recv: &mut RecvStream,
+    max: usize,
+) -> anyhow::Result<Response> {
+    match Response::read(recv, max).await {
+        Err(e @ FrameError::TooLarge { .. }) => {
+            connection.close(0u32.into(), b"oversized response");
+            bail!("Node sent an oversized response: {}", e)
+        }
+        res => Ok(res?),
+    }
+}
+
+/// Fails when the served ticket isn't the one `options` expects.
+fn check_expected(ticket: &Ticket, options: &ReceiveOptions) -> anyhow::Result<()> {
+    if let Some(expected) = &options.expected
+        && expected.hash != ticket.hash
+    {
+        bail!(
+            "Ticket mismatch: expected hash {}, but the host served {}",
+            expected.hash,
+            ticket.hash
+        );
+    }
+
+    if let Some(name) = &options.expect_name
+        && ticket.filename.as_ref() != Some(name)
+    {
+        bail!(
+            "Expected file '{}', but the host served '{}'",
+            name,
+            ticket.filename.as_deref().unwrap_or("<unnamed>")
+        );
+    }
+    Ok(())
+}
+
+/// TLS `no_application_protocol` alert, sent when the peer doesn't accept our ALPN.
+const NO_APPLICATION_PROTOCOL: u8 = 120;
+
+fn is_alpn_mismatch(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<ConnectionError>(),
+        Some(ConnectionError::ConnectionClosed(close))
+            if close.error_code == TransportErrorCode::crypto(NO_APPLICATION_PROTOCOL)
+    )
+}
+
+/// Whether the host only speaks [`LEGACY_ALPN`], which carries no opcode.
+fn is_legacy(connection: &Connection) -> bool {
+    connection.alpn().as_deref() == Some(LEGACY_ALPN)
+}
+
+/// Runs `request`, giving up with [`PoofError::Timeout`] once `timeout` passes.
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    request: impl Future<Output = anyhow::Result<T>>,
+    action: impl FnOnce() -> String,
+) -> anyhow::Result<T> {
+    let Some(timeout) = timeout else {
+        return request.await;
+    };
+    tokio::time::timeout(timeout, request)
+        .await
+        .map_err(|_| PoofError::Timeout {
+            action: action(),
+            after: format_duration(timeout),
+        })?
+}
+
+/// Drives a download to completion while rendering its progress.
+async fn track_download(mut progress: DownloadProgress) -> anyhow::Result<Stats> {
+    let mut bar = TransferBar::new();
+    while let Some(event) = progress.next().await {
+        match event? {
+            BytesDownloadProgress::Found { id, hash, size, .. } => bar.found(id, hash, size),
+            BytesDownloadProgress::FoundLocal {
+                hash,
+                size,
+                valid_ranges,
+                ..
+            } => bar.found_local(
+                hash,
+                size.value(),
+                stored_bytes(&valid_ranges.to_chunk_ranges(), size.value()),
+            ),
+            BytesDownloadProgress::Progress { id, offset } => bar.progress(id, offset),
+            BytesDownloadProgress::Done { id } => bar.done(id),
+            BytesDownloadProgress::AllDone(stats) => {
+                bar.finish();
+                return Ok(stats);
+            }
+            BytesDownloadProgress::Abort(e) => return Err(e.into()),
+            _ => {}
+        }
+    }
+    bail!("Download ended before completing")
+}
+
+/// Counts the bytes of a `size` bytes blob covered by `ranges`.
+fn stored_bytes(ranges: &ChunkRanges, size: u64) -> u64 {
+    ranges
+        .boundaries()
+        .chunks(2)
+        .map(|bounds| {
+            let start = bounds[0].to_bytes().min(size);
+            let end = bounds.get(1).map_or(size, |end| end.to_bytes().min(size));
+            end - start
+        })
+        .sum()
+}
+
+/// Decides where to write when `path` may already exist, see [`Overwrite`].
+fn resolve_destination(path: PathBuf, overwrite: Overwrite) -> anyhow::Result<PathBuf> {
+    if !path.exists() {
+        return Ok(path);
+    }
+
+    match overwrite {
+        Overwrite::Force => Ok(path),
+        Overwrite::NoClobber => {
+            let stem = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let extension = path
+                .extension()
+                .map(|extension| format!(".{}", extension.to_string_lossy()))
+                .unwrap_or_default();
+            let free = (1..)
+                .map(|n| path.with_file_name(format!("{}-{}{}", stem, n, extension)))
+                .find(|candidate| !candidate.exists())
+                .expect("some numbered name is free");
+            info!("{} exists, writing to {}", path.display(), free.display());
+            Ok(free)
+        }
+        Overwrite::Ask if std::io::stdin().is_terminal() && std::io::stderr().is_terminal() => {
+            eprint!("{} exists, overwrite? [y/N] ", path.display());
+            let mut answer = String::new();
+            std::io::stdin().read_line(&mut answer)?;
+            if matches!(answer.trim(), "y" | "Y" | "yes") {
+                Ok(path)
+            } else {
+                bail!("Not overwriting {}", path.display())
+            }
+        }
+        Overwrite::Ask => bail!(
+            "{} already exists, pass --force to overwrite it or --no-clobber to keep both",
+            path.display()
+        ),
+    }
+}
+
+/// Warns when `ticket` was made further in the future than clocks plausibly
+/// drift, its expiry would then be off by as much.
+fn warn_clock_skew(node_id: NodeId, ticket: &Ticket) {
+    let Some(ahead) = ticket
+        .created_at()
+        .and_then(|created_at| created_at.duration_since(SystemTime::now()).ok())
+    else {
+        return;
+    };
+    if ahead > MAX_CLOCK_SKEW {
+        warning!(
+            "Node {} made '{}' {} ahead of this machine's clock, one of the clocks is likely wrong",
+            node_id.reduced(),
+            ticket.query,
+            format_duration(ahead)
+        );
+    }
+}
+
+/// Asks before downloading `size` bytes for `query` on an interactive
+/// terminal, refuses otherwise.
+fn confirm_download(query: &str, size: u64) -> anyhow::Result<()> {
+    if !(std::io::stdin().is_terminal() && std::io::stderr().is_terminal()) {
+        bail!(
+            "'{}' is {}, pass --yes to download it anyway",
+            query,
+            format_bytes(size)
+        );
+    }
+    eprint!(
+        "This will download {}, continue? [y/N] ",
+        format_bytes(size)
+    );
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    if matches!(answer.trim(), "y" | "Y" | "yes") {
+        Ok(())
+    } else {
+        bail!("Not downloading '{}'", query)
+    }
+}
+
+/// Lists the files below `root` as `(name, path)` pairs, where names are
+/// `/`-separated paths relative to `root`.
+fn scan_directory(root: &Path, follow_symlinks: bool) -> anyhow::Result<Vec<(String, PathBuf)>> {
+    let mut files = Vec::new();
+    let mut visited = HashSet::new();
+    let mut pending = vec![root.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        // Symlinked directories can loop back onto themselves
+        if !visited.insert(dir.canonicalize()?) {
+            warning!("Skipping {}, it was already visited", dir.display());
+            continue;
+        }
+
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let mut file_type = entry.file_type()?;
+            if file_type.is_symlink() {
+                if !follow_symlinks {
+                    warning!("Skipping symlink {}", path.display());
+                    continue;
+                }
+                match std::fs::metadata(&path) {
+                    Ok(metadata) => file_type = metadata.file_type(),
+                    Err(e) => {
+                        warning!("Skipping broken symlink {}: {}", path.display(), e);
+                        continue;
+                    }
+                }
+            }
+
+            if file_type.is_dir() {
+                pending.push(path);
+            } else if file_type.is_file() {
+                let name = relative_canonicalized_path_to_string(path.strip_prefix(root)?)?;
+                files.push((name, path));
+            } else {
+                warning!(
+                    "Skipping {} {}",
+                    describe_file_type(&file_type),
+                    path.display()
+                );
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// Whether `path` is spelled as a directory, ending in a separator.
+fn names_directory(path: &Path) -> bool {
+    path.as_os_str()
+        .to_string_lossy()
+        .ends_with(std::path::is_separator)
+}
+
+/// Fails when an output filled in from a template could land outside of the
+/// directory it names. Placeholder values are already single components, but
+/// the check covers the whole path.
+fn check_template_output(path: &Path) -> anyhow::Result<()> {
+    let escapes = path.components().any(|component| match component {
+        Component::ParentDir => true,
+        // A drive or root only belongs at the start of an absolute template
+        Component::Prefix(_) | Component::RootDir => !path.is_absolute(),
+        Component::CurDir | Component::Normal(_) => false,
+    });
+    if escapes {
+        bail!(
+            "Refusing to write to '{}', a template can't lead out of its directory",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Resolves a collection entry name below `root`, refusing names that would escape it.
+fn entry_path(root: &Path, name: &str) -> anyhow::Result<PathBuf> {
+    let mut path = root.to_path_buf();
+    for part in name.split('/') {
+        let mut components = Path::new(part).components();
+        let is_normal = matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        );
+        if !is_normal || part.contains('\\') {
+            bail!(
+                "Refusing to write entry '{}' outside of {}",
+                name,
+                root.display()
+            );
+        }
+        path.push(part);
+    }
+    Ok(path)
+}
+
+fn describe_file_type(file_type: &std::fs::FileType) -> &'static str {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if file_type.is_fifo() {
+            return "FIFO";
+        }
+        if file_type.is_socket() {
+            return "socket";
+        }
+        if file_type.is_block_device() {
+            return "block device";
+        }
+        if file_type.is_char_device() {
+            return "character device";
+        }
+    }
+    if file_type.is_symlink() {
+        return "symlink";
+    }
+    "special file"
+}
+
+impl ProtocolHandler for PoofProtocol {
+    fn accept(&self, connection: iroh::endpoint::Connection) -> BoxedFuture<anyhow::Result<()>> {
+        let this = self.clone();
+        Box::pin(async move {
+            let open_for = this
+                .breaker
+                .lock()
+                .expect("circuit breaker poisoned")
+                .open_for();
+            if let Some(open_for) = open_for {
+                tracing::debug!("Circuit breaker open, turning connection away");
+                // The catcher is the one left waiting, so its failure is no news
+                if let Err(e) = this.turn_away(&connection, open_for).await {
+                    tracing::debug!("Failed to turn connection away: {}", e);
+                }
+                connection.close(0u32.into(), b"backing off");
+                return Ok(());
+            }
+
+            let node_id = connection.remote_node_id().ok();
+            let guard = this.active_transfers.track();
+            let result = this.handle_connection(connection).await;
+            drop(guard);
+            if let Err(e) = &result {
+                this.events.emit(EventKind::Error, node_id, e.to_string());
+            }
+
+            let mut breaker = this.breaker.lock().expect("circuit breaker poisoned");
+            match result {
+                Ok(()) => {
+                    breaker.record_success();
+                    Ok(())
+                }
+                Err(e) if breaker.record_failure() => {
+                    tracing::error!(
+                        "{} consecutive connections failed, backing off for {}s (last error: {})",
+                        breaker.threshold,
+                        FAILURE_BACKOFF.as_secs(),
+                        e
+                    );
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use iroh::{Endpoint, NodeAddr, RelayMode, protocol::Router};
+    use iroh_blobs::net_protocol::Blobs;
+
+    use super::*;
+    use crate::core::tickets::TicketManager;
+
+    async fn endpoint() -> Endpoint {
+        Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind_addr_v4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+            .bind()
+            .await
+            .unwrap()
+    }
+
+    /// A node on loopback with no relay or discovery, serving poof like a drop
+    /// does but keeping blobs in memory.
+    async fn node() -> (Arc<PoofProtocol>, Router) {
+        let endpoint = endpoint().await;
+        let blobs = Blobs::memory().build(&endpoint);
+        serve(endpoint, blobs, TicketStore::default())
+    }
+
+    /// Like [`node`], but keeping blobs and tickets in `dir` like a drop on
+    /// the disk store.
+    async fn disk_node(dir: &Path) -> (Arc<PoofProtocol>, Router) {
+        let endpoint = endpoint().await;
+        let blobs = Blobs::persistent(dir.join("blobs"))
+            .await
+            .unwrap()
+            .build(&endpoint);
+        let tickets = TicketStore::saved_to(TicketManager::at(dir.join("tickets.toml")));
+        serve(endpoint, blobs, tickets)
+    }
+
+    fn serve<S: iroh_blobs::store::Store>(
+        endpoint: Endpoint,
+        blobs: Blobs<S>,
+        tickets: TicketStore,
+    ) -> (Arc<PoofProtocol>, Router) {
+        let proto = PoofProtocol::new(
+            blobs.client().clone(),
+            endpoint.clone(),
+            Events::default(),
+            ActiveTransfers::default(),
+            tickets,
+        );
+        let router = Router::builder(endpoint)
+            .accept(iroh_blobs::ALPN, blobs)
+            .accept(ALPN, proto.clone())
+            .accept(LEGACY_ALPN, proto.clone())
+            .spawn();
+        (proto, router)
+    }
+
+    /// Lets `catcher` reach `host` through its loopback address.
+    fn introduce(catcher: &PoofProtocol, host: &PoofProtocol) -> NodeId {
+        let node_id = host.endpoint.node_id();
+        let addr = host.endpoint.bound_sockets().0;
+        catcher
+            .endpoint
+            .add_node_addr(NodeAddr::new(node_id).with_direct_addresses([addr]))
+            .unwrap();
+        node_id
+    }
+
+    /// Sends `query` to `node_id` on a connection of its own, returning the raw answer.
+    async fn ask(catcher: &PoofProtocol, node_id: NodeId, query: &str) -> Response {
+        let connection = catcher.endpoint.connect(node_id, ALPN).await.unwrap();
+        let (mut send, mut recv) = connection.open_bi().await.unwrap();
+        let request = Request::Query {
+            query: query.to_string(),
+            password: None,
+        };
+        request.write(&mut send, false).await.unwrap();
+        send.finish().unwrap();
+        Response::read(&mut recv, MAX_TICKET_SIZE).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn drops_silent_catchers() {
+        let (host, host_router) = node().await;
+        let (catcher, catcher_router) = node().await;
+        host.set_idle_timeout(Duration::from_millis(200));
+        let node_id = introduce(&catcher, &host);
+
+        let connection = catcher.endpoint.connect(node_id, ALPN).await.unwrap();
+        // Never opening a stream, the host gives up on its own
+        let closed = tokio::time::timeout(Duration::from_secs(10), connection.closed())
+            .await
+            .expect("the host kept a silent catcher");
+        match closed {
+            ConnectionError::ApplicationClosed(close) => {
+                assert_eq!(&close.reason[..], b"idle timeout")
+            }
+            other => panic!("expected the host to close the connection, got {other:?}"),
+        }
+
+        catcher_router.shutdown().await.unwrap();
+        host_router.shutdown().await.unwrap();
+    }
+
+    /// Makes a FIFO at `path`, which nothing ever writes to.
+    #[cfg(unix)]
+    fn mkfifo(path: &Path) {
+        let status = std::process::Command::new("mkfifo")
+            .arg(path)
+            .status()
+            .unwrap();
+        assert!(status.success(), "mkfifo failed");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn refuses_to_drop_fifos() {
+        let dir = tempfile::tempdir().unwrap();
+        let fifo = dir.path().join("pipe");
+        mkfifo(&fifo);
+        let (host, router) = node().await;
+
+        // Reading the FIFO would block until a writer shows up, which never happens
+        let send = host.send(DropSource::Path(fifo.clone()), SendOptions::default());
+        let err = tokio::time::timeout(Duration::from_secs(10), send)
+            .await
+            .expect("the FIFO was read")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Cannot drop a FIFO");
+        assert!(host.tickets.is_empty());
+
+        let tickets = DashMap::new();
+        let preview = preview(&tickets, DropSource::Path(fifo), SendOptions::default());
+        let err = tokio::time::timeout(Duration::from_secs(10), preview)
+            .await
+            .expect("the FIFO was read")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Cannot drop a FIFO");
+
+        router.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn drops_directories_as_collections() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "a").unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("sub").join("b.txt"), "bb").unwrap();
+        #[cfg(unix)]
+        mkfifo(&dir.path().join("pipe"));
+        let (host, router) = node().await;
+
+        let tickets = host
+            .send(
+                DropSource::Path(dir.path().to_path_buf()),
+                SendOptions::default(),
+            )
+            .await
+            .unwrap();
+        let [ticket] = &tickets[..] else {
+            panic!("expected a single ticket, got {tickets:?}");
+        };
+        assert!(ticket.is_directory());
+        // The FIFO is skipped rather than read
+        assert_eq!(
+            ticket.entries.as_deref(),
+            Some(&["a.txt".to_string(), "sub/b.txt".to_string()][..])
+        );
+        assert_eq!(ticket.size, Some(3));
+
+        router.shutdown().await.unwrap();
+    }
+
+    #[test]
+    fn derived_queries_take_more_of_the_hash_on_collision() {
+        let tickets = DashMap::new();
+        let first = Ticket::new(Hash::new(b"first")).unwrap();
+        let first = insert_unique(&tickets, first);
+        assert_eq!(first.query, first.hash[..6]);
+
+        // Another hash under the same prefix gets a longer one
+        let other = Ticket::new(Hash::new(b"other"))
+            .unwrap()
+            .with_query(first.query.clone());
+        let other = insert_unique(&tickets, other);
+        assert_eq!(other.query, other.hash[..7]);
+
+        // The same content again gets numbered instead
+        let again = insert_unique(&tickets, first.clone());
+        assert_eq!(again.query, format!("{}-2", first.query));
+        assert_eq!(tickets.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn resolves_queries_and_filenames() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        std::fs::write(&path, "notes").unwrap();
+        let (host, host_router) = node().await;
+        let (catcher, catcher_router) = node().await;
+        let node_id = introduce(&catcher, &host);
+
+        let ticket = host
+            .send(DropSource::Path(path), SendOptions::default())
+            .await
+            .unwrap()
+            .remove(0);
+        for query in [ticket.query.as_str(), "notes.txt"] {
+            let response = ask(&catcher, node_id, query).await;
+            assert!(matches!(response.code, ResponseCode::Ok), "{query}");
+            let served: Ticket = facet_msgpack::from_slice(&response.payload).unwrap();
+            assert_eq!(served.hash, ticket.hash);
+        }
+
+        let response = ask(&catcher, node_id, "missing").await;
+        assert!(matches!(response.code, ResponseCode::NotFound));
+        // A prefix of a query is not a query
+        let response = ask(&catcher, node_id, &ticket.query[..3]).await;
+        assert!(matches!(response.code, ResponseCode::NotFound));
+
+        // Other content under the same name makes the name ambiguous
+        let other = Ticket::new(Hash::new(b"other notes"))
+            .unwrap()
+            .with_filename(Some("notes.txt".to_string()));
+        host.tickets.insert(other.query.clone(), other.clone());
+        let response = ask(&catcher, node_id, "notes.txt").await;
+        assert!(matches!(response.code, ResponseCode::Ambiguous));
+        let mut candidates = response
+            .text()
+            .lines()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        candidates.sort();
+        let mut expected = vec![ticket.query.clone(), other.query.clone()];
+        expected.sort();
+        assert_eq!(candidates, expected);
+
+        catcher_router.shutdown().await.unwrap();
+        host_router.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn name_mismatch_aborts_before_downloading() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        std::fs::write(&path, "notes").unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let (host, host_router) = node().await;
+        let (catcher, catcher_router) = node().await;
+        let node_id = introduce(&catcher, &host);
+
+        let ticket = host
+            .send(DropSource::Path(path), SendOptions::default())
+            .await
+            .unwrap()
+            .remove(0);
+        let options = ReceiveOptions {
+            output_dir: Some(out.path().to_path_buf()),
+            expect_name: Some("invoice.pdf".to_string()),
+            ..Default::default()
+        };
+        let err = catcher
+            .receive(node_id, ticket.query.clone(), options)
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("Expected file 'invoice.pdf'"),
+            "{err}"
+        );
+
+        let hash = ticket.hash().unwrap();
+        assert!(matches!(
+            catcher.blobs.status(hash).await.unwrap(),
+            BlobStatus::NotFound
+        ));
+        assert_eq!(std::fs::read_dir(out.path()).unwrap().count(), 0);
+
+        catcher_router.shutdown().await.unwrap();
+        host_router.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn removed_tickets_are_not_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        std::fs::write(&path, "notes").unwrap();
+        let (host, host_router) = node().await;
+        let (catcher, catcher_router) = node().await;
+        let node_id = introduce(&catcher, &host);
+        let mut events = host.events.subscribe();
+
+        let ticket = host
+            .send(DropSource::Path(path), SendOptions::default())
+            .await
+            .unwrap()
+            .remove(0);
+        let response = ask(&catcher, node_id, &ticket.query).await;
+        assert!(matches!(response.code, ResponseCode::Ok));
+
+        assert!(host.remove_ticket(&ticket.query));
+        assert!(!host.remove_ticket(&ticket.query));
+        assert!(host.list_tickets().is_empty());
+        for query in [ticket.query.as_str(), "notes.txt"] {
+            let response = ask(&catcher, node_id, query).await;
+            assert!(matches!(response.code, ResponseCode::NotFound), "{query}");
+        }
+        let removed = std::iter::from_fn(|| events.try_recv().ok())
+            .find(|event| matches!(event.kind, EventKind::Remove))
+            .unwrap();
+        assert_eq!(removed.detail, format!("ticket removed: {}", ticket.query));
+
+        catcher_router.shutdown().await.unwrap();
+        host_router.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn expired_tickets_are_removed() {
+        let (host, router) = node().await;
+        let mut events = host.events.subscribe();
+        let live = Ticket::new(Hash::new(b"live")).unwrap();
+        let expired = Ticket::new(Hash::new(b"expired"))
+            .unwrap()
+            .with_expiry(Some(SystemTime::now() - Duration::from_secs(1)));
+        host.tickets.insert(live.query.clone(), live.clone());
+        host.tickets.insert(expired.query.clone(), expired.clone());
+
+        let pruned = host.prune_expired();
+        assert_eq!(pruned.len(), 1);
+        assert_eq!(pruned[0].query, expired.query);
+        assert_eq!(host.list_tickets().len(), 1);
+        let event = events.try_recv().unwrap();
+        assert!(matches!(event.kind, EventKind::Remove));
+        assert_eq!(event.detail, format!("ticket expired: {}", expired.query));
+
+        router.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn tickets_expiring_on_request_are_removed() {
+        let (host, host_router) = node().await;
+        let (catcher, catcher_router) = node().await;
+        let node_id = introduce(&catcher, &host);
+        let mut events = host.events.subscribe();
+        let expired = Ticket::new(Hash::new(b"expired"))
+            .unwrap()
+            .with_expiry(Some(SystemTime::now() - Duration::from_secs(1)));
+        host.tickets.insert(expired.query.clone(), expired.clone());
+
+        let response = ask(&catcher, node_id, &expired.query).await;
+        assert!(matches!(response.code, ResponseCode::Expired));
+        assert!(host.list_tickets().is_empty());
+        // Reported like pruning does, along with who asked
+        let event = std::iter::from_fn(|| events.try_recv().ok())
+            .find(|event| !matches!(event.kind, EventKind::Accept))
+            .unwrap();
+        assert!(matches!(event.kind, EventKind::Remove));
+        assert_eq!(event.node_id, Some(catcher.endpoint.node_id()));
+        assert_eq!(event.detail, format!("ticket expired: {}", expired.query));
+
+        catcher_router.shutdown().await.unwrap();
+        host_router.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn backing_off_answers_rate_limited() {
+        let (host, host_router) = node().await;
+        let (catcher, catcher_router) = node().await;
+        let node_id = introduce(&catcher, &host);
+        host.breaker.lock().unwrap().open_until = Some(Instant::now() + FAILURE_BACKOFF);
+
+        let response = ask(&catcher, node_id, "notes.txt").await;
+        assert!(matches!(response.code, ResponseCode::RateLimited));
+        assert!(
+            response.text().starts_with("backing off for "),
+            "{}",
+            response.text()
+        );
+
+        let err = catcher
+            .receive(node_id, "notes.txt".to_string(), ReceiveOptions::default())
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("is busy, try again later"),
+            "{err}"
+        );
+
+        catcher_router.shutdown().await.unwrap();
+        host_router.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn resumes_small_drops_from_the_disk_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        std::fs::write(&path, "notes").unwrap();
+
+        let (host, router) = disk_node(dir.path()).await;
+        let ticket = host
+            .send(DropSource::Path(path), SendOptions::default())
+            .await
+            .unwrap()
+            .remove(0);
+        router.shutdown().await.unwrap();
+        drop(host);
+
+        let (host, router) = disk_node(dir.path()).await;
+        let resumed = host.resume().await.unwrap();
+        assert_eq!(resumed.len(), 1);
+        assert_eq!(resumed[0].query, ticket.query);
+        assert_eq!(host.content_size(&resumed[0]).await.unwrap(), 5);
+        router.shutdown().await.unwrap();
+    }
+
+    #[test]
+    fn entry_path_stays_below_root() {
+        let root = Path::new("downloads");
+        assert_eq!(
+            entry_path(root, "notes.txt").unwrap(),
+            root.join("notes.txt")
+        );
+        assert_eq!(
+            entry_path(root, "docs/notes.txt").unwrap(),
+            root.join("docs").join("notes.txt")
+        );
+        for name in [
+            "/etc/passwd",
+            "../../.bashrc",
+            "docs/../../x",
+            "..",
+            ".",
+            "",
+            "docs//x",
+            "..\\x",
+        ] {
+            assert!(entry_path(root, name).is_err(), "accepted {:?}", name);
+        }
+    }
+
+    #[test]
+    fn template_outputs_stay_below_their_directory() {
+        let ticket = Ticket::new(Hash::new(b"template")).unwrap();
+        let expand = |template: &str, name: &str| {
+            let template = OutputTemplate::from_str(template).unwrap();
+            PathBuf::from(template.expand(&ticket, name))
+        };
+
+        for (template, name) in [
+            ("{filename}", "notes.txt"),
+            ("{filename}", "../../.bashrc"),
+            ("{filename}", "/etc/passwd"),
+            ("{date}/{query}-{filename}", ".."),
+            ("/tmp/poof/{filename}", "notes.txt"),
+        ] {
+            let path = expand(template, name);
+            assert!(check_template_output(&path).is_ok(), "rejected {:?}", path);
+            assert!(
+                !path.components().any(|c| c == Component::ParentDir),
+                "{:?} escapes",
+                path
+            );
+        }
+        for template in ["../{filename}", "{query}/../../{filename}"] {
+            let path = expand(template, "notes.txt");
+            assert!(check_template_output(&path).is_err(), "accepted {:?}", path);
+        }
     }
 }
@@ -0,0 +1,186 @@
+use super::config::ConfigManager;
+use crate::cli::{SettingKey, parse_expiry};
+use crate::utils::constants::{CONFIG_DIRECTORY, SETTINGS_FILE};
+use crate::utils::format::parse_bytes;
+use crate::{PoofError, Result};
+use facet::Facet;
+use iroh::RelayUrl;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Defaults from config.toml, explicit flags always win over them.
+///
+/// Durations and URLs are kept as written so the file stays readable, they
+/// are parsed when used.
+#[derive(Debug, Default, Clone, Facet)]
+pub struct Settings {
+    /// Key used when `--key` isn't given, instead of the default in keys.toml
+    pub default_key: Option<String>,
+    /// Directory catches are written to when no output is given
+    pub output_dir: Option<String>,
+    /// How long drops are served, e.g. `1h`
+    pub expire: Option<String>,
+    /// Relay used instead of the default ones
+    pub relay: Option<String>,
+    /// Connection retries before a catch gives up
+    pub retries: Option<usize>,
+    /// Delay before the first retry, e.g. `500ms`
+    pub retry_delay: Option<String>,
+    /// Size below which drops stay in memory, e.g. `16MiB`
+    pub store_threshold: Option<String>,
+}
+
+impl Settings {
+    pub fn get(&self, key: SettingKey) -> Option<String> {
+        match key {
+            SettingKey::DefaultKey => self.default_key.clone(),
+            SettingKey::OutputDir => self.output_dir.clone(),
+            SettingKey::Expire => self.expire.clone(),
+            SettingKey::Relay => self.relay.clone(),
+            SettingKey::Retries => self.retries.map(|retries| retries.to_string()),
+            SettingKey::RetryDelay => self.retry_delay.clone(),
+            SettingKey::StoreThreshold => self.store_threshold.clone(),
+        }
+    }
+
+    /// Sets `key`, rejecting values the flag it stands for wouldn't accept.
+    pub fn set(&mut self, key: SettingKey, value: &str) -> Result<()> {
+        let invalid = |e: String| crate::error!("Invalid value '{}' for {}: {}", value, key, e);
+        let value = value.to_string();
+        match key {
+            SettingKey::DefaultKey => self.default_key = Some(value),
+            SettingKey::OutputDir => self.output_dir = Some(value),
+            SettingKey::Expire => {
+                parse_expiry(&value).map_err(invalid)?;
+                self.expire = Some(value);
+            }
+            SettingKey::Relay => {
+                RelayUrl::from_str(&value).map_err(|e| invalid(e.to_string()))?;
+                self.relay = Some(value);
+            }
+            SettingKey::Retries => {
+                self.retries = Some(
+                    value
+                        .parse()
+                        .map_err(|e: std::num::ParseIntError| invalid(e.to_string()))?,
+                );
+            }
+            SettingKey::RetryDelay => {
+                humantime::parse_duration(&value).map_err(|e| invalid(e.to_string()))?;
+                self.retry_delay = Some(value);
+            }
+            SettingKey::StoreThreshold => {
+                parse_bytes(&value).map_err(invalid)?;
+                self.store_threshold = Some(value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Clears `key`, returning the value it had.
+    pub fn unset(&mut self, key: SettingKey) -> Option<String> {
+        let previous = self.get(key);
+        match key {
+            SettingKey::DefaultKey => self.default_key = None,
+            SettingKey::OutputDir => self.output_dir = None,
+            SettingKey::Expire => self.expire = None,
+            SettingKey::Relay => self.relay = None,
+            SettingKey::Retries => self.retries = None,
+            SettingKey::RetryDelay => self.retry_delay = None,
+            SettingKey::StoreThreshold => self.store_threshold = None,
+        }
+        previous
+    }
+
+    /// Checks that every stored value still parses.
+    pub fn validate(&self) -> Result<()> {
+        self.expire()?;
+        self.relay()?;
+        self.retry_delay()?;
+        self.store_threshold()?;
+        Ok(())
+    }
+
+    /// The output directory with a leading `~` expanded to the home directory.
+    pub fn output_dir(&self) -> Option<PathBuf> {
+        let dir = self.output_dir.as_deref()?;
+        match (dir.strip_prefix("~/"), dirs::home_dir()) {
+            (Some(rest), Some(home)) => Some(home.join(rest)),
+            _ if dir == "~" => dirs::home_dir(),
+            _ => Some(PathBuf::from(dir)),
+        }
+    }
+
+    pub fn expire(&self) -> Result<Option<Duration>> {
+        self.expire
+            .as_deref()
+            .map(|expire| parse_expiry(expire).map_err(|e| invalid_entry("expire", e.into())))
+            .transpose()
+    }
+
+    pub fn relay(&self) -> Result<Option<RelayUrl>> {
+        self.relay
+            .as_deref()
+            .map(|relay| RelayUrl::from_str(relay).map_err(|e| invalid_entry("relay", e.into())))
+            .transpose()
+    }
+
+    pub fn retry_delay(&self) -> Result<Option<Duration>> {
+        self.retry_delay
+            .as_deref()
+            .map(|delay| {
+                humantime::parse_duration(delay).map_err(|e| invalid_entry("retry_delay", e.into()))
+            })
+            .transpose()
+    }
+
+    pub fn store_threshold(&self) -> Result<Option<u64>> {
+        self.store_threshold
+            .as_deref()
+            .map(|size| parse_bytes(size).map_err(|e| invalid_entry("store_threshold", e.into())))
+            .transpose()
+    }
+}
+
+fn invalid_entry(field: &str, source: Box<dyn std::error::Error + Send + Sync>) -> PoofError {
+    PoofError::InvalidEntry {
+        message: format!("Setting '{}' has an invalid value", field),
+        file: "config",
+        source,
+    }
+}
+
+#[derive(Default)]
+pub struct SettingsManager;
+
+impl ConfigManager<Settings> for SettingsManager {
+    fn config_path(&self) -> PathBuf {
+        CONFIG_DIRECTORY.join(SETTINGS_FILE)
+    }
+}
+
+impl SettingsManager {
+    pub fn new() -> Self {
+        Self
+    }
+
+    pub fn get(&self, key: SettingKey) -> Result<Option<String>> {
+        Ok(self.load()?.get(key))
+    }
+
+    pub fn set(&self, key: SettingKey, value: &str) -> Result<()> {
+        let _lock = self.lock()?;
+        let mut settings = self.load()?;
+        settings.set(key, value)?;
+        self.save(&settings)
+    }
+
+    pub fn unset(&self, key: SettingKey) -> Result<Option<String>> {
+        let _lock = self.lock()?;
+        let mut settings = self.load()?;
+        let previous = settings.unset(key);
+        self.save(&settings)?;
+        Ok(previous)
+    }
+}
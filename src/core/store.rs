@@ -0,0 +1,313 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions, TryLockError},
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use futures_lite::StreamExt;
+use iroh_blobs::{
+    Hash, HashAndFormat,
+    hashseq::parse_hash_seq,
+    rpc::client::blobs::MemClient,
+    store::{MapEntry, Store},
+};
+
+use crate::Result;
+
+const LOCK_FILE: &str = "poof.lock";
+/// Tags [`prune`] never deletes, they go once whatever set them is done.
+const KEPT_PREFIX: &str = "kept-";
+const SERVED_PREFIX: &str = "served-";
+/// Length of the time closing tag names, `2025-01-01T00:00:00.000Z`.
+const TAG_TIME_LENGTH: usize = 24;
+/// How often unreferenced blobs are swept from the disk store.
+pub const GC_PERIOD: Duration = Duration::from_secs(60);
+
+/// Takes exclusive ownership of the store in `dir`, or returns `None`
+/// when another poof process already holds it.
+pub fn lock(dir: &Path) -> Result<Option<File>> {
+    fs::create_dir_all(dir)?;
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join(LOCK_FILE))?;
+    match file.try_lock() {
+        Ok(()) => Ok(Some(file)),
+        Err(TryLockError::WouldBlock) => Ok(None),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// Drops the tags left by previous sessions so their blobs get collected,
+/// except `kept-` tags and those of content in `saved`, which the tickets
+/// of the last drop point at.
+pub async fn prune(client: &MemClient, saved: &HashSet<Hash>) -> anyhow::Result<()> {
+    let stale = client
+        .tags()
+        .list()
+        .await?
+        .try_collect::<_, _, Vec<_>>()
+        .await?
+        .into_iter()
+        .filter(|tag| {
+            !tag.name.0.starts_with(KEPT_PREFIX.as_bytes()) && !saved.contains(&tag.hash)
+        });
+    for tag in stale {
+        client.tags().delete(tag.name).await?;
+    }
+    Ok(())
+}
+
+/// Tags `content` so the collector keeps it across sessions until it is
+/// [`release`]d, for downloads a later catch may resume.
+pub async fn keep(client: &MemClient, content: HashAndFormat) -> anyhow::Result<()> {
+    client
+        .tags()
+        .set(tag_name(KEPT_PREFIX, content.hash), content)
+        .await
+}
+
+/// Drops the tags [`keep`] set on `hash`.
+pub async fn release(client: &MemClient, hash: Hash) -> anyhow::Result<()> {
+    client
+        .tags()
+        .delete_prefix(format!("{}{}", KEPT_PREFIX, hash))
+        .await
+}
+
+/// Tags `content` so the collector keeps it while it is served, for blobs
+/// served without having been added by this session. Like the tags of added
+/// blobs, [`prune`] drops it once no saved ticket points at the content.
+pub async fn tag(client: &MemClient, content: HashAndFormat) -> anyhow::Result<()> {
+    client
+        .tags()
+        .set(tag_name(SERVED_PREFIX, content.hash), content)
+        .await
+}
+
+/// `<prefix><hash>-<time>`, closing with the time like the `auto-<time>`
+/// tags of added blobs so [`collect`] can tell their age.
+fn tag_name(prefix: &str, hash: Hash) -> String {
+    format!(
+        "{}{}-{}",
+        prefix,
+        hash,
+        humantime::format_rfc3339_millis(SystemTime::now())
+    )
+}
+
+/// When the tag named `name` was set, if its name says.
+fn tag_time(name: &[u8]) -> Option<SystemTime> {
+    let name = std::str::from_utf8(name).ok()?;
+    // Auto tags set within the same millisecond get a `-<n>` suffix
+    let name = match name.rsplit_once('-') {
+        Some((head, n)) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => head,
+        _ => name,
+    };
+    let time = name.get(name.len().checked_sub(TAG_TIME_LENGTH)?..)?;
+    humantime::parse_rfc3339(time).ok()
+}
+
+/// What [`collect`] removed from the store, or would have on a dry run.
+#[derive(Debug, Default)]
+pub struct Collected {
+    /// Hash and size of every blob no remaining tag reaches
+    pub blobs: Vec<(Hash, u64)>,
+    /// How many tags were dropped
+    pub tags: usize,
+}
+
+impl Collected {
+    pub fn bytes(&self) -> u64 {
+        self.blobs.iter().map(|(_, size)| size).sum()
+    }
+}
+
+/// Drops the tags set before `cutoff`, or all of them without one, then the
+/// blobs no remaining tag reaches. Content in `live` stays along with its
+/// tags, and so do tags whose name carries no time when there is a cutoff.
+/// A `dry_run` changes nothing and only reports what would go.
+pub async fn collect(
+    store: &impl Store,
+    live: &HashSet<HashAndFormat>,
+    cutoff: Option<SystemTime>,
+    dry_run: bool,
+) -> anyhow::Result<Collected> {
+    let live_hashes = live
+        .iter()
+        .map(|content| content.hash)
+        .collect::<HashSet<_>>();
+    let mut stale = Vec::new();
+    let mut roots = live.iter().copied().collect::<Vec<_>>();
+    for tag in store.tags(None, None).await? {
+        let (name, content) = tag?;
+        let old = cutoff.is_none_or(|cutoff| tag_time(&name.0).is_some_and(|time| time < cutoff));
+        if old && !live_hashes.contains(&content.hash) {
+            stale.push(name);
+        } else {
+            roots.push(content);
+        }
+    }
+
+    let reachable = reachable(store, roots).await?;
+    let mut collected = Collected {
+        tags: stale.len(),
+        ..Default::default()
+    };
+    for hash in store.blobs().await?.chain(store.partial_blobs().await?) {
+        let hash = hash?;
+        if reachable.contains(&hash) {
+            continue;
+        }
+        let size = match store.get(&hash).await? {
+            Some(entry) => entry.size().value(),
+            None => 0,
+        };
+        collected.blobs.push((hash, size));
+    }
+    if dry_run {
+        return Ok(collected);
+    }
+
+    for name in stale {
+        store.delete_tag(name).await?;
+    }
+    let hashes = collected.blobs.iter().map(|(hash, _)| *hash).collect();
+    store.delete(hashes).await?;
+    store.sync().await?;
+    Ok(collected)
+}
+
+/// Every blob `roots` reach, with the children of hash sequences. Partial
+/// sequences can't be read, so like for the collector their children don't count.
+async fn reachable(
+    store: &impl Store,
+    roots: impl IntoIterator<Item = HashAndFormat>,
+) -> anyhow::Result<HashSet<Hash>> {
+    let mut reachable = HashSet::new();
+    for HashAndFormat { hash, format } in roots {
+        if !reachable.insert(hash) || format.is_raw() {
+            continue;
+        }
+        let Some(entry) = store.get(&hash).await? else {
+            continue;
+        };
+        if !entry.is_complete() {
+            continue;
+        }
+        let (mut children, _) = parse_hash_seq(entry.data_reader().await?).await?;
+        while let Some(child) = children.next().await? {
+            reachable.insert(child);
+        }
+    }
+    Ok(reachable)
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_blobs::{
+        BlobFormat, Tag,
+        hashseq::HashSeq,
+        store::{Map, ReadableStore, mem},
+    };
+
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    async fn add(store: &mem::Store, content: &'static [u8]) -> HashAndFormat {
+        let tag = store
+            .import_bytes(content.into(), BlobFormat::Raw)
+            .await
+            .unwrap();
+        *tag.inner()
+    }
+
+    async fn set(store: &mem::Store, name: String, content: HashAndFormat) {
+        store.set_tag(Tag::from(name), content).await.unwrap();
+    }
+
+    fn auto(age: Duration) -> String {
+        let time = SystemTime::now() - age;
+        format!("auto-{}", humantime::format_rfc3339_millis(time))
+    }
+
+    #[test]
+    fn reads_the_time_off_tag_names() {
+        let hash = Hash::new(b"tagged");
+        let time = humantime::parse_rfc3339("2025-01-02T03:04:05.678Z").unwrap();
+        for name in [
+            "auto-2025-01-02T03:04:05.678Z".to_string(),
+            "auto-2025-01-02T03:04:05.678Z-2".to_string(),
+            format!("kept-{hash}-2025-01-02T03:04:05.678Z"),
+        ] {
+            assert_eq!(tag_time(name.as_bytes()), Some(time), "{name}");
+        }
+        assert!(tag_time(tag_name(SERVED_PREFIX, hash).as_bytes()).is_some());
+        // Tags set before they carried a time
+        assert_eq!(tag_time(format!("kept-{hash}").as_bytes()), None);
+        assert_eq!(tag_time(b"served-"), None);
+    }
+
+    #[tokio::test]
+    async fn collects_old_blobs_no_live_ticket_needs() {
+        let store = mem::Store::new();
+        let old = add(&store, b"old").await;
+        set(&store, auto(60 * DAY), old).await;
+        let recent = add(&store, b"recent").await;
+        set(&store, tag_name(KEPT_PREFIX, recent.hash), recent).await;
+        let live = add(&store, b"live").await;
+        set(&store, auto(61 * DAY), live).await;
+        let undated = add(&store, b"undated").await;
+        set(&store, format!("{KEPT_PREFIX}{}", undated.hash), undated).await;
+        let orphan = add(&store, b"orphan").await;
+        // A collection keeps its children
+        let child = add(&store, b"child").await;
+        let seq = HashSeq::from_iter([child.hash]);
+        let seq = store
+            .import_bytes(seq.into(), BlobFormat::HashSeq)
+            .await
+            .unwrap();
+        set(&store, auto(DAY), *seq.inner()).await;
+        drop(seq);
+
+        let cutoff = Some(SystemTime::now() - 30 * DAY);
+        let lives = HashSet::from([live]);
+        let mut expected = vec![old.hash, orphan.hash];
+        expected.sort();
+        let removed = |collected: &Collected| {
+            let mut hashes = collected
+                .blobs
+                .iter()
+                .map(|(hash, _)| *hash)
+                .collect::<Vec<_>>();
+            hashes.sort();
+            hashes
+        };
+
+        let collected = collect(&store, &lives, cutoff, true).await.unwrap();
+        assert_eq!(removed(&collected), expected);
+        assert_eq!(collected.tags, 1);
+        assert_eq!(collected.bytes(), 9);
+        assert!(store.get(&old.hash).await.unwrap().is_some());
+        assert_eq!(store.tags(None, None).await.unwrap().count(), 5);
+
+        let collected = collect(&store, &lives, cutoff, false).await.unwrap();
+        assert_eq!(removed(&collected), expected);
+        for gone in [old, orphan] {
+            assert!(store.get(&gone.hash).await.unwrap().is_none());
+        }
+        for kept in [recent, live, undated, child] {
+            assert!(store.get(&kept.hash).await.unwrap().is_some());
+        }
+        assert_eq!(store.tags(None, None).await.unwrap().count(), 4);
+
+        // Without a cutoff only what live tickets point at stays
+        collect(&store, &lives, None, false).await.unwrap();
+        assert!(store.get(&live.hash).await.unwrap().is_some());
+        assert_eq!(store.blobs().await.unwrap().count(), 1);
+        assert_eq!(store.tags(None, None).await.unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,132 @@
+use std::{str::FromStr, time::SystemTime};
+
+use crate::core::ticket::Ticket;
+
+/// Where `catch --output-template` writes each ticket, e.g.
+/// `{date}/{query}-{filename}`. Braces are doubled to be written literally.
+#[derive(Debug, Clone)]
+pub struct OutputTemplate(Vec<Part>);
+
+#[derive(Debug, Clone)]
+enum Part {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Placeholder {
+    /// The query the host serves the ticket under
+    Query,
+    /// The announced filename, or the start of the hash without one
+    Filename,
+    Hash,
+    /// Today's date in UTC, `YYYY-MM-DD`
+    Date,
+}
+
+impl Placeholder {
+    const ALL: [(&str, Placeholder); 4] = [
+        ("query", Placeholder::Query),
+        ("filename", Placeholder::Filename),
+        ("hash", Placeholder::Hash),
+        ("date", Placeholder::Date),
+    ];
+}
+
+impl OutputTemplate {
+    /// Fills in the placeholders for `ticket`, whose file is called `name`.
+    ///
+    /// Values come from the host, so separators in them are replaced and can't
+    /// move the output out of the directory the template picks.
+    pub fn expand(&self, ticket: &Ticket, name: &str) -> String {
+        let mut out = String::new();
+        for part in &self.0 {
+            match part {
+                Part::Literal(literal) => out.push_str(literal),
+                Part::Placeholder(placeholder) => {
+                    let value = match placeholder {
+                        Placeholder::Query => ticket.query.clone(),
+                        Placeholder::Filename => name.to_string(),
+                        Placeholder::Hash => ticket.hash.clone(),
+                        Placeholder::Date => {
+                            let now = humantime::format_rfc3339_seconds(SystemTime::now());
+                            now.to_string()[..10].to_string()
+                        }
+                    };
+                    out.push_str(&sanitize(&value));
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Keeps a placeholder value to a single, regular path component.
+fn sanitize(value: &str) -> String {
+    let value = value.replace(['/', '\\'], "_");
+    match value.as_str() {
+        "" | "." | ".." => "_".to_string(),
+        _ => value,
+    }
+}
+
+impl FromStr for OutputTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("unclosed '{{{}' in the template", name)),
+                        }
+                    }
+                    let Some((_, placeholder)) =
+                        Placeholder::ALL.iter().find(|(known, _)| *known == name)
+                    else {
+                        return Err(format!(
+                            "unknown placeholder '{{{}}}', expected one of {}",
+                            name,
+                            Placeholder::ALL
+                                .iter()
+                                .map(|(known, _)| format!("{{{known}}}"))
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        ));
+                    };
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Placeholder(*placeholder));
+                }
+                '}' => return Err("unmatched '}', write '}}' for a literal one".to_string()),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        if !parts
+            .iter()
+            .any(|part| matches!(part, Part::Placeholder(_)))
+        {
+            return Err("the template has no placeholder, use --output instead".to_string());
+        }
+        Ok(Self(parts))
+    }
+}
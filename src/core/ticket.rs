@@ -1,29 +1,89 @@
-use std::str::FromStr;
+use std::{
+    path::Path,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
 
+use argon2::{
+    Argon2,
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+};
 use facet::Facet;
+use iroh::NodeId;
 use iroh_blobs::Hash;
+use rand::rngs::OsRng;
+
+use crate::Result;
+
+/// Version byte prefixed to encoded tickets so the format can evolve.
+pub const TICKET_VERSION: u8 = 0;
+/// Longest note a drop may carry, in bytes.
+pub const MAX_NOTE_LENGTH: usize = 512;
 
 #[derive(Debug, Facet, Clone)]
 pub struct Ticket {
     pub hash: String,
     pub query: String,
     pub filename: Option<String>,
+    /// Entry names when the ticket points at a directory collection
+    #[facet(default)]
+    pub entries: Option<Vec<String>>,
+    /// Unix timestamp after which the ticket is no longer served
+    #[facet(default)]
+    pub expires_at: Option<u64>,
+    /// Argon2 hash of the passphrase catchers must present, never sent to them
+    #[facet(default)]
+    pub password_hash: Option<String>,
+    /// Whether a single file is wrapped in a collection that carries its name
+    #[facet(default)]
+    pub wrapped: bool,
+    /// Short message for catchers, shown before the download
+    #[facet(default)]
+    pub note: Option<String>,
+    /// Total size of the content in bytes, unknown for tickets from older hosts
+    #[facet(default)]
+    pub size: Option<u64>,
+    /// Unix timestamp of when the ticket was made, by the host's clock
+    #[facet(default)]
+    pub created_at: Option<u64>,
+    /// Unix permission bits of the dropped file, sent with `--preserve-meta`
+    #[facet(default)]
+    pub mode: Option<u32>,
+    /// Unix timestamp of the dropped file's last modification, sent with `--preserve-meta`
+    #[facet(default)]
+    pub modified_at: Option<u64>,
 }
 
 impl Ticket {
     /// Creates a new ticket.
-    pub fn new(hash: Hash) -> Self {
+    pub fn new(hash: Hash) -> Result<Self> {
         let hash = hash.to_string();
-        Self {
-            query: Self::generate_query(&hash),
+        Ok(Self {
+            query: Self::generate_query(&hash)?,
             hash,
             filename: None,
-        }
+            entries: None,
+            expires_at: None,
+            password_hash: None,
+            wrapped: false,
+            note: None,
+            size: None,
+            mode: None,
+            modified_at: None,
+            created_at: Some(
+                SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+            ),
+        })
     }
 
     /// Generates a query string (6-char)
-    fn generate_query(hash: &str) -> String {
-        String::from(&hash[..6])
+    fn generate_query(hash: &str) -> Result<String> {
+        hash.get(..6)
+            .map(String::from)
+            .ok_or_else(|| crate::error!("Hash '{}' is too short to derive a query", hash))
     }
 
     pub fn with_filename(mut self, filename: Option<String>) -> Self {
@@ -31,8 +91,223 @@ impl Ticket {
         self
     }
 
-    pub fn hash(&self) -> Hash {
-        Hash::from_str(&self.hash).expect("Invalid hash format")
+    pub fn with_query(mut self, query: String) -> Self {
+        self.query = query;
+        self
+    }
+
+    pub fn with_entries(mut self, entries: Vec<String>) -> Self {
+        self.entries = Some(entries);
+        self
+    }
+
+    pub fn with_wrapped(mut self, wrapped: bool) -> Self {
+        self.wrapped = wrapped;
+        self
+    }
+
+    pub fn with_size(mut self, size: u64) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    /// Keeps the mode and modification time of the file `metadata` is about,
+    /// the mode only being known on Unix.
+    pub fn with_meta(mut self, metadata: &std::fs::Metadata) -> Self {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            self.mode = Some(metadata.permissions().mode() & 0o7777);
+        }
+        self.modified_at = metadata
+            .modified()
+            .ok()
+            .and_then(|at| at.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|at| at.as_secs());
+        self
+    }
+
+    pub fn with_note(mut self, note: Option<String>) -> Self {
+        self.note = note;
+        self
+    }
+
+    /// Checks that `note` is short and free of control characters other than
+    /// newlines and tabs, so a host can't mess with the catcher's terminal.
+    pub fn check_note(note: &str) -> std::result::Result<(), String> {
+        if note.len() > MAX_NOTE_LENGTH {
+            return Err(format!(
+                "note is {} bytes long, at most {} are allowed",
+                note.len(),
+                MAX_NOTE_LENGTH
+            ));
+        }
+        if let Some(c) = note
+            .chars()
+            .find(|c| c.is_control() && !matches!(c, '\n' | '\t'))
+        {
+            return Err(format!("note contains the control character {:?}", c));
+        }
+        Ok(())
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.entries.is_some()
+    }
+
+    /// Whether the hash points at a collection rather than the content itself
+    pub fn is_collection(&self) -> bool {
+        self.is_directory() || self.wrapped
+    }
+
+    pub fn with_expiry(mut self, expires_at: Option<SystemTime>) -> Self {
+        self.expires_at = expires_at.map(|at| {
+            at.duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        });
+        self
+    }
+
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.expires_at
+            .map(|ts| SystemTime::UNIX_EPOCH + Duration::from_secs(ts))
+    }
+
+    pub fn modified_at(&self) -> Option<SystemTime> {
+        self.modified_at
+            .map(|ts| SystemTime::UNIX_EPOCH + Duration::from_secs(ts))
+    }
+
+    pub fn created_at(&self) -> Option<SystemTime> {
+        self.created_at
+            .map(|ts| SystemTime::UNIX_EPOCH + Duration::from_secs(ts))
+    }
+
+    /// Protects the ticket with `password`, storing only its hash.
+    pub fn with_password(mut self, password: Option<&str>) -> Result<Self> {
+        self.password_hash = password
+            .map(|password| {
+                let salt = SaltString::generate(&mut OsRng);
+                Argon2::default()
+                    .hash_password(password.as_bytes(), &salt)
+                    .map(|hash| hash.to_string())
+                    .map_err(|e| crate::error!("Failed to hash password: {}", e))
+            })
+            .transpose()?;
+        Ok(self)
+    }
+
+    /// Whether `password` unlocks the ticket, always true for unprotected tickets.
+    pub fn check_password(&self, password: Option<&str>) -> bool {
+        let Some(hash) = &self.password_hash else {
+            return true;
+        };
+        let (Some(password), Ok(hash)) = (password, PasswordHash::new(hash)) else {
+            return false;
+        };
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    }
+
+    /// The ticket as sent to catchers, without its password hash.
+    pub fn public(&self) -> Self {
+        Self {
+            password_hash: None,
+            ..self.clone()
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at().is_some_and(|at| SystemTime::now() >= at)
+    }
+
+    pub fn hash(&self) -> Result<Hash> {
+        Hash::from_str(&self.hash).map_err(|e| {
+            crate::error!(
+                source = e,
+                "Ticket for query '{}' has an invalid hash",
+                self.query
+            )
+        })
+    }
+
+    /// Encodes the ticket as lowercase base32 over a version byte followed by its msgpack form.
+    pub fn to_base32(&self) -> String {
+        let mut bytes = vec![TICKET_VERSION];
+        bytes.extend(facet_msgpack::to_vec(self));
+        data_encoding::BASE32_NOPAD
+            .encode(&bytes)
+            .to_ascii_lowercase()
+    }
+
+    /// Decodes a ticket produced by [`Ticket::to_base32`].
+    pub fn from_base32(s: &str) -> Result<Self> {
+        let bytes = data_encoding::BASE32_NOPAD
+            .decode(s.to_ascii_uppercase().as_bytes())
+            .map_err(|e| crate::error!(source = e, "Invalid ticket encoding"))?;
+        let (version, payload) = bytes
+            .split_first()
+            .ok_or_else(|| crate::error!("Empty ticket"))?;
+        if *version != TICKET_VERSION {
+            return Err(crate::error!("Unsupported ticket version {}", version));
+        }
+        facet_msgpack::from_slice(payload)
+            .map_err(|e| crate::error!("Failed to deserialize ticket: {}", e))
+    }
+}
+
+/// Everything a catcher needs to fetch a drop, written by `drop --ticket-out`
+/// and read back by `catch --ticket-in`.
+#[derive(Debug, Facet, Clone)]
+pub struct TicketFile {
+    pub node_id: String,
+    pub query: String,
+    pub hash: String,
+    pub size: u64,
+    pub filename: Option<String>,
+}
+
+impl TicketFile {
+    pub fn new(node_id: NodeId, ticket: &Ticket, size: u64) -> Self {
+        Self {
+            node_id: node_id.to_string(),
+            query: ticket.query.clone(),
+            hash: ticket.hash.clone(),
+            size,
+            filename: ticket.filename.clone(),
+        }
+    }
+
+    /// Reads and validates a ticket file.
+    pub fn read(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let file: Self = facet_json::from_str(&content)
+            .map_err(|e| crate::error!("Invalid ticket file '{}': {}", path.display(), e))?;
+        file.node_id()?;
+        file.hash()?;
+        if file.query.is_empty() {
+            return Err(crate::error!(
+                "Invalid ticket file '{}': empty query",
+                path.display()
+            ));
+        }
+        Ok(file)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, facet_json::to_string(self))?;
+        Ok(())
+    }
+
+    pub fn node_id(&self) -> Result<NodeId> {
+        NodeId::from_str(&self.node_id)
+            .map_err(|e| crate::error!(source = e, "Invalid node id in ticket file"))
+    }
+
+    pub fn hash(&self) -> Result<Hash> {
+        Hash::from_str(&self.hash).map_err(|e| crate::error!("Invalid hash in ticket file: {}", e))
     }
 }
 
@@ -42,6 +317,11 @@ pub enum ResponseCode {
     Ok = 0,
     NotFound = 1,
     Error = 2,
+    Expired = 3,
+    Unauthorized = 4,
+    Ambiguous = 5,
+    /// The host turns requests away for now, the payload may say why
+    RateLimited = 6,
 }
 
 impl ResponseCode {
@@ -50,6 +330,37 @@ impl ResponseCode {
             0 => Some(ResponseCode::Ok),
             1 => Some(ResponseCode::NotFound),
             2 => Some(ResponseCode::Error),
+            3 => Some(ResponseCode::Expired),
+            4 => Some(ResponseCode::Unauthorized),
+            5 => Some(ResponseCode::Ambiguous),
+            6 => Some(ResponseCode::RateLimited),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        *self as u8
+    }
+}
+
+/// What a catcher asks for, sent first on `poof/1` streams.
+#[derive(Debug, Facet, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Opcode {
+    /// A ticket by query, followed by the query and optional password frames
+    Query = 0,
+    /// Every ticket the host serves
+    List = 1,
+    /// An empty answer, to measure the round trip
+    Ping = 2,
+}
+
+impl Opcode {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Opcode::Query),
+            1 => Some(Opcode::List),
+            2 => Some(Opcode::Ping),
             _ => None,
         }
     }
@@ -58,3 +369,129 @@ impl ResponseCode {
         *self as u8
     }
 }
+
+/// A served ticket as shown to catchers listing a host.
+#[derive(Debug, Facet, Clone)]
+pub struct Listing {
+    pub query: String,
+    pub filename: Option<String>,
+    pub size: u64,
+    /// Whether catching it needs a password
+    pub protected: bool,
+}
+
+/// Reply to an [`Opcode::List`] request.
+#[derive(Debug, Facet, Clone)]
+pub struct Listings {
+    pub tickets: Vec<Listing>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ticket as sent by hosts speaking poof/0.
+    #[derive(Debug, Facet)]
+    struct LegacyTicket {
+        hash: String,
+        query: String,
+        filename: Option<String>,
+    }
+
+    #[test]
+    fn decodes_legacy_tickets() {
+        let hash = Hash::new(b"legacy").to_string();
+        let legacy = LegacyTicket {
+            query: hash[..6].to_string(),
+            hash: hash.clone(),
+            filename: Some("notes.txt".to_string()),
+        };
+        let ticket: Ticket = facet_msgpack::from_slice(&facet_msgpack::to_vec(&legacy)).unwrap();
+        assert_eq!(ticket.hash, hash);
+        assert_eq!(ticket.query, legacy.query);
+        assert_eq!(ticket.filename, legacy.filename);
+        assert!(ticket.entries.is_none());
+        assert!(ticket.expires_at.is_none());
+        assert!(ticket.password_hash.is_none());
+        assert!(!ticket.wrapped);
+        assert!(ticket.size.is_none());
+    }
+
+    #[test]
+    fn queries_need_a_long_enough_hash() {
+        let hash = Hash::new(b"query").to_string();
+        assert_eq!(Ticket::generate_query(&hash).unwrap(), hash[..6]);
+        assert!(Ticket::generate_query("abc").is_err());
+        assert!(Ticket::generate_query("").is_err());
+
+        let ticket = Ticket::new(Hash::new(b"query")).unwrap();
+        for short in ["", "abc"] {
+            let ticket = Ticket {
+                hash: short.to_string(),
+                ..ticket.clone()
+            };
+            assert!(ticket.hash().is_err());
+        }
+    }
+
+    #[test]
+    fn response_codes_round_trip() {
+        for value in 0..=6 {
+            let code = ResponseCode::from_u8(value).unwrap();
+            assert_eq!(code.to_u8(), value);
+        }
+        // Values the old codes had must not move
+        assert!(matches!(ResponseCode::from_u8(0), Some(ResponseCode::Ok)));
+        assert!(matches!(
+            ResponseCode::from_u8(1),
+            Some(ResponseCode::NotFound)
+        ));
+        assert!(matches!(
+            ResponseCode::from_u8(2),
+            Some(ResponseCode::Error)
+        ));
+        assert!((7..=u8::MAX).all(|value| ResponseCode::from_u8(value).is_none()));
+    }
+
+    #[test]
+    fn base32_round_trips() {
+        let hash = Hash::new(b"round trip");
+        for filename in [None, Some("notes.txt".to_string())] {
+            let ticket = Ticket::new(hash)
+                .unwrap()
+                .with_filename(filename.clone())
+                .with_size(42)
+                .with_note(Some("hi".to_string()));
+            let encoded = ticket.to_base32();
+            assert_eq!(encoded, encoded.to_ascii_lowercase());
+
+            let decoded = Ticket::from_base32(&encoded).unwrap();
+            assert_eq!(decoded.hash, ticket.hash);
+            assert_eq!(decoded.query, ticket.query);
+            assert_eq!(decoded.filename, filename);
+            assert_eq!(decoded.size, Some(42));
+            assert_eq!(decoded.note.as_deref(), Some("hi"));
+            assert_eq!(decoded.created_at, ticket.created_at);
+            // Case doesn't matter, URIs may be upper-cased on the way
+            assert!(Ticket::from_base32(&encoded.to_ascii_uppercase()).is_ok());
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_ticket_versions() {
+        let ticket = Ticket::new(Hash::new(b"version")).unwrap();
+        let mut bytes = data_encoding::BASE32_NOPAD
+            .decode(ticket.to_base32().to_ascii_uppercase().as_bytes())
+            .unwrap();
+        bytes[0] = TICKET_VERSION + 1;
+        let encoded = data_encoding::BASE32_NOPAD.encode(&bytes);
+        let err = Ticket::from_base32(&encoded).unwrap_err();
+        assert!(
+            err.to_string().contains("Unsupported ticket version"),
+            "{err}"
+        );
+
+        assert!(Ticket::from_base32("").is_err());
+        assert!(Ticket::from_base32("not base32!").is_err());
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    collections::HashMap,
+    ops::Deref,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use dashmap::DashMap;
+use facet::Facet;
+
+use super::{config::ConfigManager, ticket::Ticket};
+use crate::{
+    Result,
+    utils::constants::{CONFIG_DIRECTORY, TICKETS_FILE},
+};
+
+/// The tickets a drop served, keyed by query, so `drop --resume` can serve
+/// them again. Passphrases are only kept as their Argon2 hash.
+#[derive(Debug, Clone, Facet, Default)]
+pub struct TicketConfig {
+    /// Encoded with [`Ticket::to_base32`], facet-toml writes single entry
+    /// lists as plain strings it can't read back
+    #[facet(default)]
+    pub tickets: HashMap<String, String>,
+}
+
+#[derive(Debug)]
+pub struct TicketManager {
+    path: PathBuf,
+}
+
+impl Default for TicketManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConfigManager<TicketConfig> for TicketManager {
+    fn config_path(&self) -> PathBuf {
+        self.path.clone()
+    }
+}
+
+impl TicketManager {
+    pub fn new() -> Self {
+        Self::at(CONFIG_DIRECTORY.join(TICKETS_FILE))
+    }
+
+    /// Manages the tickets saved at `path` instead of the config directory.
+    pub fn at(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+/// The tickets served by this node, saved to tickets.toml on every change
+/// when persistent.
+#[derive(Debug, Clone, Default)]
+pub struct TicketStore {
+    tickets: Arc<DashMap<String, Ticket>>,
+    /// Also serializes saves, so concurrent changes never interleave writes
+    manager: Option<Arc<Mutex<TicketManager>>>,
+}
+
+impl TicketStore {
+    /// A store saved to tickets.toml, only for the process owning the blob
+    /// store since the saved tickets point into it.
+    pub fn persistent() -> Self {
+        Self::saved_to(TicketManager::new())
+    }
+
+    /// A store saved by `manager`, wherever it keeps its file.
+    pub fn saved_to(manager: TicketManager) -> Self {
+        Self {
+            tickets: Default::default(),
+            manager: Some(Arc::new(Mutex::new(manager))),
+        }
+    }
+
+    pub fn is_persistent(&self) -> bool {
+        self.manager.is_some()
+    }
+
+    /// The tickets saved by the last session, none when not persistent.
+    pub fn saved(&self) -> Result<Vec<Ticket>> {
+        let Some(manager) = &self.manager else {
+            return Ok(Vec::new());
+        };
+        let config = manager.lock().expect("ticket manager poisoned").load()?;
+        let mut tickets = config
+            .tickets
+            .values()
+            .map(|ticket| Ticket::from_base32(ticket))
+            .collect::<Result<Vec<_>>>()?;
+        tickets.sort_by(|a, b| a.query.cmp(&b.query));
+        Ok(tickets)
+    }
+
+    /// Writes the current tickets out, only warning on failure since serving
+    /// goes on without them being saved.
+    pub fn save(&self) {
+        let Some(manager) = &self.manager else {
+            return;
+        };
+        let manager = manager.lock().expect("ticket manager poisoned");
+        let config = TicketConfig {
+            tickets: self
+                .tickets
+                .iter()
+                .map(|entry| (entry.key().clone(), entry.value().to_base32()))
+                .collect(),
+        };
+        if let Err(e) = manager.save(&config) {
+            tracing::warn!("Failed to save tickets: {}", e);
+        }
+    }
+}
+
+impl Deref for TicketStore {
+    type Target = DashMap<String, Ticket>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.tickets
+    }
+}
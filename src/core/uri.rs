@@ -0,0 +1,177 @@
+use std::{fmt, str::FromStr};
+
+use iroh::{NodeId, PublicKey};
+
+use crate::utils::error::PoofError;
+
+pub const SCHEME: &str = "poof";
+pub const HOST_SCHEME: &str = "poof-host";
+
+/// A node id and query bundled into one shareable argument,
+/// `poof://<node_id>/<query>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoofUri {
+    pub node_id: NodeId,
+    pub query: String,
+}
+
+impl PoofUri {
+    pub fn new(node_id: NodeId, query: impl Into<String>) -> Self {
+        Self {
+            node_id,
+            query: query.into(),
+        }
+    }
+
+    /// Whether `s` claims to be a URI, malformed or not.
+    pub fn is_uri(s: &str) -> bool {
+        s.split_once("://")
+            .is_some_and(|(scheme, _)| scheme.eq_ignore_ascii_case(SCHEME))
+    }
+}
+
+impl fmt::Display for PoofUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}://{}/{}", SCHEME, self.node_id, self.query)
+    }
+}
+
+impl FromStr for PoofUri {
+    type Err = PoofError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| PoofError::InvalidUri {
+            uri: s.to_string(),
+            reason: reason.to_string(),
+        };
+
+        let (scheme, rest) = s
+            .split_once("://")
+            .ok_or_else(|| invalid("missing scheme"))?;
+        if !scheme.eq_ignore_ascii_case(SCHEME) {
+            return Err(invalid("unknown scheme"));
+        }
+        let (node_id, query) = rest
+            .split_once('/')
+            .ok_or_else(|| invalid("missing query"))?;
+        let node_id = NodeId::from_str(node_id).map_err(|_| invalid("invalid node id"))?;
+        if query.is_empty() {
+            return Err(invalid("missing query"));
+        }
+
+        Ok(Self::new(node_id, query))
+    }
+}
+
+/// A public key to save as a host, with the alias and description it
+/// suggests, `poof-host://<public_key>[/<alias>][?desc=<description>]`.
+/// The description is percent-encoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostUri {
+    pub public_key: PublicKey,
+    pub alias: Option<String>,
+    pub description: Option<String>,
+}
+
+impl HostUri {
+    pub fn new(public_key: PublicKey, alias: Option<String>) -> Self {
+        Self {
+            public_key,
+            alias,
+            description: None,
+        }
+    }
+
+    pub fn with_description(mut self, description: Option<String>) -> Self {
+        self.description = description;
+        self
+    }
+}
+
+impl fmt::Display for HostUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}://{}", HOST_SCHEME, self.public_key)?;
+        if let Some(alias) = &self.alias {
+            write!(f, "/{}", alias)?;
+        }
+        if let Some(description) = &self.description {
+            write!(f, "?desc={}", percent_encode(description))?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for HostUri {
+    type Err = PoofError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| PoofError::InvalidHostUri {
+            uri: s.to_string(),
+            reason: reason.to_string(),
+        };
+
+        let (scheme, rest) = s
+            .split_once("://")
+            .ok_or_else(|| invalid("missing scheme"))?;
+        if !scheme.eq_ignore_ascii_case(HOST_SCHEME) {
+            return Err(invalid("unknown scheme"));
+        }
+        let (path, params) = match rest.split_once('?') {
+            Some((path, params)) => (path, Some(params)),
+            None => (rest, None),
+        };
+        let (public_key, alias) = match path.split_once('/') {
+            Some((public_key, alias)) => (public_key, Some(alias)),
+            None => (path, None),
+        };
+        let public_key =
+            PublicKey::from_str(public_key).map_err(|_| invalid("invalid public key"))?;
+
+        let mut description = None;
+        for param in params.into_iter().flat_map(|params| params.split('&')) {
+            match param.split_once('=') {
+                Some(("desc", value)) => {
+                    description =
+                        Some(percent_decode(value).ok_or_else(|| invalid("invalid description"))?)
+                }
+                _ => return Err(invalid(&format!("unknown parameter '{}'", param))),
+            }
+        }
+
+        Ok(Self::new(
+            public_key,
+            alias.filter(|alias| !alias.is_empty()).map(String::from),
+        )
+        .with_description(description.filter(|description| !description.is_empty())))
+    }
+}
+
+/// Escapes everything but unreserved characters as `%XX`.
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Undoes [`percent_encode`], also reading `+` as a space. `None` on a
+/// malformed escape or when the result isn't UTF-8.
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.bytes();
+    while let Some(b) = rest.next() {
+        match b {
+            b'%' => {
+                let hex = [rest.next()?, rest.next()?];
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b'+' => bytes.push(b' '),
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
@@ -1,7 +1,27 @@
 use clap::Parser;
-use poof::{cli::Opts, core::run};
+use poof::{
+    cli::Opts,
+    core::run,
+    utils::logging::{self, Level},
+};
 
 #[tokio::main]
 async fn main() -> miette::Result<()> {
-    Ok(run(Opts::parse()).await?)
+    let opts = Opts::parse();
+    let json = opts.json;
+    match run(opts).await {
+        Err(err) if json => {
+            let mut report = String::new();
+            miette::JSONReportHandler::new()
+                .render_report(&mut report, &err)
+                .map_err(|e| miette::miette!("Failed to render error: {e}"))?;
+            logging::emit(logging::json_line(
+                Level::Error,
+                &err.to_string(),
+                Some(&report),
+            ));
+            std::process::exit(1);
+        }
+        res => Ok(res?),
+    }
 }
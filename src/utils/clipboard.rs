@@ -0,0 +1,65 @@
+//! Copies text to the system clipboard through the platform's own tool, so
+//! no windowing libraries get linked into poof.
+
+#[cfg(feature = "clipboard")]
+use std::{
+    io::{IsTerminal, Write},
+    process::{Command, Stdio},
+};
+
+/// Clipboard tools tried in order, taking the text on stdin.
+#[cfg(all(feature = "clipboard", target_os = "macos"))]
+const TOOLS: &[(&str, &[&str])] = &[("pbcopy", &[])];
+#[cfg(all(feature = "clipboard", windows))]
+const TOOLS: &[(&str, &[&str])] = &[("clip", &[])];
+#[cfg(all(feature = "clipboard", not(any(target_os = "macos", windows))))]
+const TOOLS: &[(&str, &[&str])] = &[
+    ("wl-copy", &[]),
+    ("xclip", &["-selection", "clipboard"]),
+    ("xsel", &["--clipboard", "--input"]),
+];
+
+/// Puts `text` on the clipboard, returning whether it worked. Skipped without
+/// a terminal, or without a display on Linux and the BSDs.
+#[cfg(feature = "clipboard")]
+pub fn copy(text: &str) -> bool {
+    if !std::io::stderr().is_terminal() {
+        return false;
+    }
+    #[cfg(not(any(target_os = "macos", windows)))]
+    if std::env::var_os("WAYLAND_DISPLAY").is_none() && std::env::var_os("DISPLAY").is_none() {
+        tracing::debug!("No display to copy to");
+        return false;
+    }
+    TOOLS.iter().any(|(program, args)| {
+        let copied = Command::new(program)
+            .args(*args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .and_then(|mut child| {
+                // Closed before waiting, the tool reads until end of input
+                child
+                    .stdin
+                    .take()
+                    .expect("stdin is piped")
+                    .write_all(text.as_bytes())?;
+                child.wait()
+            });
+        match copied {
+            Ok(status) => status.success(),
+            Err(e) => {
+                tracing::debug!("Failed to copy with {}: {}", program, e);
+                false
+            }
+        }
+    })
+}
+
+/// Built without the `clipboard` feature, nothing is ever copied.
+#[cfg(not(feature = "clipboard"))]
+pub fn copy(_text: &str) -> bool {
+    tracing::debug!("Built without clipboard support");
+    false
+}
@@ -1,10 +1,28 @@
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
 use std::path::PathBuf;
 
+static CONFIG_DIRECTORY_OVERRIDE: OnceCell<PathBuf> = OnceCell::new();
+
+/// `~/.config/poof`, unless [`set_config_directory`] picked another one first.
 pub static CONFIG_DIRECTORY: Lazy<PathBuf> = Lazy::new(|| {
+    if let Some(dir) = CONFIG_DIRECTORY_OVERRIDE.get() {
+        return dir.clone();
+    }
     let home = dirs::home_dir().expect("Failed to get home directory");
     home.join(".config").join(env!("CARGO_PKG_NAME"))
 });
 
+/// Moves [`CONFIG_DIRECTORY`] to `dir`, returning false when it was already
+/// read or set since it can't change afterwards.
+pub fn set_config_directory(dir: PathBuf) -> bool {
+    Lazy::get(&CONFIG_DIRECTORY).is_none() && CONFIG_DIRECTORY_OVERRIDE.set(dir).is_ok()
+}
+
 pub const KEYS_FILE: &str = "keys.toml";
+pub const SETTINGS_FILE: &str = "config.toml";
 pub const DEFAULT_KEY_NAME: &str = "default";
+pub const HISTORY_FILE: &str = "history.toml";
+pub const TICKETS_FILE: &str = "tickets.toml";
+/// How many catches `poof history` remembers, unless `limit` is set in history.toml
+pub const HISTORY_LIMIT: usize = 100;
+pub const BLOBS_DIRECTORY: &str = "blobs";
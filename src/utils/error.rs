@@ -5,7 +5,7 @@ use thiserror::Error;
 pub enum PoofError {
     #[error(transparent)]
     #[diagnostic(code(punch::other))]
-    Other(#[from] anyhow::Error),
+    Other(anyhow::Error),
 
     #[error(transparent)]
     #[diagnostic(code(punch::io))]
@@ -19,6 +19,75 @@ pub enum PoofError {
     #[diagnostic(code(punch::toml))]
     Toml(#[from] facet_toml::TomlSerError),
 
+    #[error("Failed to connect to node {node_id}")]
+    #[diagnostic(
+        code(punch::connection),
+        help("Make sure the host is online and still dropping the file")
+    )]
+    Connection {
+        node_id: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[error("The remote node is not running a compatible poof version")]
+    #[diagnostic(
+        code(punch::protocol_mismatch),
+        help(
+            "Node {node_id} does not speak the {alpn} protocol, make sure both sides run the same poof version"
+        )
+    )]
+    IncompatibleProtocol { node_id: String, alpn: String },
+
+    #[error("{message}")]
+    #[diagnostic(
+        code(punch::config::invalid_entry),
+        help("Fix or remove it with `poof config edit {file}`")
+    )]
+    InvalidEntry {
+        message: String,
+        file: &'static str,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[error("Invalid URI {uri}: {reason}")]
+    #[diagnostic(code(punch::uri), help("URIs look like poof://<node id>/<query>"))]
+    InvalidUri { uri: String, reason: String },
+
+    #[error("Invalid host URI {uri}: {reason}")]
+    #[diagnostic(
+        code(punch::host_uri),
+        help("Host URIs look like poof-host://<public key>[/<alias>][?desc=<description>]")
+    )]
+    InvalidHostUri { uri: String, reason: String },
+
+    #[error("Failed to download blob {hash}")]
+    #[diagnostic(code(punch::download))]
+    Download {
+        hash: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[error("Received content does not match hash {expected}")]
+    #[diagnostic(
+        code(punch::integrity),
+        help("The transfer was corrupted or tampered with, got {actual} instead")
+    )]
+    IntegrityMismatch { expected: String, actual: String },
+
+    #[error("Failed to write file to {path}")]
+    #[diagnostic(code(punch::export))]
+    Export {
+        path: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    #[error("{action} timed out after {after}")]
+    #[diagnostic(
+        code(punch::timeout),
+        help("Check that the host is online, or wait longer with --timeout")
+    )]
+    Timeout { action: String, after: String },
+
     #[error("An error occurred: {message}")]
     #[diagnostic(code(punch::error))]
     Error {
@@ -27,13 +96,24 @@ pub enum PoofError {
     },
 }
 
+/// Protocol code works with `anyhow`, so unwrap any `PoofError` it carries
+/// instead of burying it under `Other`.
+impl From<anyhow::Error> for PoofError {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast::<PoofError>() {
+            Ok(err) => err,
+            Err(err) => PoofError::Other(err),
+        }
+    }
+}
+
 pub type Result<T, E = PoofError> = std::result::Result<T, E>;
 
 #[macro_export]
 macro_rules! error {
     (source = $source:expr, $($arg:tt)*) => {
         {
-            crate::utils::error::PoofError::Error {
+            $crate::utils::error::PoofError::Error {
                 message: format!($($arg)*),
                 source: Some(Box::new($source)),
             }
@@ -41,7 +121,7 @@ macro_rules! error {
     };
     ($($arg:tt)*) => {
         {
-            crate::utils::error::PoofError::Error {
+            $crate::utils::error::PoofError::Error {
                 message: format!($($arg)*),
                 source: None,
             }
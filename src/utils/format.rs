@@ -1,4 +1,5 @@
 use owo_colors::OwoColorize;
+use sha2::{Digest, Sha256};
 
 pub trait ReducedId {
     fn reduced(&self) -> String;
@@ -16,11 +17,48 @@ impl ReducedId for iroh::NodeId {
     }
 }
 
+/// Number of 4-character hex groups in a fingerprint.
+const FINGERPRINT_GROUPS: usize = 3;
+
+/// A short, stable name for a public key, meant to be compared out-of-band.
+pub trait Fingerprint {
+    fn fingerprint(&self) -> String;
+}
+
+impl Fingerprint for iroh::PublicKey {
+    /// SHA-256 of the 32 raw public key bytes, of which the first 6 bytes are
+    /// rendered as uppercase hex in groups of 4 joined by `-`: `AB12-CD34-EF56`.
+    fn fingerprint(&self) -> String {
+        let digest = Sha256::digest(self.as_bytes());
+        digest[..FINGERPRINT_GROUPS * 2]
+            .chunks(2)
+            .map(|group| format!("{:02X}{:02X}", group[0], group[1]))
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+}
+
+/// Brings a user-typed fingerprint to the form [`Fingerprint`] produces,
+/// tolerating lowercase and missing or misplaced dashes.
+pub fn normalize_fingerprint(fingerprint: &str) -> String {
+    let hex: Vec<char> = fingerprint
+        .chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    hex.chunks(4)
+        .map(|group| group.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
 pub fn format_duration(duration: std::time::Duration) -> String {
     let ms = duration.as_millis();
 
     if ms < 1000 {
         format!("{}ms", ms)
+    } else if ms < 10_000 && ms % 1000 >= 100 {
+        format!("{}.{}s", ms / 1000, ms % 1000 / 100)
     } else if ms < 60_000 {
         format!("{}s", ms / 1000)
     } else if ms < 3_600_000 {
@@ -31,3 +69,65 @@ pub fn format_duration(duration: std::time::Duration) -> String {
         format!("{}d", ms / 86_400_000)
     }
 }
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Parses an amount of bytes like `1GiB`, `500k` or `1.5MB`, the inverse of
+/// [`format_bytes`] with binary units when no `B` suffix says otherwise.
+pub fn parse_bytes(s: &str) -> Result<u64, String> {
+    let amount = s.trim();
+    let split = amount
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(amount.len());
+    let (number, unit) = amount.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("expected an amount like 2MiB, got '{s}'"))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1 << 10,
+        "kb" => 1_000,
+        "m" | "mib" => 1 << 20,
+        "mb" => 1_000_000,
+        "g" | "gib" => 1 << 30,
+        "gb" => 1_000_000_000,
+        other => return Err(format!("unknown unit '{other}' in '{s}'")),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Throughput of `bytes` moved in `duration`, e.g. `2.3 MiB/s`.
+pub fn format_rate(bytes: u64, duration: std::time::Duration) -> String {
+    let secs = duration.as_secs_f64();
+    if secs <= 0.0 {
+        return format!("{}/s", format_bytes(bytes));
+    }
+    format!("{}/s", format_bytes((bytes as f64 / secs) as u64))
+}
+
+/// Renders `data` as a QR code made of unicode half blocks.
+pub fn render_qr(data: &str) -> anyhow::Result<String> {
+    use qrcode::render::unicode::Dense1x2;
+
+    let code = qrcode::QrCode::new(data)?;
+    Ok(code
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build())
+}
@@ -1,14 +1,124 @@
+use std::{
+    io::IsTerminal,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use facet::Facet;
+use owo_colors::OwoColorize;
 use tracing::level_filters::LevelFilter;
-use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
+use tracing_subscriber::{
+    EnvFilter, Layer,
+    fmt::{
+        format::Writer,
+        time::{FormatTime, SystemTime, Uptime},
+        writer::BoxMakeWriter,
+    },
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+};
+
+use crate::cli::{ColorChoice, LogTime};
+
+/// Set when stdout carries data, so messages must stay out of it.
+static STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Sends all human-readable output to stderr from now on.
+pub fn use_stderr() {
+    STDERR.store(true, Ordering::Relaxed);
+}
+
+pub fn is_stderr() -> bool {
+    STDERR.load(Ordering::Relaxed)
+}
+
+/// Set by `--json`, messages become JSON lines.
+static JSON: AtomicBool = AtomicBool::new(false);
+
+pub fn use_json() {
+    JSON.store(true, Ordering::Relaxed);
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Cleared by `--color`, or when `auto` finds no terminal, colors are then
+/// stripped from everything printed to that stream.
+static STDOUT_COLOR: AtomicBool = AtomicBool::new(true);
+static STDERR_COLOR: AtomicBool = AtomicBool::new(true);
+
+/// Decides whether stdout and stderr get colors, `auto` honoring `NO_COLOR`.
+pub fn use_color(choice: ColorChoice) {
+    let enabled = |terminal: bool| match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            terminal && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+        }
+    };
+    STDOUT_COLOR.store(enabled(std::io::stdout().is_terminal()), Ordering::Relaxed);
+    STDERR_COLOR.store(enabled(std::io::stderr().is_terminal()), Ordering::Relaxed);
+}
+
+/// Whether messages get colors on the stream they are printed to.
+pub fn is_color() -> bool {
+    if is_stderr() {
+        is_color_stderr()
+    } else {
+        STDOUT_COLOR.load(Ordering::Relaxed)
+    }
+}
+
+pub fn is_color_stderr() -> bool {
+    STDERR_COLOR.load(Ordering::Relaxed)
+}
+
+/// How log lines are filtered and formatted.
+#[derive(Debug, Clone, Copy)]
+pub struct LogOptions {
+    /// Level of the crate's own events, unless `POOF_LOG` says otherwise
+    pub level: LevelFilter,
+    pub time: LogTime,
+}
+
+/// Formats [`LogTime`] timestamps, keeping a single layer type for all of them.
+struct Timer {
+    time: LogTime,
+    uptime: Uptime,
+}
+
+impl FormatTime for Timer {
+    fn format_time(&self, w: &mut Writer<'_>) -> std::fmt::Result {
+        match self.time {
+            LogTime::None => Ok(()),
+            LogTime::Uptime => self.uptime.format_time(w),
+            LogTime::Rfc3339 => SystemTime.format_time(w),
+        }
+    }
+}
 
-pub fn init() -> anyhow::Result<()> {
+pub fn init(options: LogOptions) -> anyhow::Result<()> {
+    let writer = if is_stderr() {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
+    let layer = tracing_subscriber::fmt::layer()
+        .compact()
+        .with_ansi(is_color())
+        .with_target(false)
+        .with_writer(writer);
+    let layer = match options.time {
+        LogTime::None => layer.without_time().boxed(),
+        time => layer
+            .with_timer(Timer {
+                time,
+                uptime: Uptime::default(),
+            })
+            .boxed(),
+    };
     tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::fmt::layer()
-                .compact()
-                .without_time()
-                .with_target(false),
-        )
+        .with(layer)
         .with({
             EnvFilter::builder()
                 .with_default_directive(LevelFilter::OFF.into())
@@ -18,7 +128,7 @@ pub fn init() -> anyhow::Result<()> {
                         "{}={}",
                         env!("CARGO_PKG_NAME"),
                         std::env::var(format!("{}_LOG", env!("CARGO_PKG_NAME").to_uppercase()))
-                            .unwrap_or_else(|_| "off".to_string())
+                            .unwrap_or_else(|_| options.level.to_string())
                     )
                     .parse()?,
                 )
@@ -27,12 +137,117 @@ pub fn init() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Severity of a user-facing message.
+#[derive(Debug, Clone, Copy)]
+pub enum Level {
+    Success,
+    Warning,
+    Info,
+    Error,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Success => "success",
+            Level::Warning => "warning",
+            Level::Info => "info",
+            Level::Error => "error",
+        }
+    }
+
+    fn icon(&self) -> String {
+        match self {
+            Level::Success => "✓".green().to_string(),
+            Level::Warning => "⚠".yellow().to_string(),
+            Level::Info => "ℹ".blue().to_string(),
+            Level::Error => "✗".red().to_string(),
+        }
+    }
+}
+
+/// Prints a message, as a JSON line under `--json`.
+pub fn message(level: Level, msg: String) {
+    if is_json() {
+        emit(json_line(level, &msg, None));
+    } else {
+        emit(format!("{} {}", level.icon(), msg));
+    }
+}
+
+/// Like [`message`], attaching `data` to the JSON line.
+pub fn message_with<'a, T: Facet<'a>>(level: Level, msg: String, data: &T) {
+    if is_json() {
+        emit(json_line(level, &msg, Some(&facet_json::to_string(data))));
+    } else {
+        emit(format!("{} {}", level.icon(), msg));
+    }
+}
+
+/// Builds `{"level":..,"msg":..,"data":..}` with `data` already serialized.
+pub fn json_line(level: Level, msg: &str, data: Option<&str>) -> String {
+    let mut line = format!(
+        r#"{{"level":{},"msg":{}"#,
+        facet_json::to_string(&level.as_str().to_string()),
+        facet_json::to_string(&strip_ansi(msg))
+    );
+    if let Some(data) = data {
+        line.push_str(r#","data":"#);
+        line.push_str(data);
+    }
+    line.push('}');
+    line
+}
+
+/// Prints a line wherever messages currently go.
+pub fn emit(line: String) {
+    let line = if is_color() { line } else { strip_ansi(&line) };
+    if is_stderr() {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
+
+/// Like [`emit`], but always to stderr, for lines that must stay out of
+/// output meant to be piped.
+pub fn emit_stderr(line: String) {
+    if is_color_stderr() {
+        eprintln!("{}", line);
+    } else {
+        eprintln!("{}", strip_ansi(&line));
+    }
+}
+
+/// Removes the color codes messages are formatted with.
+fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip the CSI sequence up to its final byte
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
 #[macro_export]
 macro_rules! success {
     ($($arg:tt)*) => {
         {
+            #[allow(unused_imports)]
             use owo_colors::OwoColorize;
-            println!("{} {}", "✓".green(), format!($($arg)*))
+            $crate::utils::logging::message(
+                $crate::utils::logging::Level::Success,
+                format!($($arg)*),
+            )
         }
     };
 }
@@ -41,8 +256,12 @@ macro_rules! success {
 macro_rules! warning {
     ($($arg:tt)*) => {
         {
+            #[allow(unused_imports)]
             use owo_colors::OwoColorize;
-            println!("{} {}", "⚠".yellow(), format!($($arg)*))
+            $crate::utils::logging::message(
+                $crate::utils::logging::Level::Warning,
+                format!($($arg)*),
+            )
         }
     };
 }
@@ -50,9 +269,13 @@ macro_rules! warning {
 #[macro_export]
 macro_rules! info {
     ($($arg:tt)*) => {
-       {
+        {
+            #[allow(unused_imports)]
             use owo_colors::OwoColorize;
-            println!("{} {}", "ℹ".blue(), format!($($arg)*))
-       }
+            $crate::utils::logging::message(
+                $crate::utils::logging::Level::Info,
+                format!($($arg)*),
+            )
+        }
     };
 }
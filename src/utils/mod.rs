@@ -1,4 +1,7 @@
+pub mod clipboard;
 pub mod constants;
 pub mod error;
 pub mod format;
 pub mod logging;
+pub mod progress;
+pub mod secret;
@@ -0,0 +1,126 @@
+use std::{collections::HashMap, io::IsTerminal, time::Instant};
+
+use indicatif::{ProgressBar, ProgressStyle};
+use iroh_blobs::Hash;
+
+use crate::{
+    info,
+    utils::{
+        format::{format_bytes, format_duration},
+        logging,
+    },
+};
+
+const BAR_TEMPLATE: &str = "{spinner:.blue} [{bar:30.blue/dimmed}] {binary_bytes}/{binary_total_bytes} ({binary_bytes_per_sec}, {eta})";
+const SPINNER_TEMPLATE: &str = "{spinner:.blue} {binary_bytes} ({binary_bytes_per_sec})";
+const PLAIN_BAR_TEMPLATE: &str =
+    "{spinner} [{bar:30}] {binary_bytes}/{binary_total_bytes} ({binary_bytes_per_sec}, {eta})";
+const PLAIN_SPINNER_TEMPLATE: &str = "{spinner} {binary_bytes} ({binary_bytes_per_sec})";
+
+/// The colored `template`, or its `plain` twin when colors are off.
+fn style(template: &str, plain: &str) -> ProgressStyle {
+    let template = if logging::is_color_stderr() {
+        template
+    } else {
+        plain
+    };
+    ProgressStyle::with_template(template).expect("valid template")
+}
+
+/// Renders transfer progress, a spinner until the total size is known.
+/// Stays silent when stdout is not a terminal or under `--json`.
+pub struct TransferBar {
+    bar: ProgressBar,
+    started: Instant,
+    /// Bytes received so far and expected size per progress id
+    offsets: HashMap<u64, (u64, u64)>,
+    /// Bytes already stored per blob found locally
+    stored: HashMap<Hash, u64>,
+    /// Bytes already available locally
+    local: u64,
+}
+
+impl TransferBar {
+    pub fn new() -> Self {
+        let bar = if std::io::stdout().is_terminal() && !logging::is_json() {
+            ProgressBar::new_spinner()
+        } else {
+            ProgressBar::hidden()
+        };
+        bar.set_style(style(SPINNER_TEMPLATE, PLAIN_SPINNER_TEMPLATE));
+        Self {
+            bar,
+            started: Instant::now(),
+            offsets: HashMap::new(),
+            stored: HashMap::new(),
+            local: 0,
+        }
+    }
+
+    /// Adds `size` bytes to the expected total, unless `hash` is partially
+    /// stored and already counted by [`Self::found_local`].
+    pub fn found(&mut self, id: u64, hash: Hash, size: u64) {
+        match self.stored.remove(&hash) {
+            // Progress offsets are absolute, so continue from what is stored
+            Some(stored) => {
+                self.local -= stored;
+                self.offsets.insert(id, (stored, size));
+            }
+            None => {
+                self.add_length(size);
+                self.offsets.insert(id, (0, size));
+            }
+        }
+    }
+
+    /// Accounts for the `stored` bytes of a `size` bytes blob that don't need
+    /// to be fetched.
+    pub fn found_local(&mut self, hash: Hash, size: u64, stored: u64) {
+        self.local += stored;
+        self.add_length(size);
+        self.bar.inc(stored);
+        // Even a fully stored blob is fetched again while its size is unverified
+        self.stored.insert(hash, stored);
+    }
+
+    fn add_length(&self, size: u64) {
+        match self.bar.length() {
+            Some(_) => self.bar.inc_length(size),
+            None => {
+                self.bar.set_style(style(BAR_TEMPLATE, PLAIN_BAR_TEMPLATE));
+                self.bar.set_length(size);
+            }
+        }
+    }
+
+    pub fn progress(&mut self, id: u64, offset: u64) {
+        if let Some((current, _)) = self.offsets.get_mut(&id) {
+            self.bar.inc(offset.saturating_sub(*current));
+            *current = offset.max(*current);
+        }
+    }
+
+    /// Marks `id` as complete, small blobs may finish without any progress.
+    pub fn done(&mut self, id: u64) {
+        if let Some(&(_, size)) = self.offsets.get(&id) {
+            self.progress(id, size);
+        }
+    }
+
+    /// Clears the bar and prints a summary of the transfer.
+    pub fn finish(self) {
+        self.bar.finish_and_clear();
+        let received = self.offsets.values().map(|(offset, _)| offset).sum::<u64>();
+        info!(
+            "Received {} in {}",
+            format_bytes(received + self.local),
+            format_duration(self.started.elapsed())
+        );
+    }
+}
+
+impl Default for TransferBar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,90 @@
+use std::io::IsTerminal;
+
+use anyhow::{Context, bail};
+use argon2::Argon2;
+use chacha20poly1305::{
+    KeyInit, XChaCha20Poly1305, XNonce,
+    aead::{Aead, AeadCore, OsRng},
+};
+use rand::RngCore;
+
+/// Marks a stored secret as sealed with [`encrypt`].
+pub const ENCRYPTED_PREFIX: &str = "encrypted:";
+/// Read instead of prompting, for scripts.
+pub const PASSPHRASE_ENV: &str = "POOF_PASSPHRASE";
+
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 24;
+
+pub fn is_encrypted(stored: &str) -> bool {
+    stored.starts_with(ENCRYPTED_PREFIX)
+}
+
+/// Seals `secret` with XChaCha20-Poly1305 under a key derived from
+/// `passphrase` with argon2, as `encrypted:<base32 salt, nonce and ciphertext>`.
+pub fn encrypt(secret: &[u8], passphrase: &str) -> anyhow::Result<String> {
+    let mut salt = [0; SALT_SIZE];
+    rand::rngs::OsRng.fill_bytes(&mut salt);
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher(passphrase, &salt)?
+        .encrypt(&nonce, secret)
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt secret"))?;
+
+    let mut bytes = salt.to_vec();
+    bytes.extend_from_slice(&nonce);
+    bytes.extend(ciphertext);
+    Ok(format!(
+        "{}{}",
+        ENCRYPTED_PREFIX,
+        data_encoding::BASE32_NOPAD
+            .encode(&bytes)
+            .to_ascii_lowercase()
+    ))
+}
+
+/// Opens a secret sealed by [`encrypt`].
+pub fn decrypt(stored: &str, passphrase: &str) -> anyhow::Result<Vec<u8>> {
+    let encoded = stored
+        .strip_prefix(ENCRYPTED_PREFIX)
+        .context("Secret is not encrypted")?;
+    let bytes = data_encoding::BASE32_NOPAD
+        .decode(encoded.to_ascii_uppercase().as_bytes())
+        .context("Encrypted secret is not valid base32")?;
+    if bytes.len() < SALT_SIZE + NONCE_SIZE {
+        bail!("Encrypted secret is truncated");
+    }
+
+    let (salt, rest) = bytes.split_at(SALT_SIZE);
+    let (nonce, ciphertext) = rest.split_at(NONCE_SIZE);
+    cipher(passphrase, salt)?
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow::anyhow!("Wrong passphrase"))
+}
+
+fn cipher(passphrase: &str, salt: &[u8]) -> anyhow::Result<XChaCha20Poly1305> {
+    let mut key = [0; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow::anyhow!("Failed to derive key: {e}"))?;
+    Ok(XChaCha20Poly1305::new(&key.into()))
+}
+
+/// Reads a passphrase from `POOF_PASSPHRASE`, or prompts for it without echo.
+/// With `confirm`, the prompt is repeated and both entries must match.
+pub fn read_passphrase(prompt: &str, confirm: bool) -> anyhow::Result<String> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    if !std::io::stdin().is_terminal() {
+        bail!("{prompt}: no terminal to prompt on, set {PASSPHRASE_ENV} instead");
+    }
+
+    let passphrase = rpassword::prompt_password(format!("{prompt}: "))?;
+    if passphrase.is_empty() {
+        bail!("The passphrase cannot be empty");
+    }
+    if confirm && rpassword::prompt_password("Repeat the passphrase: ")? != passphrase {
+        bail!("The passphrases don't match");
+    }
+    Ok(passphrase)
+}